@@ -6,7 +6,11 @@ fn main() {
 
     for (key, value) in env::vars() {
         // Filter to include only desired env vars
-        if key.starts_with("GOOGLE_") || key == "API_URL" || key == "OUT_DIR" {
+        if key.starts_with("GOOGLE_")
+            || key.starts_with("MICROSOFT_")
+            || key == "API_URL"
+            || key == "OUT_DIR"
+        {
             println!("cargo:rustc-env={}={}", key, value);
         }
     }
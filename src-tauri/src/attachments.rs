@@ -0,0 +1,206 @@
+// This module lists the attachments of a message and downloads them one at a
+// time with `BODY.PEEK[<part>]`, so the whole message is never fetched again.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use imap_proto::types::{MessageSection, SectionPath};
+use mail_parser::{MessageParser, MessagePartId, MimeHeaders, PartType};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::email::Session;
+use crate::error::{Error, ErrorKind, Result};
+
+/// The directory in the system temp dir attachments are opened from
+const TEMP_DIR_NAME: &str = "mail-client-attachments";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub mime_type: String,
+    /// The decoded size in bytes
+    pub size: usize,
+    pub content_id: Option<String>,
+    /// The IMAP body section of the attachment, e.g. `2` or `1.3`
+    pub part: String,
+    /// Whether the attachment is displayed in the body, e.g. an embedded image
+    pub inline: bool,
+}
+
+/// List the attachments of a parsed message
+///
+/// # Arguments
+/// * `message` - The parsed message
+/// # Returns
+/// * `Vec<Attachment>` - The attachments
+///
+pub fn parse_attachments(message: &mail_parser::Message) -> Vec<Attachment> {
+    let sections = section_numbers(message);
+
+    message
+        .attachments
+        .iter()
+        .filter_map(|id| {
+            let part = message.part(*id)?;
+
+            Some(Attachment {
+                filename: part.attachment_name().map(|name| name.to_string()),
+                mime_type: mime_type(part),
+                size: part.len(),
+                content_id: part.content_id().map(|id| id.to_string()),
+                part: sections.get(id)?.clone(),
+                inline: part
+                    .content_disposition()
+                    .is_some_and(|cd| cd.ctype().eq_ignore_ascii_case("inline")),
+            })
+        })
+        .collect()
+}
+
+/// Map the parts of a message to their IMAP body section numbers
+fn section_numbers(message: &mail_parser::Message) -> HashMap<MessagePartId, String> {
+    fn walk(
+        message: &mail_parser::Message,
+        id: MessagePartId,
+        section: String,
+        sections: &mut HashMap<MessagePartId, String>,
+    ) {
+        let Some(part) = message.part(id) else {
+            return;
+        };
+
+        if let PartType::Multipart(children) = &part.body {
+            for (i, child) in children.iter().enumerate() {
+                let child_section = if section.is_empty() {
+                    (i + 1).to_string()
+                } else {
+                    format!("{}.{}", section, i + 1)
+                };
+                walk(message, *child, child_section, sections);
+            }
+        }
+        sections.insert(id, section);
+    }
+
+    let mut sections = HashMap::new();
+    walk(message, 0, String::new(), &mut sections);
+
+    // The body of a message that is not multipart is section 1
+    if let Some(root) = sections.get_mut(&0) {
+        if root.is_empty() {
+            *root = "1".to_string();
+        }
+    }
+
+    sections
+}
+
+/// Fetch and decode a single attachment
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// * `uid` - The UID of the message
+/// * `part` - The body section of the attachment, see `Attachment::part`
+/// # Returns
+/// * `Result<Vec<u8>>` - The decoded content
+///
+pub fn fetch_attachment(
+    session: &mut Session,
+    mailbox: &str,
+    uid: u32,
+    part: &str,
+) -> Result<Vec<u8>> {
+    let (_, contents) = fetch_part(session, mailbox, uid, part)?;
+    Ok(contents)
+}
+
+/// Fetch and decode a single body part
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// * `uid` - The UID of the message
+/// * `part` - The body section, e.g. `2` or `1.3`
+/// # Returns
+/// * `Result<(String, Vec<u8>)>` - The MIME type and the decoded content
+///
+pub fn fetch_part(
+    session: &mut Session,
+    mailbox: &str,
+    uid: u32,
+    part: &str,
+) -> Result<(String, Vec<u8>)> {
+    let path = part
+        .split('.')
+        .map(|n| n.parse::<u32>())
+        .collect::<std::result::Result<Vec<u32>, _>>()
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid message part: {}", part),
+            )
+        })?;
+
+    session.select(mailbox)?;
+
+    // The MIME headers hold the transfer encoding needed to decode the body
+    let responses = session.uid_fetch(
+        uid.to_string(),
+        format!("(UID BODY.PEEK[{0}.MIME] BODY.PEEK[{0}])", part),
+    )?;
+    let fetch = responses
+        .first()
+        .ok_or(Error::new(ErrorKind::NotFound, "Could not get attachment"))?;
+
+    let headers = fetch
+        .section(&SectionPath::Part(path.clone(), Some(MessageSection::Mime)))
+        .ok_or(Error::from("Attachment is missing headers"))?;
+    let body = fetch
+        .section(&SectionPath::Part(path, None))
+        .ok_or(Error::from("Attachment is missing body"))?;
+
+    let raw = [headers, body].concat();
+    let parsed = MessageParser::new()
+        .parse(&raw)
+        .ok_or(Error::from("Could not parse attachment"))?;
+    let part = parsed
+        .part(0)
+        .ok_or(Error::from("Could not parse attachment"))?;
+
+    Ok((mime_type(part), part.contents().to_vec()))
+}
+
+pub fn mime_type(part: &mail_parser::MessagePart) -> String {
+    part.content_type()
+        .map(|ct| match ct.subtype() {
+            Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+            None => ct.ctype().to_string(),
+        })
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+        .to_lowercase()
+}
+
+/// Write an attachment to a file
+pub fn save(contents: &[u8], path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Get a new path in the temp dir for an attachment, keeping its file name
+pub fn temp_path(filename: Option<&str>) -> PathBuf {
+    // Only keep the file name, a crafted name must not escape the directory
+    let filename = filename
+        .and_then(|name| Path::new(name).file_name())
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| "attachment".into());
+
+    std::env::temp_dir()
+        .join(TEMP_DIR_NAME)
+        .join(Uuid::new_v4().to_string())
+        .join(filename)
+}
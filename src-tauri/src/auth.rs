@@ -1,18 +1,20 @@
 // This file contains the authentication logic for the application.
 use axum::{extract::Query, response::IntoResponse, routing::get, Extension, Router};
-use chrono::{TimeDelta, Utc};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
 use oauth2::{
-    basic::{BasicClient, BasicErrorResponseType, BasicTokenType},
+    basic::{BasicErrorResponseType, BasicTokenType},
     reqwest, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
-    EmptyExtraTokenFields, EndpointNotSet, EndpointSet, PkceCodeChallenge, PkceCodeVerifier,
-    RedirectUrl, RevocationErrorResponseType, RevocationUrl, StandardErrorResponse,
-    StandardRevocableToken, StandardTokenIntrospectionResponse, StandardTokenResponse,
-    TokenResponse, TokenUrl,
+    EmptyExtraTokenFields, EndpointMaybeSet, EndpointNotSet, EndpointSet, ExtraTokenFields,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RevocationErrorResponseType, RevocationUrl,
+    StandardErrorResponse, StandardRevocableToken, StandardTokenIntrospectionResponse,
+    StandardTokenResponse, TokenResponse, TokenUrl,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     net::{SocketAddr, TcpListener},
     sync::Arc,
+    time::Duration,
 };
 use tauri::{
     async_runtime::{JoinHandle, Mutex},
@@ -21,69 +23,88 @@ use tauri::{
 use tauri_plugin_opener::open_url;
 
 use crate::{
-    auth_store::{OAuthCredentials, PersistedCredentials},
-    config::Config,
-    constants::{
-        GOOGLE_AUTH_URI, GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET, GOOGLE_MAIL_SCOPE,
-        GOOGLE_PROFILE_API, GOOGLE_PROFILE_MAIL_SCOPE, GOOGLE_PROFILE_SCOPE, GOOGLE_REVOKATION_URI,
-        GOOGLE_TOKEN_URI,
-    },
-    error::Error,
+    auth_store::{AccountCredentials, OAuthCredentials, PersistedCredentials},
+    config::{Account, Config},
+    error::{Error, ErrorKind, Result},
+    oauth_provider::{OAuthProvider, ProfileSource},
     util::navigate,
     AppState,
 };
 
+/// Extra fields of the token response, the id token is only returned by OpenID Connect providers
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
 type OAuthClient = Client<
     StandardErrorResponse<BasicErrorResponseType>,
-    StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
+    StandardTokenResponse<IdTokenFields, BasicTokenType>,
     StandardTokenIntrospectionResponse<EmptyExtraTokenFields, BasicTokenType>,
     StandardRevocableToken,
     StandardErrorResponse<RevocationErrorResponseType>,
     EndpointSet,
     EndpointNotSet,
     EndpointNotSet,
-    EndpointSet,
+    EndpointMaybeSet,
     EndpointSet,
 >;
 
+/// The task running the local server which receives the OAuth redirect
+type ServerHandle = JoinHandle<std::result::Result<(), axum::Error>>;
+
 #[derive(Clone)]
 struct OAuthState {
     csrf_token: CsrfToken,
     pkce: Arc<(PkceCodeChallenge, String)>,
     client: Arc<OAuthClient>,
     socket_addr: SocketAddr,
+    provider: OAuthProvider,
 }
 
-pub fn create_client() -> OAuthClient {
-    let client_id = ClientId::new(GOOGLE_CLIENT_ID.to_string());
-    let client_secret = ClientSecret::new(GOOGLE_CLIENT_SECRET.to_string());
-    let auth_uri = AuthUrl::new(GOOGLE_AUTH_URI.to_string()).expect("Invalid authorization URL");
-    let token_uri = TokenUrl::new(GOOGLE_TOKEN_URI.to_string()).expect("Invalid token URL");
-    let revokation_url =
-        RevocationUrl::new(GOOGLE_REVOKATION_URI.to_string()).expect("Invalid revocation URL");
+pub fn create_client(provider: OAuthProvider) -> Result<OAuthClient> {
+    let invalid_url = |name: &str, e: oauth2::url::ParseError| {
+        Error::new(ErrorKind::OAuth, format!("Invalid {} URL: {}", name, e))
+    };
 
-    let client = BasicClient::new(client_id)
-        .set_client_secret(client_secret)
+    let client_id = ClientId::new(provider.client_id().to_string());
+    let auth_uri = AuthUrl::new(provider.auth_uri().to_string())
+        .map_err(|e| invalid_url("authorization", e))?;
+    let token_uri =
+        TokenUrl::new(provider.token_uri().to_string()).map_err(|e| invalid_url("token", e))?;
+    let revokation_url = provider
+        .revocation_uri()
+        .map(|uri| RevocationUrl::new(uri.to_string()).map_err(|e| invalid_url("revocation", e)))
+        .transpose()?;
+
+    let client = Client::new(client_id)
         .set_auth_uri(auth_uri)
         .set_token_uri(token_uri)
-        .set_revocation_url(revokation_url);
+        .set_revocation_url_option(revokation_url);
 
-    return client;
+    // Public clients (e.g. Microsoft) authenticate with PKCE only
+    Ok(match provider.client_secret() {
+        Some(secret) => client.set_client_secret(ClientSecret::new(secret.to_string())),
+        None => client,
+    })
 }
 
-fn get_available_addr() -> SocketAddr {
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    let addr = listener.local_addr().unwrap();
+fn get_available_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
     drop(listener);
 
-    return addr;
+    return Ok(addr);
 }
 
-pub async fn init_google_oauth_flow(handle: tauri::AppHandle) -> Result<(), Error> {
+pub async fn init_oauth_flow(handle: tauri::AppHandle, provider: OAuthProvider) -> Result<()> {
     let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-    let socket_addr = get_available_addr(); // or any other port
+    let socket_addr = get_available_addr()?; // or any other port
     let redirect_url = format!("http://{socket_addr}/callback").to_string();
-    let client = create_client().set_redirect_uri(RedirectUrl::new(redirect_url.clone()).unwrap());
+    let client = create_client(provider)?.set_redirect_uri(RedirectUrl::new(redirect_url.clone())?);
 
     let oauth_state = OAuthState {
         csrf_token: CsrfToken::new_random(),
@@ -93,16 +114,17 @@ pub async fn init_google_oauth_flow(handle: tauri::AppHandle) -> Result<(), Erro
         )),
         client: Arc::new(client),
         socket_addr,
+        provider,
     };
 
     let oauth_state_clone = oauth_state.clone();
     handle.manage(oauth_state);
 
-    let scopes: Vec<oauth2::Scope> = vec![
-        oauth2::Scope::new(GOOGLE_MAIL_SCOPE.to_string()),
-        oauth2::Scope::new(GOOGLE_PROFILE_SCOPE.to_string()),
-        oauth2::Scope::new(GOOGLE_PROFILE_MAIL_SCOPE.to_string()),
-    ];
+    let scopes: Vec<oauth2::Scope> = provider
+        .scopes()
+        .iter()
+        .map(|scope| oauth2::Scope::new(scope.to_string()))
+        .collect();
 
     let (auth_url, _) = oauth_state_clone
         .client
@@ -113,11 +135,16 @@ pub async fn init_google_oauth_flow(handle: tauri::AppHandle) -> Result<(), Erro
 
     // Open the authorization URL in the user's default browser
     print!("Opening URL: {}\n", auth_url);
-    open_url(auth_url, None::<String>).expect("Failed to open URL");
+    open_url(auth_url, None::<String>).map_err(|e| {
+        Error::new(
+            ErrorKind::OAuth,
+            format!("Failed to open the authorization URL: {}", e),
+        )
+    })?;
 
     let handle_clone = handle.clone();
 
-    let server_handle: JoinHandle<Result<(), axum::Error>> =
+    let server_handle: ServerHandle =
         tauri::async_runtime::spawn(async move { run_server(&handle_clone).await });
 
     // Store the server handle in the app state
@@ -143,22 +170,109 @@ struct ProfileResponse {
     // email_verified: bool,
 }
 
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    email: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// Read the email address from the payload of an id token
+///
+/// The token was received directly from the token endpoint over TLS, so the
+/// signature is not verified here.
+fn email_from_id_token(id_token: &str) -> Option<String> {
+    let payload = id_token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims = serde_json::from_slice::<IdTokenClaims>(&payload).ok()?;
+
+    claims.email.or(claims.preferred_username)
+}
+
+/// Parse the response of the profile endpoint
+///
+/// # Arguments
+/// * `body` - The JSON body of the response
+/// # Returns
+/// * `Result<String>` - The email address of the user
+///
+fn parse_profile(body: &[u8]) -> Result<String> {
+    let profile = serde_json::from_slice::<ProfileResponse>(body)
+        .map_err(|e| Error::new(ErrorKind::OAuth, format!("Failed to parse profile: {}", e)))?;
+
+    Ok(profile.email)
+}
+
+/// When an access token expires, relative to now
+///
+/// A token without a lifetime is treated as expired, so it is refreshed before its first use.
+///
+/// # Arguments
+/// * `expires_in` - The lifetime of the token from the token response
+/// # Returns
+/// * `Result<DateTime<Utc>>` - The expiry time
+///
+fn token_expiry(expires_in: Option<Duration>) -> Result<DateTime<Utc>> {
+    TimeDelta::from_std(expires_in.unwrap_or_default())
+        .ok()
+        .and_then(|lifetime| Utc::now().checked_add_signed(lifetime))
+        .ok_or(Error::new(
+            ErrorKind::OAuth,
+            "Invalid access token lifetime",
+        ))
+}
+
 async fn authorize(
     handle: Extension<tauri::AppHandle>,
     query: Query<CallbackQuery>,
 ) -> impl IntoResponse {
+    let email = match complete_authorization(&handle, &query).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("Failed to complete the OAuth flow: {}", e);
+            return e.to_string();
+        }
+    };
+
+    // Signal the server to shut down
+    match handle.try_state::<ServerHandle>() {
+        Some(server_handle) => server_handle.abort(),
+        None => println!("Failed to close the OAuth callback server"),
+    }
+
+    // Open the inbox in the tauri app
+    match handle.get_webview_window("main") {
+        Some(window) => navigate(window, format!("/{}/INBOX", email).as_str()),
+        None => println!("Main window not found"),
+    }
+
+    "Login successful.
+You can close this window."
+        .to_string()
+}
+
+/// Exchange the authorization code for tokens and store the new account
+///
+/// # Arguments
+/// * `handle` - The app handle
+/// * `query` - The query of the redirect to the callback URL
+/// # Returns
+/// * `Result<String>` - The email address of the account
+///
+async fn complete_authorization(
+    handle: &tauri::AppHandle,
+    query: &CallbackQuery,
+) -> Result<String> {
     let oauth_state = handle
         .try_state::<OAuthState>()
-        .expect("Failed to get OAuth Flow state");
+        .ok_or(Error::new(ErrorKind::OAuth, "No OAuth flow in progress"))?;
 
     if query.state.secret() != oauth_state.csrf_token.secret() {
-        return "Not authorized".to_string();
+        return Err(Error::new(ErrorKind::OAuth, "Not authorized"));
     }
 
     let http_client = reqwest::ClientBuilder::new()
         .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Failed to build HTTP client");
+        .build()?;
 
     println!("Received authorization code: {}", query.code.secret());
 
@@ -168,90 +282,75 @@ async fn authorize(
         .exchange_code(query.code.clone())
         .set_pkce_verifier(PkceCodeVerifier::new(oauth_state.pkce.1.clone()))
         .request_async(&http_client)
-        .await;
-
-    if let Err(e) = token {
-        println!("Error exchanging code: {:?}", e);
-        return e.to_string();
-    }
-    let token = token.unwrap();
-
-    // Get the email address from the profile endpoint
-    let profile_response = reqwest::Client::new()
-        .get(GOOGLE_PROFILE_API)
-        .bearer_auth(token.access_token().secret())
-        .send()
-        .await
-        .expect("Failed to get profile");
-
-    let profile_json = profile_response
-        .json::<ProfileResponse>()
-        .await
-        .expect("Failed to parse profile");
-
-    let email = profile_json.email.as_str();
-    let user = email.to_string();
+        .await?;
+
+    // Get the email address from the profile endpoint or the id token
+    let user = match oauth_state.provider.profile_source() {
+        ProfileSource::UserInfo(profile_api) => {
+            let profile_response = reqwest::Client::new()
+                .get(profile_api)
+                .bearer_auth(token.access_token().secret())
+                .send()
+                .await?;
+
+            parse_profile(&profile_response.bytes().await?)?
+        }
+        ProfileSource::IdToken => token
+            .extra_fields()
+            .id_token
+            .as_deref()
+            .and_then(email_from_id_token)
+            .ok_or(Error::new(
+                ErrorKind::OAuth,
+                "Failed to read email address from id token",
+            ))?,
+    };
 
-    let app_state_mutex = handle
-        .try_state::<Mutex<AppState>>()
-        .expect("Failed to get app state");
+    let account = Account::from_provider(user.clone(), oauth_state.provider);
+    account.check_email()?;
 
     let credential = OAuthCredentials::new(
         token.access_token().secret().to_string(),
-        Utc::now() + TimeDelta::from_std(token.expires_in().unwrap()).unwrap(),
+        token_expiry(token.expires_in())?,
         token
             .refresh_token()
             .map(|rt| rt.secret().to_string())
             .unwrap_or_else(|| "".to_string()),
         user.clone(),
-    );
+        oauth_state.provider,
+    )?;
 
-    credential.persist().expect("Failed to persist credentials");
+    credential.persist()?;
 
-    let mut app_state = app_state_mutex.lock().await;
-    if let Err(_) = app_state.set_account(email.to_string(), credential) {
-        return "Failed to set account".to_string();
-    }
+    // Only one state is locked at a time, so this cannot deadlock with removing the account
+    let app_state_mutex = handle.state::<Mutex<AppState>>();
+    app_state_mutex.lock().await.set_account(
+        handle,
+        account.clone(),
+        AccountCredentials::OAuth(credential),
+    )?;
 
     // Store the email in the Account Config
-    let config_mutex = handle
-        .try_state::<Mutex<Config>>()
-        .expect("Failed to get config state");
+    let config_mutex = handle.state::<Mutex<Config>>();
     let mut config = config_mutex.lock().await;
 
     // Add the email to the config if it doesn't exist
-    if let Err(e) = config.add_account(email.to_string()) {
+    if let Err(e) = config.add_account(account) {
         println!("Failed to add account to config: {}", e);
     }
 
-    // Signal the server to shut down
-    let server_handle = handle.try_state::<JoinHandle<Result<(), axum::Error>>>();
-    match server_handle {
-        Some(handle) => {
-            handle.abort();
-        }
-        None => {
-            panic!("Failed to close server. Something went wrong.");
-        }
-    }
-
-    // Open the inbox in the tauri app
-    let window = handle.get_webview_window("main").unwrap();
-    navigate(window, format!("/{}/INBOX", email).as_str());
-
-    "Login successful.
-You can close this window."
-        .to_string()
+    Ok(user)
 }
 
-async fn run_server(handle: &tauri::AppHandle) -> Result<(), axum::Error> {
+async fn run_server(handle: &tauri::AppHandle) -> std::result::Result<(), axum::Error> {
     let app = Router::new()
         .route("/callback", get(authorize))
         .layer(Extension(handle.clone()));
 
-    let oauth_state = handle
-        .try_state::<OAuthState>()
-        .expect("Failed to get OAuth Flow state");
+    let Some(oauth_state) = handle.try_state::<OAuthState>() else {
+        println!("No OAuth flow in progress");
+        return Ok(());
+    };
 
     let _ = axum::Server::bind(&oauth_state.socket_addr.clone())
         .serve(app.into_make_service())
@@ -259,3 +358,89 @@ async fn run_server(handle: &tauri::AppHandle) -> Result<(), axum::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_token(payload: &str) -> String {
+        format!("header.{}.signature", URL_SAFE_NO_PAD.encode(payload))
+    }
+
+    fn is_oauth_error<T>(result: &Result<T>) -> bool {
+        matches!(result, Err(e) if matches!(e.kind(), ErrorKind::OAuth))
+    }
+
+    #[test]
+    fn reads_email_from_id_token() {
+        let token = id_token(r#"{"email": "user@example.com"}"#);
+        assert_eq!(
+            email_from_id_token(&token),
+            Some("user@example.com".to_string())
+        );
+
+        let token = id_token(r#"{"preferred_username": "user@example.com"}"#);
+        assert_eq!(
+            email_from_id_token(&token),
+            Some("user@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_id_tokens() {
+        let malformed = [
+            String::new(),
+            "no dots".to_string(),
+            "header.".to_string(),
+            "header.!!!.signature".to_string(),
+            id_token("not json"),
+            id_token("{}"),
+            id_token(r#"{"email": 42}"#),
+            id_token("[]"),
+        ];
+
+        for token in malformed {
+            assert_eq!(email_from_id_token(&token), None, "token {:?}", token);
+        }
+    }
+
+    #[test]
+    fn parses_profile() {
+        let profile = parse_profile(br#"{"sub": "1", "email": "user@example.com"}"#);
+        assert_eq!(profile.ok(), Some("user@example.com".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_profiles() {
+        let malformed: [&[u8]; 6] = [
+            b"",
+            b"not json",
+            b"{}",
+            br#"{"email": null}"#,
+            br#"{"email": 42}"#,
+            b"\xff\xfe",
+        ];
+
+        for body in malformed {
+            assert!(is_oauth_error(&parse_profile(body)), "body {:?}", body);
+        }
+    }
+
+    #[test]
+    fn computes_token_expiry() {
+        let expiry = token_expiry(Some(Duration::from_secs(3600))).unwrap();
+        assert!(expiry > Utc::now());
+
+        // Without a lifetime the token is refreshed before its first use
+        let expiry = token_expiry(None).unwrap();
+        assert!(expiry <= Utc::now());
+    }
+
+    #[test]
+    fn rejects_out_of_range_token_lifetimes() {
+        assert!(is_oauth_error(&token_expiry(Some(Duration::MAX))));
+        assert!(is_oauth_error(&token_expiry(Some(Duration::from_secs(
+            u64::MAX / 2
+        )))));
+    }
+}
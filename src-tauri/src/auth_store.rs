@@ -1,5 +1,5 @@
 // This module interacts with the keyring to store and retrieve authentication tokens.
-use crate::{auth, error::Result};
+use crate::{auth, config::Account, error::Result, oauth_provider::OAuthProvider};
 use chrono::{DateTime, Utc};
 use keyring::Entry;
 use lettre::transport::smtp::authentication::Credentials;
@@ -11,6 +11,7 @@ pub struct OAuthCredentials {
     access_token: String,
     expires_at: DateTime<Utc>,
     refresh_token: String,
+    provider: OAuthProvider,
 
     pub user: String,
     entry: Entry,
@@ -22,15 +23,17 @@ impl OAuthCredentials {
         expires_at: DateTime<Utc>,
         refresh_token: String,
         user: String,
-    ) -> Self {
-        let entry = Entry::new("mailclient", &user).unwrap();
-        OAuthCredentials {
+        provider: OAuthProvider,
+    ) -> Result<Self> {
+        let entry = Entry::new("mailclient", &user)?;
+        Ok(OAuthCredentials {
             access_token,
             expires_at,
             refresh_token,
+            provider,
             user,
             entry,
-        }
+        })
     }
     pub fn is_expired(&self) -> bool {
         Utc::now() > self.expires_at
@@ -44,7 +47,7 @@ impl OAuthCredentials {
 
         println!("Refreshing access token");
         let http_client = reqwest::Client::new();
-        let oauth_client = auth::create_client();
+        let oauth_client = auth::create_client(self.provider)?;
         let token = oauth_client
             .exchange_refresh_token(&RefreshToken::new(self.refresh_token.clone()))
             .request_async(&http_client)
@@ -74,6 +77,8 @@ struct SerializedOAuthCredentials {
     access_token: String,
     expires_at: DateTime<Utc>,
     refresh_token: String,
+    #[serde(default)]
+    provider: OAuthProvider,
 }
 
 impl PersistedCredentials for OAuthCredentials {
@@ -87,6 +92,7 @@ impl PersistedCredentials for OAuthCredentials {
             access_token: credentials.access_token,
             expires_at: credentials.expires_at,
             refresh_token: credentials.refresh_token,
+            provider: credentials.provider,
             user: key.to_string(),
             entry: entry,
         };
@@ -99,6 +105,7 @@ impl PersistedCredentials for OAuthCredentials {
             access_token: self.access_token.clone(),
             expires_at: self.expires_at,
             refresh_token: self.refresh_token.clone(),
+            provider: self.provider,
         };
         let json = serde_json::to_string(&serializable_credentials)?;
         self.entry.set_password(&json)?;
@@ -111,6 +118,106 @@ impl PersistedCredentials for OAuthCredentials {
     }
 }
 
+#[derive(Debug)]
+pub struct PasswordCredentials {
+    password: String,
+
+    pub user: String,
+    entry: Entry,
+}
+
+impl PasswordCredentials {
+    pub fn new(password: String, user: String) -> Result<Self> {
+        let entry = Entry::new("mailclient", &user)?;
+        Ok(PasswordCredentials {
+            password,
+            user,
+            entry,
+        })
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SerializedPasswordCredentials {
+    password: String,
+}
+
+impl PersistedCredentials for PasswordCredentials {
+    fn load(key: &str) -> Option<Self> {
+        let entry = Entry::new("mailclient", key).ok()?;
+
+        let json_string = entry.get_password().ok()?;
+
+        let credentials =
+            serde_json::from_str::<SerializedPasswordCredentials>(&json_string).ok()?;
+        let credentials = PasswordCredentials {
+            password: credentials.password,
+            user: key.to_string(),
+            entry: entry,
+        };
+
+        Some(credentials)
+    }
+
+    fn persist(&self) -> Result<()> {
+        let serializable_credentials = SerializedPasswordCredentials {
+            password: self.password.clone(),
+        };
+        let json = serde_json::to_string(&serializable_credentials)?;
+        self.entry.set_password(&json)?;
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<()> {
+        self.entry.delete_credential()?;
+        Ok(())
+    }
+}
+
+/// The secret of an account, depending on its authentication method
+#[derive(Debug)]
+pub enum AccountCredentials {
+    OAuth(OAuthCredentials),
+    Password(PasswordCredentials),
+}
+
+impl AccountCredentials {
+    /// Load the credentials of an account from the keyring
+    pub fn load(account: &Account) -> Option<Self> {
+        if account.uses_oauth() {
+            OAuthCredentials::load(&account.email).map(AccountCredentials::OAuth)
+        } else {
+            PasswordCredentials::load(&account.email).map(AccountCredentials::Password)
+        }
+    }
+
+    /// Refresh the access token, if the credentials have one
+    pub async fn refresh(&mut self) -> Result<()> {
+        match self {
+            AccountCredentials::OAuth(credentials) => credentials.refresh().await,
+            AccountCredentials::Password(_) => Ok(()),
+        }
+    }
+}
+
+/// SASL PLAIN authenticator for IMAP
+pub struct PlainAuthenticator<'a> {
+    pub user: &'a str,
+    pub password: &'a str,
+}
+
+impl imap::Authenticator for PlainAuthenticator<'_> {
+    type Response = String;
+
+    fn process(&self, _data: &[u8]) -> Self::Response {
+        format!("\x00{}\x00{}", self.user, self.password)
+    }
+}
+
 impl imap::Authenticator for &OAuthCredentials {
     type Response = String;
 
@@ -0,0 +1,495 @@
+// This module persists mailboxes, envelopes and message bodies in a local
+// SQLite database, so mail can be read without a connection to the server.
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::email::{Envelope, Mailbox, Message};
+use crate::error::Result;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS mailboxes (
+        account TEXT NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL,
+        uid_validity INTEGER,
+        uid_next INTEGER,
+        highest_modseq INTEGER,
+        PRIMARY KEY (account, name)
+    );
+    CREATE TABLE IF NOT EXISTS envelopes (
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        PRIMARY KEY (account, mailbox, uid)
+    );
+    CREATE TABLE IF NOT EXISTS bodies (
+        account TEXT NOT NULL,
+        mailbox TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (account, mailbox, uid)
+    );
+";
+
+/// The synchronization state of a cached mailbox
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MailboxState {
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    pub highest_modseq: Option<u64>,
+}
+
+pub struct Cache {
+    conn: Connection,
+}
+
+impl Cache {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Cache { conn })
+    }
+
+    pub fn get_mailboxes(&self, account: &str) -> Result<Vec<Mailbox>> {
+        // Rows created by set_mailbox_state before the list was cached have no data
+        let mut statement = self.conn.prepare(
+            "SELECT data FROM mailboxes WHERE account = ?1 AND data != 'null' ORDER BY name",
+        )?;
+
+        let rows = statement.query_map(params![account], |row| row.get::<_, String>(0))?;
+
+        let mut mailboxes = vec![];
+        for data in rows {
+            mailboxes.push(serde_json::from_str(&data?)?);
+        }
+        Ok(mailboxes)
+    }
+
+    /// Replace the cached mailbox list, dropping the contents of mailboxes that no longer exist
+    pub fn set_mailboxes(&mut self, account: &str, mailboxes: &[Mailbox]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        let existing: Vec<String> = {
+            let mut statement = tx.prepare("SELECT name FROM mailboxes WHERE account = ?1")?;
+            let rows = statement.query_map(params![account], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        for name in existing {
+            if !mailboxes.iter().any(|m| m.name == name) {
+                tx.execute(
+                    "DELETE FROM mailboxes WHERE account = ?1 AND name = ?2",
+                    params![account, name],
+                )?;
+                clear_mailbox(&tx, account, &name)?;
+            }
+        }
+
+        for mailbox in mailboxes {
+            tx.execute(
+                "INSERT INTO mailboxes (account, name, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (account, name) DO UPDATE SET data = excluded.data",
+                params![account, mailbox.name, serde_json::to_string(mailbox)?],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_mailbox_state(&self, account: &str, mailbox: &str) -> Result<Option<MailboxState>> {
+        let state = self
+            .conn
+            .query_row(
+                "SELECT uid_validity, uid_next, highest_modseq FROM mailboxes
+                 WHERE account = ?1 AND name = ?2",
+                params![account, mailbox],
+                |row| {
+                    Ok(MailboxState {
+                        uid_validity: row.get(0)?,
+                        uid_next: row.get(1)?,
+                        highest_modseq: row.get::<_, Option<i64>>(2)?.map(|m| m as u64),
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(state)
+    }
+
+    /// Store the synchronization state of a mailbox
+    ///
+    /// If the UIDVALIDITY changed, the cached envelopes and bodies of the
+    /// mailbox are no longer valid and are removed.
+    pub fn set_mailbox_state(
+        &mut self,
+        account: &str,
+        mailbox: &str,
+        state: &MailboxState,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if uid_validity_changed(&tx, account, mailbox, state)? {
+            println!("UIDVALIDITY of {} changed, clearing cache", mailbox);
+            clear_mailbox(&tx, account, mailbox)?;
+        }
+        write_mailbox_state(&tx, account, mailbox, state)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replace the cached envelopes and the state of a mailbox after a full sync
+    ///
+    /// Everything is stored in one transaction, so if storing the envelopes
+    /// fails the previous state is kept and the next sync starts over.
+    pub fn replace_mailbox(
+        &mut self,
+        account: &str,
+        mailbox: &str,
+        state: &MailboxState,
+        envelopes: &[Envelope],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        if uid_validity_changed(&tx, account, mailbox, state)? {
+            println!("UIDVALIDITY of {} changed, clearing cache", mailbox);
+            clear_mailbox(&tx, account, mailbox)?;
+        } else {
+            let removed: Vec<u32> = uids(&tx, account, mailbox)?
+                .into_iter()
+                .filter(|uid| !envelopes.iter().any(|e| e.uid == Some(*uid)))
+                .collect();
+            delete_messages(&tx, account, mailbox, &removed)?;
+        }
+        insert_envelopes(&tx, account, mailbox, envelopes)?;
+        write_mailbox_state(&tx, account, mailbox, state)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get the cached envelopes of a mailbox, newest first
+    pub fn get_envelopes(&self, account: &str, mailbox: &str) -> Result<Vec<Envelope>> {
+        let mut statement = self.conn.prepare(
+            "SELECT data, flags FROM envelopes
+             WHERE account = ?1 AND mailbox = ?2 ORDER BY uid DESC",
+        )?;
+
+        let rows = statement.query_map(params![account, mailbox], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut envelopes = vec![];
+        for row in rows {
+            let (data, flags) = row?;
+            let mut envelope: Envelope = serde_json::from_str(&data)?;
+            envelope.flags = serde_json::from_str(&flags)?;
+            envelopes.push(envelope);
+        }
+        Ok(envelopes)
+    }
+
+    /// Get a page of cached envelopes of a mailbox, newest first
+    ///
+    /// # Arguments
+    /// * `account` - The account
+    /// * `mailbox` - The mailbox
+    /// * `before_uid` - Only get envelopes with a lower UID, or the newest ones if `None`
+    /// * `count` - The maximum number of envelopes
+    /// # Returns
+    /// * `Result<Vec<Envelope>>` - The envelopes
+    ///
+    pub fn get_envelope_page(
+        &self,
+        account: &str,
+        mailbox: &str,
+        before_uid: Option<u32>,
+        count: u32,
+    ) -> Result<Vec<Envelope>> {
+        let mut statement = self.conn.prepare(
+            "SELECT data, flags FROM envelopes
+             WHERE account = ?1 AND mailbox = ?2 AND uid < ?3 ORDER BY uid DESC LIMIT ?4",
+        )?;
+
+        let before_uid = before_uid.map_or(i64::MAX, i64::from);
+        let rows = statement.query_map(params![account, mailbox, before_uid, count], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut envelopes = vec![];
+        for row in rows {
+            let (data, flags) = row?;
+            let mut envelope: Envelope = serde_json::from_str(&data)?;
+            envelope.flags = serde_json::from_str(&flags)?;
+            envelopes.push(envelope);
+        }
+        Ok(envelopes)
+    }
+
+    /// Get a single cached envelope
+    pub fn get_envelope(&self, account: &str, mailbox: &str, uid: u32) -> Result<Option<Envelope>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT data, flags FROM envelopes
+                 WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                params![account, mailbox, uid],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        let Some((data, flags)) = row else {
+            return Ok(None);
+        };
+        let mut envelope: Envelope = serde_json::from_str(&data)?;
+        envelope.flags = serde_json::from_str(&flags)?;
+        Ok(Some(envelope))
+    }
+
+    /// Get the UIDs of the cached envelopes of a mailbox
+    pub fn get_uids(&self, account: &str, mailbox: &str) -> Result<Vec<u32>> {
+        uids(&self.conn, account, mailbox)
+    }
+
+    /// Insert or update envelopes
+    pub fn put_envelopes(
+        &mut self,
+        account: &str,
+        mailbox: &str,
+        envelopes: &[Envelope],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        insert_envelopes(&tx, account, mailbox, envelopes)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_flags(
+        &self,
+        account: &str,
+        mailbox: &str,
+        uid: u32,
+        flags: &[String],
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE envelopes SET flags = ?4 WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+            params![account, mailbox, uid, serde_json::to_string(flags)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_flags(&self, account: &str, mailbox: &str, uid: u32, flags: &[&str]) -> Result<()> {
+        let Some(mut current) = self.get_flags(account, mailbox, uid)? else {
+            return Ok(());
+        };
+
+        for flag in flags {
+            if !current.iter().any(|f| f == flag) {
+                current.push(flag.to_string());
+            }
+        }
+        self.set_flags(account, mailbox, uid, &current)
+    }
+
+    pub fn remove_flags(
+        &self,
+        account: &str,
+        mailbox: &str,
+        uid: u32,
+        flags: &[&str],
+    ) -> Result<()> {
+        let Some(mut current) = self.get_flags(account, mailbox, uid)? else {
+            return Ok(());
+        };
+
+        current.retain(|f| !flags.contains(&f.as_str()));
+        self.set_flags(account, mailbox, uid, &current)
+    }
+
+    pub fn get_flags(&self, account: &str, mailbox: &str, uid: u32) -> Result<Option<Vec<String>>> {
+        let flags = self
+            .conn
+            .query_row(
+                "SELECT flags FROM envelopes WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                params![account, mailbox, uid],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        match flags {
+            Some(flags) => Ok(Some(serde_json::from_str(&flags)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get a cached message, with the flags of its envelope
+    pub fn get_message(&self, account: &str, mailbox: &str, uid: u32) -> Result<Option<Message>> {
+        let data = self
+            .conn
+            .query_row(
+                "SELECT data FROM bodies WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+                params![account, mailbox, uid],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        let mut message: Message = serde_json::from_str(&data)?;
+        if let Some(flags) = self.get_flags(account, mailbox, uid)? {
+            message.flags = flags;
+        }
+        Ok(Some(message))
+    }
+
+    pub fn put_message(&self, account: &str, mailbox: &str, message: &Message) -> Result<()> {
+        let Some(uid) = message.uid else {
+            return Ok(());
+        };
+
+        self.conn.execute(
+            "INSERT INTO bodies (account, mailbox, uid, data) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (account, mailbox, uid) DO UPDATE SET data = excluded.data",
+            params![account, mailbox, uid, serde_json::to_string(message)?],
+        )?;
+        Ok(())
+    }
+
+    /// Remove messages from the cache, e.g. after they were moved or expunged
+    pub fn remove_messages(&mut self, account: &str, mailbox: &str, uids: &[u32]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        delete_messages(&tx, account, mailbox, uids)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_account(&mut self, account: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        for table in ["mailboxes", "envelopes", "bodies"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE account = ?1", table),
+                params![account],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn clear_mailbox(conn: &Connection, account: &str, mailbox: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM envelopes WHERE account = ?1 AND mailbox = ?2",
+        params![account, mailbox],
+    )?;
+    conn.execute(
+        "DELETE FROM bodies WHERE account = ?1 AND mailbox = ?2",
+        params![account, mailbox],
+    )?;
+    Ok(())
+}
+
+/// Whether the state of a cached mailbox has a different UIDVALIDITY than `state`
+fn uid_validity_changed(
+    conn: &Connection,
+    account: &str,
+    mailbox: &str,
+    state: &MailboxState,
+) -> Result<bool> {
+    let previous: Option<Option<u32>> = conn
+        .query_row(
+            "SELECT uid_validity FROM mailboxes WHERE account = ?1 AND name = ?2",
+            params![account, mailbox],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(matches!(previous, Some(Some(previous)) if Some(previous) != state.uid_validity))
+}
+
+fn write_mailbox_state(
+    conn: &Connection,
+    account: &str,
+    mailbox: &str,
+    state: &MailboxState,
+) -> Result<()> {
+    // The mailbox row may not exist yet if the list was never cached
+    conn.execute(
+        "INSERT INTO mailboxes (account, name, data, uid_validity, uid_next, highest_modseq)
+         VALUES (?1, ?2, 'null', ?3, ?4, ?5)
+         ON CONFLICT (account, name) DO UPDATE SET
+            uid_validity = excluded.uid_validity,
+            uid_next = excluded.uid_next,
+            highest_modseq = excluded.highest_modseq",
+        params![
+            account,
+            mailbox,
+            state.uid_validity,
+            state.uid_next,
+            state.highest_modseq.map(|m| m as i64)
+        ],
+    )?;
+    Ok(())
+}
+
+fn uids(conn: &Connection, account: &str, mailbox: &str) -> Result<Vec<u32>> {
+    let mut statement =
+        conn.prepare("SELECT uid FROM envelopes WHERE account = ?1 AND mailbox = ?2 ORDER BY uid")?;
+    let rows = statement.query_map(params![account, mailbox], |row| row.get(0))?;
+
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+fn insert_envelopes(
+    conn: &Connection,
+    account: &str,
+    mailbox: &str,
+    envelopes: &[Envelope],
+) -> Result<()> {
+    for envelope in envelopes {
+        let Some(uid) = envelope.uid else {
+            continue;
+        };
+        conn.execute(
+            "INSERT INTO envelopes (account, mailbox, uid, data, flags)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (account, mailbox, uid) DO UPDATE SET
+                data = excluded.data,
+                flags = excluded.flags",
+            params![
+                account,
+                mailbox,
+                uid,
+                serde_json::to_string(envelope)?,
+                serde_json::to_string(&envelope.flags)?
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn delete_messages(conn: &Connection, account: &str, mailbox: &str, uids: &[u32]) -> Result<()> {
+    for uid in uids {
+        conn.execute(
+            "DELETE FROM envelopes WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+            params![account, mailbox, uid],
+        )?;
+        conn.execute(
+            "DELETE FROM bodies WHERE account = ?1 AND mailbox = ?2 AND uid = ?3",
+            params![account, mailbox, uid],
+        )?;
+    }
+    Ok(())
+}
@@ -1,25 +1,80 @@
-use crate::auth::init_google_oauth_flow;
+use crate::attachments;
+use crate::auth::init_oauth_flow;
+use crate::auth_store::{AccountCredentials, PasswordCredentials, PersistedCredentials};
+use crate::cache::Cache;
+use crate::compose::{self, Draft, SendResult};
 use crate::config::{Account, Config};
-use crate::constants::GOOGLE_SMTP_HOST;
-use crate::email::{self, EmailAddress, Envelope, Mailbox};
-use crate::error::{Error, ErrorKind, Result};
-use crate::AppState;
-use lettre::message::Mailbox as LettreMailbox;
-use lettre::transport::smtp::authentication::Mechanism;
-use lettre::{message::header::ContentType, Message, SmtpTransport, Transport};
+use crate::discovery::{DiscoveredSettings, Discovery};
+use crate::email::{self, Envelope, EnvelopePage, Mailbox, PageQuery};
+use crate::error::{Context, Error, ErrorContext, ErrorKind, Result};
+use crate::idle::IdleWorkers;
+use crate::oauth_provider::OAuthProvider;
+use crate::sanitize;
+use crate::search::{self, SearchQuery};
+use crate::search_index::{LocalSearchResult, SearchIndex};
+use crate::sync;
+use crate::threads::{self, ServerThreading, Thread};
+use crate::{get_account_config, get_account_state, run_blocking, AppState};
+use lettre::Transport;
+use std::path::Path;
 use tauri::async_runtime::Mutex;
 use tauri::Manager;
 
 #[tauri::command]
 pub async fn login_with_google(handle: tauri::AppHandle) -> Result<()> {
-    init_google_oauth_flow(handle).await
+    init_oauth_flow(handle, OAuthProvider::Google).await
+}
+
+#[tauri::command]
+pub async fn login_with_microsoft(handle: tauri::AppHandle) -> Result<()> {
+    init_oauth_flow(handle, OAuthProvider::Microsoft).await
+}
+
+#[tauri::command]
+pub async fn login_with_password(
+    handle: tauri::AppHandle,
+    account: Account,
+    password: String,
+) -> Result<()> {
+    account.check_email()?;
+    if account.uses_oauth() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Account is configured for OAuth2",
+        ));
+    }
+
+    let credentials =
+        AccountCredentials::Password(PasswordCredentials::new(password, account.email.clone())?);
+
+    // Verify the settings before storing anything
+    let (account, credentials) = run_blocking(move || {
+        let mut imap_session = email::get_imap_session(&account, &credentials)?;
+        imap_session.logout()?;
+        Ok((account, credentials))
+    })
+    .await?;
+
+    if let AccountCredentials::Password(password) = &credentials {
+        password.persist()?;
+    }
+
+    let app_state_mutex = handle.state::<Mutex<AppState>>();
+    app_state_mutex
+        .lock()
+        .await
+        .set_account(&handle, account.clone(), credentials)?;
+
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let mut account_config = account_config_mutex.lock().await;
+    account_config.add_account(account)?;
+
+    Ok(())
 }
 
 #[tauri::command]
 pub async fn get_config(handle: tauri::AppHandle) -> Result<Vec<Account>> {
-    let account_config_mutex = handle
-        .try_state::<Mutex<Config>>()
-        .expect("Failed to get config state");
+    let account_config_mutex = handle.state::<Mutex<Config>>();
     let account_config = account_config_mutex.lock().await;
 
     let config = account_config.accounts().to_vec();
@@ -28,57 +83,253 @@ pub async fn get_config(handle: tauri::AppHandle) -> Result<Vec<Account>> {
 }
 
 #[tauri::command]
-pub async fn config_add_account(handle: tauri::AppHandle, email: &str) -> Result<()> {
+pub async fn config_add_account(handle: tauri::AppHandle, account: Account) -> Result<()> {
     let account_config_mutex = handle.state::<Mutex<Config>>();
     let mut account_config = account_config_mutex.lock().await;
 
-    account_config
-        .add_account(email.to_string())
-        .expect("Failed to add account");
+    account_config.add_account(account)
+}
 
-    Ok(())
+#[tauri::command]
+pub async fn discover_settings(email: &str) -> Result<DiscoveredSettings> {
+    let discovery = Discovery::system()?;
+    discovery.discover(email).await
 }
 
 #[tauri::command]
 pub async fn config_remove_account(handle: tauri::AppHandle, email: &str) -> Result<()> {
-    let account_config_mutex = handle.state::<Mutex<Config>>();
-    let mut account_config = account_config_mutex.lock().await;
+    // Release the config before locking the app state, which is locked first elsewhere
+    {
+        let account_config_mutex = handle.state::<Mutex<Config>>();
+        let mut account_config = account_config_mutex.lock().await;
+        account_config.remove_account(email)?;
+    }
+
+    let app_state_mutex = handle.state::<Mutex<AppState>>();
+    app_state_mutex.lock().await.remove_account(email);
+
+    let idle_workers_mutex = handle.state::<Mutex<IdleWorkers>>();
+    idle_workers_mutex.lock().await.unwatch_account(email);
+
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+    cache_mutex.lock().await.remove_account(email)?;
+
+    let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+    let mut search_index = search_index_mutex.lock().await;
+    search_index.remove_account(email);
+    search_index.commit()?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn watch_mailbox(handle: tauri::AppHandle, email: &str, mailbox: &str) -> Result<()> {
+    let account_config = get_account_config(&handle, email).await?;
 
-    account_config
-        .remove_account(email)
-        .expect("Failed to remove account");
+    let idle_workers_mutex = handle.state::<Mutex<IdleWorkers>>();
+    let mut idle_workers = idle_workers_mutex.lock().await;
+    idle_workers.watch(handle.clone(), account_config, mailbox);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn unwatch_mailbox(handle: tauri::AppHandle, email: &str, mailbox: &str) -> Result<()> {
+    let idle_workers_mutex = handle.state::<Mutex<IdleWorkers>>();
+    let mut idle_workers = idle_workers_mutex.lock().await;
+    idle_workers.unwatch(email, mailbox);
 
     Ok(())
 }
 
 #[tauri::command]
 pub async fn get_mailboxes(handle: tauri::AppHandle, email: &str) -> Result<Vec<Mailbox>> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
-
-    let mailboxes = email::get_mailboxes(imap_session).unwrap();
-    return Ok(mailboxes);
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::account(email);
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+
+    let mailboxes = account
+        .with_imap_session_retried(email::get_mailboxes)
+        .await
+        .context(context);
+
+    match mailboxes {
+        Ok(mailboxes) => {
+            cache_mutex.lock().await.set_mailboxes(email, &mailboxes)?;
+            Ok(mailboxes)
+        }
+        // Fall back to the cached list when offline
+        Err(e) => {
+            let cached = cache_mutex.lock().await.get_mailboxes(email)?;
+            if cached.is_empty() {
+                return Err(e);
+            }
+            println!("Serving cached mailboxes: {}", e);
+            Ok(cached)
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_threads(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+) -> Result<Vec<Thread>> {
+    let envelopes = match sync::sync_envelopes(&handle, email, mailbox).await {
+        Ok(envelopes) => envelopes,
+        Err(e) => {
+            println!(
+                "Failed to sync {}, threading cached envelopes: {}",
+                mailbox, e
+            );
+            let cache_mutex = handle.state::<Mutex<Cache>>();
+            let cache = cache_mutex.lock().await;
+            cache.get_envelopes(email, mailbox)?
+        }
+    };
+
+    let server_threading = {
+        let account_config = get_account_config(&handle, email).await?;
+        let account = get_account_state(&handle, &account_config).await?;
+        let context = ErrorContext::mailbox(email, mailbox);
+
+        let mailbox = mailbox.to_string();
+        account
+            .with_imap_session_retried(move |imap_session| {
+                threads::get_server_threading(imap_session, &mailbox)
+            })
+            .await
+            .context(context)
+    };
+
+    // The headers are enough to build the threads when the server cannot help
+    let server_threading = server_threading.unwrap_or_else(|e| {
+        println!("Failed to get threads from the server: {}", e);
+        ServerThreading::None
+    });
+
+    Ok(threads::build_threads(envelopes, server_threading))
 }
 
 #[tauri::command]
-pub async fn get_envelopes(
+pub async fn get_envelope_page(
     handle: tauri::AppHandle,
     email: &str,
     mailbox: &str,
+    query: PageQuery,
+) -> Result<EnvelopePage> {
+    let envelopes = match query {
+        PageQuery::Newest {
+            count,
+            before_uid: None,
+        } => {
+            let cached = {
+                let cache_mutex = handle.state::<Mutex<Cache>>();
+                let cache = cache_mutex.lock().await;
+                cache.get_envelope_page(email, mailbox, None, count)?
+            };
+
+            // Serve the cache right away, the fresh envelopes are emitted once synced
+            if cached.is_empty() {
+                sync::sync_envelopes(&handle, email, mailbox).await?;
+                let cache_mutex = handle.state::<Mutex<Cache>>();
+                let cache = cache_mutex.lock().await;
+                cache.get_envelope_page(email, mailbox, None, count)?
+            } else {
+                sync::sync_envelopes_in_background(
+                    handle.clone(),
+                    email.to_string(),
+                    mailbox.to_string(),
+                );
+                cached
+            }
+        }
+        PageQuery::Newest {
+            count,
+            before_uid: Some(before_uid),
+        } => sync::load_older(&handle, email, mailbox, before_uid, count).await?,
+        // Arbitrary ranges may not be cached
+        PageQuery::Sequence { .. } | PageQuery::Uid { .. } => {
+            let account_config = get_account_config(&handle, email).await?;
+            let account = get_account_state(&handle, &account_config).await?;
+            let context = ErrorContext::mailbox(email, mailbox);
+            let mailbox = mailbox.to_string();
+
+            return account
+                .with_imap_session_retried(move |imap_session| {
+                    email::get_envelope_page(imap_session, &mailbox, &query)
+                })
+                .await
+                .context(context);
+        }
+    };
+
+    let state = {
+        let cache_mutex = handle.state::<Mutex<Cache>>();
+        let cache = cache_mutex.lock().await;
+        cache.get_mailbox_state(email, mailbox)?.unwrap_or_default()
+    };
+
+    Ok(EnvelopePage {
+        envelopes,
+        total: None,
+        unseen: None,
+        uid_validity: state.uid_validity,
+        uid_next: state.uid_next,
+    })
+}
+
+#[tauri::command]
+pub async fn search_messages(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    query: SearchQuery,
 ) -> Result<Vec<Envelope>> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
-
-    let envelopes = email::get_envelopes(imap_session, mailbox)?;
-    return Ok(envelopes);
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::mailbox(email, mailbox);
+    let mailbox = mailbox.to_string();
+
+    account
+        .with_imap_session_retried(move |imap_session| {
+            search::search_messages(imap_session, &mailbox, &query)
+        })
+        .await
+        .context(context)
+}
+
+#[tauri::command]
+pub async fn search_local(
+    handle: tauri::AppHandle,
+    query: &str,
+    limit: Option<usize>,
+) -> Result<Vec<LocalSearchResult>> {
+    let hits = {
+        let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+        let search_index = search_index_mutex.lock().await;
+        search_index.search(query, limit.unwrap_or(50))?
+    };
+
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+    let cache = cache_mutex.lock().await;
+
+    let mut results = vec![];
+    for hit in hits {
+        // Skip hits the cache no longer knows about
+        if let Some(envelope) = cache.get_envelope(&hit.email, &hit.mailbox, hit.uid)? {
+            results.push(LocalSearchResult {
+                email: hit.email,
+                mailbox: hit.mailbox,
+                score: hit.score,
+                envelope,
+            });
+        }
+    }
+
+    Ok(results)
 }
 
 #[tauri::command]
@@ -88,15 +339,208 @@ pub async fn get_message(
     mailbox: &str,
     uid: u32,
 ) -> Result<email::Message> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
-
-    let html = email::get_message(imap_session, mailbox, uid)?;
-    return Ok(html);
+    let account_config = get_account_config(&handle, email).await?;
+
+    // The cache keeps the unsanitized body, so allowing remote content applies right away
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+    if let Some(message) = cache_mutex.lock().await.get_message(email, mailbox, uid)? {
+        return Ok(sanitize::sanitize_message(message, &account_config));
+    }
+
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let message = {
+        let (email, mailbox) = (email.to_string(), mailbox.to_string());
+        account
+            .with_imap_session_retried(move |imap_session| {
+                email::get_message(imap_session, &email, &mailbox, uid)
+            })
+            .await
+            .context(context)?
+    };
+    cache_mutex
+        .lock()
+        .await
+        .put_message(email, mailbox, &message)?;
+
+    let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+    let mut search_index = search_index_mutex.lock().await;
+    search_index.put_message(email, mailbox, &message)?;
+    search_index.commit()?;
+
+    return Ok(sanitize::sanitize_message(message, &account_config));
+}
+
+/// Load remote content for all messages of a sender, or for a single message
+#[tauri::command]
+pub async fn allow_remote_content(
+    handle: tauri::AppHandle,
+    email: &str,
+    sender: Option<&str>,
+    message_id: Option<&str>,
+) -> Result<()> {
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let mut account_config = account_config_mutex.lock().await;
+
+    let mut account = account_config
+        .account(email)
+        .cloned()
+        .ok_or(Error::new(ErrorKind::NotFound, "Account not found"))?;
+    if let Some(sender) = sender {
+        account.remote_content.allow_sender(sender);
+    }
+    if let Some(message_id) = message_id {
+        account.remote_content.allow_message(message_id);
+    }
+
+    account_config.update_account(account)
+}
+
+/// Replace links wrapped by click trackers with their destinations
+#[tauri::command]
+pub async fn set_unwrap_tracking_links(
+    handle: tauri::AppHandle,
+    email: &str,
+    enabled: bool,
+) -> Result<()> {
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let mut account_config = account_config_mutex.lock().await;
+
+    let mut account = account_config
+        .account(email)
+        .cloned()
+        .ok_or(Error::new(ErrorKind::NotFound, "Account not found"))?;
+    account.unwrap_tracking_links = enabled;
+
+    account_config.update_account(account)
+}
+
+/// Set whether sent messages are copied to the Sent mailbox, `None` to decide by provider
+#[tauri::command]
+pub async fn set_save_sent(
+    handle: tauri::AppHandle,
+    email: &str,
+    enabled: Option<bool>,
+) -> Result<()> {
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let mut account_config = account_config_mutex.lock().await;
+
+    let mut account = account_config
+        .account(email)
+        .cloned()
+        .ok_or(Error::new(ErrorKind::NotFound, "Account not found"))?;
+    account.save_sent = enabled;
+
+    account_config.update_account(account)
+}
+
+/// Set the other addresses of an account, which replies to all leave out
+#[tauri::command]
+pub async fn set_account_aliases(
+    handle: tauri::AppHandle,
+    email: &str,
+    aliases: Vec<String>,
+) -> Result<()> {
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let mut account_config = account_config_mutex.lock().await;
+
+    let mut account = account_config
+        .account(email)
+        .cloned()
+        .ok_or(Error::new(ErrorKind::NotFound, "Account not found"))?;
+    account.aliases = aliases;
+
+    account_config.update_account(account)
+}
+
+/// Prefill a reply to a message
+#[tauri::command]
+pub async fn reply_message(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+    all: Option<bool>,
+) -> Result<Draft> {
+    let account_config = get_account_config(&handle, email).await?;
+    let message = get_message(handle, email, mailbox, uid).await?;
+
+    Ok(compose::reply(
+        &message,
+        &account_config,
+        all.unwrap_or(false),
+    ))
+}
+
+/// Prefill a forward of a message, quoting it or attaching it as `message/rfc822`
+#[tauri::command]
+pub async fn forward_message(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+    as_attachment: Option<bool>,
+) -> Result<Draft> {
+    let message = get_message(handle, email, mailbox, uid).await?;
+
+    Ok(compose::forward(&message, as_attachment.unwrap_or(false)))
+}
+
+#[tauri::command]
+pub async fn save_attachment(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+    part: &str,
+    path: &str,
+) -> Result<()> {
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let (mailbox, part, path) = (mailbox.to_string(), part.to_string(), path.to_string());
+
+    let contents = account
+        .with_imap_session_retried(move |imap_session| {
+            attachments::fetch_attachment(imap_session, &mailbox, uid, &part)
+        })
+        .await
+        .context(context.clone())?;
+
+    run_blocking(move || attachments::save(&contents, Path::new(&path)))
+        .await
+        .context(context)
+}
+
+/// Save an attachment to the temp dir, e.g. to open it, and return its path
+#[tauri::command]
+pub async fn save_attachment_to_temp(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+    part: &str,
+    filename: Option<&str>,
+) -> Result<String> {
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let (mailbox, part) = (mailbox.to_string(), part.to_string());
+    let path = attachments::temp_path(filename);
+
+    let contents = account
+        .with_imap_session_retried(move |imap_session| {
+            attachments::fetch_attachment(imap_session, &mailbox, uid, &part)
+        })
+        .await
+        .context(context.clone())?;
+
+    let temp_path = path.clone();
+    run_blocking(move || attachments::save(&contents, &temp_path))
+        .await
+        .context(context)?;
+
+    Ok(path.to_string_lossy().to_string())
 }
 
 #[tauri::command]
@@ -107,14 +551,27 @@ pub async fn add_flags(
     uid: u32,
     flags: Vec<&str>,
 ) -> Result<()> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+
+    {
+        let mailbox = mailbox.to_string();
+        let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        account
+            .with_imap_session_retried(move |imap_session| {
+                let flags = flags.iter().map(String::as_str).collect();
+                email::add_flags(imap_session, &mailbox, uid, flags)
+            })
+            .await
+            .context(context)?;
+    }
 
-    email::add_flags(imap_session, mailbox, uid, flags)?;
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+    cache_mutex
+        .lock()
+        .await
+        .add_flags(email, mailbox, uid, &flags)?;
     Ok(())
 }
 
@@ -126,14 +583,27 @@ pub async fn remove_flags(
     uid: u32,
     flags: Vec<&str>,
 ) -> Result<()> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+
+    {
+        let mailbox = mailbox.to_string();
+        let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        account
+            .with_imap_session_retried(move |imap_session| {
+                let flags = flags.iter().map(String::as_str).collect();
+                email::remove_flags(imap_session, &mailbox, uid, flags)
+            })
+            .await
+            .context(context)?;
+    }
 
-    email::remove_flags(imap_session, mailbox, uid, flags)?;
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+    cache_mutex
+        .lock()
+        .await
+        .remove_flags(email, mailbox, uid, &flags)?;
     Ok(())
 }
 
@@ -144,27 +614,47 @@ pub async fn delete_message(
     mailbox: &str,
     uid: u32,
 ) -> Result<()> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
-
-    let mailboxes = email::get_mailboxes(imap_session).unwrap();
-    let trash = mailboxes
-        .iter()
-        .find(|m| m.attributes.contains(&"\\Trash".to_string()));
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let mailbox_name = mailbox.to_string();
 
     // Move to trash mailbox if it exists, otherwise add \Deleted flag
-    if let Some(trash) = trash {
-        if trash.name != mailbox {
-            email::move_mail(imap_session, mailbox, uid, &trash.name)?;
-            return Ok(());
-        }
+    let moved = account
+        .with_imap_session(move |imap_session| {
+            let mailboxes = email::get_mailboxes(imap_session)?;
+            let trash = mailboxes
+                .iter()
+                .find(|m| m.attributes.contains(&"\\Trash".to_string()));
+
+            if let Some(trash) = trash {
+                if trash.name != mailbox_name {
+                    email::move_mail(imap_session, &mailbox_name, uid, &trash.name)?;
+                    return Ok(true);
+                }
+            }
+
+            email::add_flags(imap_session, &mailbox_name, uid, vec!["\\Deleted"])?;
+            Ok(false)
+        })
+        .await
+        .context(context)?;
+
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+
+    if moved {
+        cache_mutex
+            .lock()
+            .await
+            .remove_messages(email, mailbox, &[uid])?;
+        remove_from_search_index(&handle, email, mailbox, uid).await?;
+        return Ok(());
     }
 
-    email::add_flags(imap_session, mailbox, uid, vec!["\\Deleted"])?;
+    cache_mutex
+        .lock()
+        .await
+        .add_flags(email, mailbox, uid, &["\\Deleted"])?;
     Ok(())
 }
 
@@ -175,105 +665,88 @@ pub async fn archive_message(
     mailbox: &str,
     uid: u32,
 ) -> Result<()> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
-
-    let mailboxes = email::get_mailboxes(imap_session).unwrap();
-    let archive = mailboxes
-        .iter()
-        .find(|m| m.attributes.contains(&"\\All".to_string()));
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let mailbox_name = mailbox.to_string();
+
+    let archived = account
+        .with_imap_session(move |imap_session| {
+            let mailboxes = email::get_mailboxes(imap_session)?;
+            let archive = mailboxes
+                .iter()
+                .find(|m| m.attributes.contains(&"\\All".to_string()));
+
+            match archive {
+                Some(archive) => {
+                    email::move_mail(imap_session, &mailbox_name, uid, &archive.name)?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+        .await
+        .context(context)?;
 
     // Move to archive mailbox if it exists, otherwise add \Deleted flag
-    if let Some(archive) = archive {
-        email::move_mail(imap_session, mailbox, uid, &archive.name)?;
+    if archived {
+        let cache_mutex = handle.state::<Mutex<Cache>>();
+        cache_mutex
+            .lock()
+            .await
+            .remove_messages(email, mailbox, &[uid])?;
+        remove_from_search_index(&handle, email, mailbox, uid).await?;
     } else {
-        return Err(Error::from("Archive mailbox not found"))?;
+        return Err(Error::new(ErrorKind::NotFound, "Archive mailbox not found"));
     }
 
     Ok(())
 }
 
 #[tauri::command]
-pub async fn send_email(
-    handle: tauri::AppHandle,
-    from: &str,
-    to: Vec<EmailAddress>,
-    cc: Vec<EmailAddress>,
-    bcc: Vec<EmailAddress>,
-    subject: &str,
-    body: &str,
-) -> Result<String> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(from)
-        .ok_or(Error::from("Account not found"))?;
-    let auth = &account.credentials;
-    let domain = GOOGLE_SMTP_HOST;
-
-    let to: Vec<LettreMailbox> = to
-        .into_iter()
-        .map(|email| {
-            LettreMailbox::new(
-                email.name,
-                email.address.parse().expect("Invalid email address"),
-            )
-        })
-        .collect();
-
-    let cc: Vec<LettreMailbox> = cc
-        .into_iter()
-        .map(|email| {
-            LettreMailbox::new(
-                email.name,
-                email.address.parse().expect("Invalid email address"),
-            )
-        })
-        .collect();
-
-    let bcc: Vec<LettreMailbox> = bcc
-        .into_iter()
-        .map(|email| {
-            LettreMailbox::new(
-                email.name,
-                email.address.parse().expect("Invalid email address"),
-            )
-        })
-        .collect();
-
-    let mut message = Message::builder().from(
-        from.parse()
-            .expect(format!("Failed to parse sender email: {}", from).as_str()),
-    );
-
-    for recipient in to {
-        message = message.to(recipient);
-    }
-    for recipient in cc {
-        message = message.cc(recipient);
-    }
-    for recipient in bcc {
-        message = message.bcc(recipient);
+pub async fn send_email(handle: tauri::AppHandle, from: &str, draft: Draft) -> Result<SendResult> {
+    let account_config = get_account_config(&handle, from).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+
+    let max_message_size = account_config.max_message_size();
+    let sender = from.to_string();
+
+    // Forwarded parts and quoted images are fetched from the server
+    let message = if compose::needs_imap_session(&draft) {
+        account
+            .with_imap_session_retried(move |imap_session| {
+                compose::build_message(&sender, &draft, Some(imap_session), max_message_size)
+            })
+            .await?
+    } else {
+        run_blocking(move || compose::build_message(&sender, &draft, None, max_message_size))
+            .await?
+    };
+
+    let mailer = account.get_smtp_transport().await?;
+
+    let formatted = message.formatted();
+    let result = run_blocking(move || Ok(mailer.send(&message)?))
+        .await
+        .context(ErrorContext::account(from))?;
+
+    // The message is already sent, so failing to keep a copy is only reported
+    let mut sent_copy_error = None;
+    if account_config.saves_sent_messages() {
+        let saved = account
+            .with_imap_session(move |imap_session| email::save_sent(imap_session, &formatted))
+            .await
+            .context(ErrorContext::account(from));
+        if let Err(e) = saved {
+            println!("Failed to save sent message: {}", e);
+            sent_copy_error = Some(e);
+        }
     }
 
-    let message = message
-        .subject(subject)
-        .header(ContentType::TEXT_HTML)
-        .body(body.to_string())
-        .map_err(|e| Error::new(ErrorKind::Generic(e.to_string()), "Failed to create email"))?;
-
-    let mailer = SmtpTransport::relay(domain)
-        .expect("Failed to create SMTP transport")
-        .authentication(vec![Mechanism::Xoauth2])
-        .credentials(auth.into())
-        .build();
-
-    let result = mailer.send(&message)?;
-    Ok(result.code().to_string())
+    Ok(SendResult {
+        code: result.code().to_string(),
+        sent_copy_error,
+    })
 }
 
 #[tauri::command]
@@ -282,21 +755,65 @@ pub async fn save_draft(
     email: &str,
     mailbox: &str,
     uid: Option<u32>,
-    subject: Option<&str>,
-    body: Option<&str>,
-    to: Option<Vec<EmailAddress>>,
-    cc: Option<Vec<EmailAddress>>,
-    bcc: Option<Vec<EmailAddress>>,
+    draft: Draft,
 ) -> Result<u32> {
-    let app_state_mutex = handle.state::<Mutex<AppState>>();
-    let mut app_state = app_state_mutex.lock().await;
-    let account = app_state
-        .get_account(email)
-        .ok_or(Error::from("Account not found"))?;
-    let imap_session = account.get_imap_session().await?;
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::mailbox(email, mailbox);
+    let (email, mailbox) = (email.to_string(), mailbox.to_string());
+
+    account
+        .with_imap_session(move |imap_session| {
+            let message = compose::build_draft(&email, &draft, Some(&mut *imap_session))?;
+            let message_id = message
+                .headers()
+                .get_raw("Message-ID")
+                .ok_or(Error::from("Draft is missing a Message-ID"))?
+                .to_string();
+
+            // Save the draft (create new or update existing)
+            email::save_draft(
+                imap_session,
+                &mailbox,
+                uid,
+                &message.formatted(),
+                &message_id,
+            )
+        })
+        .await
+        .context(context)
+}
 
-    // Save the draft (create new or update existing)
-    let new_uid = email::save_draft(imap_session, mailbox, uid, subject, body, to, cc, bcc)?;
+/// Open a saved draft for editing
+#[tauri::command]
+pub async fn open_draft(
+    handle: tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+) -> Result<Draft> {
+    let account_config = get_account_config(&handle, email).await?;
+    let account = get_account_state(&handle, &account_config).await?;
+    let context = ErrorContext::message(email, mailbox, uid);
+    let mailbox = mailbox.to_string();
+
+    account
+        .with_imap_session_retried(move |imap_session| {
+            let raw = email::fetch_raw_message(imap_session, &mailbox, uid)?;
+            compose::parse_draft(&raw)
+        })
+        .await
+        .context(context)
+}
 
-    Ok(new_uid)
+async fn remove_from_search_index(
+    handle: &tauri::AppHandle,
+    email: &str,
+    mailbox: &str,
+    uid: u32,
+) -> Result<()> {
+    let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+    let mut search_index = search_index_mutex.lock().await;
+    search_index.remove_messages(email, mailbox, &[uid]);
+    search_index.commit()
 }
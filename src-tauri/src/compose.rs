@@ -0,0 +1,1141 @@
+// This module prefills drafts that reply to or forward a message, and builds
+// the MIME message of a draft.
+//
+// Replies set `In-Reply-To` and `References` so they stay in the conversation
+// (RFC 5322 section 3.6.4), and quote the original in both the HTML and the
+// plain text body.
+//
+// Messages are built as `multipart/mixed` around a `multipart/alternative` of
+// the text and HTML bodies. Images embedded in the HTML, as data URIs or
+// `mail-part` URLs of quoted messages, are sent as `multipart/related` parts.
+use std::fs;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::DateTime;
+use lettre::address::Envelope;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment as LettreAttachment, Mailbox as LettreMailbox, MultiPart};
+use lettre::message::{MessageBuilder, SinglePart};
+use mail_parser::decoders::html::html_to_text;
+use mail_parser::{HeaderValue, MessageParser, MimeHeaders, PartType};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Account;
+use crate::email::{self, EmailAddress, Message, Session};
+use crate::error::{Error, ErrorKind, Result};
+use crate::{attachments, inline_images};
+
+/// Subject prefixes of replies, as written by common clients and locales
+const REPLY_PREFIXES: &[&str] = &["re", "aw", "sv", "vs", "antw", "ref", "rif"];
+/// Subject prefixes of forwards
+const FORWARD_PREFIXES: &[&str] = &["fwd", "fw", "wg", "tr", "rv", "enc"];
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Draft {
+    #[serde(default)]
+    pub to: Vec<EmailAddress>,
+    #[serde(default)]
+    pub cc: Vec<EmailAddress>,
+    #[serde(default)]
+    pub bcc: Vec<EmailAddress>,
+    #[serde(default)]
+    pub subject: String,
+    /// The HTML body
+    #[serde(default)]
+    pub body: String,
+    /// The plain text body
+    #[serde(default)]
+    pub text: String,
+    /// The Message-ID replied to, without angle brackets
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    /// The Message-IDs of the conversation, oldest first, without angle brackets
+    #[serde(default)]
+    pub references: Vec<String>,
+    /// Parts of other messages to attach, e.g. the attachments of a forwarded message
+    #[serde(default)]
+    pub forwarded: Vec<ForwardedPart>,
+    /// Files to attach
+    #[serde(default)]
+    pub attachments: Vec<OutgoingAttachment>,
+}
+
+/// A file attached to an outgoing message, read from disk or given as base64
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OutgoingAttachment {
+    pub filename: String,
+    /// The MIME type, guessed from the file name if not set
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// The path of a local file
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The base64 encoded content, e.g. of a pasted image
+    #[serde(default)]
+    pub data: Option<String>,
+    /// The Content-ID referenced by `cid:` URLs in the HTML body, without angle brackets
+    #[serde(default)]
+    pub content_id: Option<String>,
+}
+
+/// A part of a message on the server, attached when the draft is sent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForwardedPart {
+    pub mailbox: String,
+    pub uid: u32,
+    /// The body section, or `None` to attach the whole message as `message/rfc822`
+    pub part: Option<String>,
+    pub filename: String,
+    pub mime_type: String,
+    /// The decoded size in bytes, if known
+    pub size: Option<usize>,
+}
+
+/// The outcome of sending a message
+#[derive(Debug, Serialize)]
+pub struct SendResult {
+    /// The SMTP reply code
+    pub code: String,
+    /// Why no copy was saved to the Sent mailbox, the message itself was sent
+    pub sent_copy_error: Option<Error>,
+}
+
+/// Prefill a reply to a message
+///
+/// # Arguments
+/// * `message` - The message to reply to
+/// * `account` - The account replying, whose addresses are left out of the recipients
+/// * `all` - Whether to reply to all recipients
+/// # Returns
+/// * `Draft` - The prefilled reply
+///
+pub fn reply(message: &Message, account: &Account, all: bool) -> Draft {
+    let is_own = |address: &EmailAddress| account.owns_address(&address.address);
+
+    // Replying to our own message continues the conversation with its recipients
+    let sent_by_us = message.from.iter().any(is_own);
+    let mut to = if sent_by_us {
+        message.to.clone()
+    } else if !message.reply_to.is_empty() {
+        message.reply_to.clone()
+    } else {
+        message.from.clone()
+    };
+    let mut cc = vec![];
+
+    if all {
+        if !sent_by_us {
+            to.extend(message.to.iter().cloned());
+        }
+        cc.extend(message.cc.iter().cloned());
+    }
+
+    let mut seen = vec![];
+    to = unique_recipients(to, &is_own, &mut seen);
+    cc = unique_recipients(cc, &is_own, &mut seen);
+
+    // Replying to a message we sent only to ourselves
+    if to.is_empty() && cc.is_empty() {
+        to = message.from.clone();
+    }
+
+    let message_id = message.message_id();
+    let mut references = references(message);
+    references.extend(message_id.clone());
+
+    let attribution = attribution(message);
+    let body = format!(
+        "<br><br><div>{}</div><blockquote type=\"cite\" style=\"margin:0 0 0 .8ex;border-left:1px solid #ccc;padding-left:1ex\">{}</blockquote>",
+        escape_html(&attribution),
+        message.body
+    );
+    let text = format!("\n\n{}\n{}", attribution, quote_text(&message.text));
+
+    Draft {
+        to,
+        cc,
+        subject: prefix_subject(message.subject.as_deref(), "Re", REPLY_PREFIXES),
+        body,
+        text,
+        in_reply_to: message_id,
+        references,
+        ..Default::default()
+    }
+}
+
+/// Prefill a forward of a message
+///
+/// # Arguments
+/// * `message` - The message to forward
+/// * `as_attachment` - Whether to attach the original as `message/rfc822` instead of quoting it
+/// # Returns
+/// * `Draft` - The prefilled forward, without recipients
+///
+pub fn forward(message: &Message, as_attachment: bool) -> Draft {
+    let subject = prefix_subject(message.subject.as_deref(), "Fwd", FORWARD_PREFIXES);
+    let mailbox = message.mailbox_name.clone();
+    let Some(uid) = message.uid else {
+        return Draft {
+            subject,
+            ..Default::default()
+        };
+    };
+
+    if as_attachment {
+        let filename = format!(
+            "{}.eml",
+            sanitize_filename(message.subject.as_deref().unwrap_or("message"))
+        );
+        return Draft {
+            subject,
+            forwarded: vec![ForwardedPart {
+                mailbox,
+                uid,
+                part: None,
+                filename,
+                mime_type: "message/rfc822".to_string(),
+                size: None,
+            }],
+            ..Default::default()
+        };
+    }
+
+    let headers = forwarded_headers(message);
+    let body = format!(
+        "<br><br><div>---------- Forwarded message ---------<br>{}</div><br>{}",
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, escape_html(value)))
+            .collect::<Vec<_>>()
+            .join("<br>"),
+        message.body
+    );
+    let text = format!(
+        "\n\n---------- Forwarded message ---------\n{}\n\n{}",
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        message.text
+    );
+
+    // Embedded images are carried by the quoted body
+    let forwarded = message
+        .attachments
+        .iter()
+        .filter(|attachment| !(attachment.inline && attachment.content_id.is_some()))
+        .map(|attachment| ForwardedPart {
+            mailbox: mailbox.clone(),
+            uid,
+            part: Some(attachment.part.clone()),
+            filename: attachment
+                .filename
+                .clone()
+                .unwrap_or_else(|| "attachment".to_string()),
+            mime_type: attachment.mime_type.clone(),
+            size: Some(attachment.size),
+        })
+        .collect();
+
+    Draft {
+        subject,
+        body,
+        text,
+        forwarded,
+        ..Default::default()
+    }
+}
+
+/// Fetch the content of a forwarded part
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `forwarded` - The part to fetch
+/// # Returns
+/// * `Result<Vec<u8>>` - The decoded part, or the raw message
+///
+pub fn fetch_forwarded(session: &mut Session, forwarded: &ForwardedPart) -> Result<Vec<u8>> {
+    match &forwarded.part {
+        Some(part) => {
+            attachments::fetch_attachment(session, &forwarded.mailbox, forwarded.uid, part)
+        }
+        None => email::fetch_raw_message(session, &forwarded.mailbox, forwarded.uid),
+    }
+}
+
+/// Whether building the message of a draft needs an IMAP session, see `build_message`
+pub fn needs_imap_session(draft: &Draft) -> bool {
+    !draft.forwarded.is_empty() || draft.body.contains(inline_images::PROTOCOL)
+}
+
+/// A part of an outgoing message
+struct Part {
+    filename: String,
+    mime_type: String,
+    content_id: Option<String>,
+    contents: Vec<u8>,
+}
+
+/// Build the MIME message of a draft
+///
+/// # Arguments
+/// * `from` - The email address of the sending account
+/// * `draft` - The draft
+/// * `session` - An IMAP session of the account, needed to fetch forwarded and quoted parts
+/// * `max_size` - The largest message the server accepts in bytes
+/// # Returns
+/// * `Result<lettre::Message>` - The message, ready to be sent or appended
+///
+pub fn build_message(
+    from: &str,
+    draft: &Draft,
+    session: Option<&mut Session>,
+    max_size: usize,
+) -> Result<lettre::Message> {
+    build(from, draft, session, max_size, false)
+}
+
+/// Build the MIME message of a draft to store on the server
+///
+/// Drafts are built like sent messages, but keep their `Bcc` header and have no size limit.
+///
+/// # Arguments
+/// * `from` - The email address of the sending account
+/// * `draft` - The draft
+/// * `session` - An IMAP session of the account, needed to fetch forwarded and quoted parts
+/// # Returns
+/// * `Result<lettre::Message>` - The message, ready to be appended
+///
+pub fn build_draft(
+    from: &str,
+    draft: &Draft,
+    session: Option<&mut Session>,
+) -> Result<lettre::Message> {
+    build(from, draft, session, usize::MAX, true)
+}
+
+fn build(
+    from: &str,
+    draft: &Draft,
+    mut session: Option<&mut Session>,
+    max_size: usize,
+    keep_bcc: bool,
+) -> Result<lettre::Message> {
+    let mut builder = headers(from, draft)?;
+    if keep_bcc {
+        // Drafts are only stored, so they may not have recipients yet
+        let sender: lettre::Address = from.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidAddress,
+                format!("Invalid sender address: {}", from),
+            )
+        })?;
+        let envelope = Envelope::new(Some(sender.clone()), vec![sender])
+            .map_err(|e| Error::new(ErrorKind::Generic(e.to_string()), "Failed to create email"))?;
+        builder = builder.keep_bcc().envelope(envelope);
+    }
+    let mut size = 0;
+
+    let mut inline = vec![];
+    let mut attached = vec![];
+    for attachment in &draft.attachments {
+        let part = load_attachment(attachment)?;
+        size += part.contents.len();
+        check_size(encoded_size(size), max_size)?;
+
+        if part.content_id.is_some() {
+            inline.push(part);
+        } else {
+            attached.push(part);
+        }
+    }
+    for forwarded in &draft.forwarded {
+        let session = session
+            .as_deref_mut()
+            .ok_or(Error::from("Forwarding needs an IMAP session"))?;
+        let contents = fetch_forwarded(session, forwarded)?;
+        size += contents.len();
+        check_size(encoded_size(size), max_size)?;
+
+        attached.push(Part {
+            filename: forwarded.filename.clone(),
+            mime_type: forwarded.mime_type.clone(),
+            content_id: None,
+            contents,
+        });
+    }
+
+    let html = embed_images(&draft.body, from, &mut session, &mut inline)?;
+    let text = if draft.text.trim().is_empty() {
+        html_to_text(&html)
+    } else {
+        draft.text.clone()
+    };
+
+    let html = SinglePart::html(html);
+    let alternative = MultiPart::alternative().singlepart(SinglePart::plain(text));
+    let mut body = if inline.is_empty() {
+        alternative.singlepart(html)
+    } else {
+        let mut related = MultiPart::related().singlepart(html);
+        for part in inline {
+            let content_type = content_type(&part.mime_type)?;
+            let content_id = part.content_id.unwrap_or_default();
+            related = related.singlepart(
+                LettreAttachment::new_inline(content_id).body(part.contents, content_type),
+            );
+        }
+        alternative.multipart(related)
+    };
+
+    if !attached.is_empty() {
+        let mut mixed = MultiPart::mixed().multipart(body);
+        for part in attached {
+            let content_type = content_type(&part.mime_type)?;
+            mixed = mixed
+                .singlepart(LettreAttachment::new(part.filename).body(part.contents, content_type));
+        }
+        body = mixed;
+    }
+
+    let message = builder.multipart(body).map_err(|e| match e {
+        lettre::error::Error::MissingTo => {
+            Error::new(ErrorKind::InvalidAddress, "The message has no recipients")
+        }
+        e => Error::new(ErrorKind::Generic(e.to_string()), "Failed to create email"),
+    })?;
+
+    check_size(message.formatted().len(), max_size)?;
+    Ok(message)
+}
+
+/// The address and threading headers of a draft
+fn headers(from: &str, draft: &Draft) -> Result<MessageBuilder> {
+    let from: LettreMailbox = from.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidAddress,
+            format!("Invalid sender address: {}", from),
+        )
+    })?;
+    let mut builder = lettre::Message::builder()
+        .from(from)
+        .date_now()
+        .message_id(Some(format!("<{}@mail-client>", Uuid::new_v4())));
+
+    for recipient in &draft.to {
+        builder = builder.to(mailbox(recipient)?);
+    }
+    for recipient in &draft.cc {
+        builder = builder.cc(mailbox(recipient)?);
+    }
+    for recipient in &draft.bcc {
+        builder = builder.bcc(mailbox(recipient)?);
+    }
+
+    builder = builder.subject(draft.subject.clone());
+    if let Some(in_reply_to) = &draft.in_reply_to {
+        builder = builder.in_reply_to(message_ids_header([in_reply_to]));
+    }
+    if !draft.references.is_empty() {
+        builder = builder.references(message_ids_header(&draft.references));
+    }
+
+    Ok(builder)
+}
+
+fn mailbox(address: &EmailAddress) -> Result<LettreMailbox> {
+    let email = address.address.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidAddress,
+            format!("Invalid email address: {}", address.address),
+        )
+    })?;
+    Ok(LettreMailbox::new(address.name.clone(), email))
+}
+
+fn load_attachment(attachment: &OutgoingAttachment) -> Result<Part> {
+    let contents = match (&attachment.path, &attachment.data) {
+        (Some(path), _) => fs::read(path)?,
+        (None, Some(data)) => STANDARD.decode(data.trim()).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid attachment data: {}", e),
+            )
+        })?,
+        (None, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Attachment has no content: {}", attachment.filename),
+            ))
+        }
+    };
+
+    Ok(Part {
+        filename: attachment.filename.clone(),
+        mime_type: attachment
+            .mime_type
+            .clone()
+            .unwrap_or_else(|| guess_mime_type(&attachment.filename).to_string()),
+        content_id: attachment
+            .content_id
+            .as_ref()
+            .map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string()),
+        contents,
+    })
+}
+
+/// Replace the data URIs and `mail-part` URLs of images with `cid:` references to inline parts
+///
+/// Only the `src` attribute of `<img>` tags is rewritten. Images of other
+/// accounts are removed, their URL would reveal the account and mailbox.
+fn embed_images(
+    html: &str,
+    account: &str,
+    session: &mut Option<&mut Session>,
+    inline: &mut Vec<Part>,
+) -> Result<String> {
+    // Lowercasing ASCII keeps the byte offsets, so indices apply to both
+    let lower = html.to_ascii_lowercase();
+    let mut embedded = String::with_capacity(html.len());
+    let mut position = 0;
+
+    while let Some(tag_start) = find_img_tag(&lower, position) {
+        let Some(tag_end) = find_tag_end(html, tag_start) else {
+            break;
+        };
+        embedded.push_str(&html[position..tag_start]);
+        position = tag_end;
+
+        let tag = &html[tag_start..tag_end];
+        let Some((value_start, value_end)) = src_attribute(&lower[tag_start..tag_end]) else {
+            embedded.push_str(tag);
+            continue;
+        };
+
+        match image_part(&tag[value_start..value_end], account, session)? {
+            Image::Embedded(mime_type, contents) => {
+                let content_id = format!("{}@mail-client", Uuid::new_v4());
+                embedded.push_str(&tag[..value_start]);
+                embedded.push_str(&format!("cid:{}", content_id));
+                embedded.push_str(&tag[value_end..]);
+                inline.push(Part {
+                    filename: content_id.clone(),
+                    mime_type,
+                    content_id: Some(content_id),
+                    contents,
+                });
+            }
+            Image::Unchanged => embedded.push_str(tag),
+            Image::Removed => {}
+        }
+    }
+    embedded.push_str(&html[position..]);
+
+    Ok(embedded)
+}
+
+/// Find the next `<img>` tag in lowercased HTML
+fn find_img_tag(lower: &str, from: usize) -> Option<usize> {
+    let mut position = from;
+    loop {
+        let start = lower[position..].find("<img").map(|i| i + position)?;
+        let next = lower[start + 4..].chars().next();
+        if next.is_some_and(|c| c.is_ascii_whitespace() || c == '/' || c == '>') {
+            return Some(start);
+        }
+        position = start + 4;
+    }
+}
+
+/// The end of the tag starting at `start`, just after its `>`
+fn find_tag_end(html: &str, start: usize) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(start + i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The range of the `src` attribute value in a lowercased tag like `<img alt="" src="...">`
+fn src_attribute(tag: &str) -> Option<(usize, usize)> {
+    let bytes = tag.as_bytes();
+    // Skip the tag name
+    let mut i = 4;
+
+    while i < bytes.len() {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        let name_start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"=/>".contains(&bytes[i]) {
+            i += 1;
+        }
+        if i == name_start {
+            return None;
+        }
+        let name = &tag[name_start..i];
+
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if bytes.get(i) != Some(&b'=') {
+            // An attribute without a value, e.g. `hidden`
+            continue;
+        }
+        i += 1;
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let (value_start, value_end) = match bytes.get(i) {
+            Some(&quote) if quote == b'"' || quote == b'\'' => {
+                let end = tag[i + 1..].find(quote as char)? + i + 1;
+                (i + 1, end)
+            }
+            _ => {
+                let end = tag[i..]
+                    .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                    .map_or(tag.len(), |end| end + i);
+                (i, end)
+            }
+        };
+        if name == "src" {
+            return Some((value_start, value_end));
+        }
+        i = value_end + 1;
+    }
+
+    None
+}
+
+/// What to do with an image of the HTML body
+enum Image {
+    /// Embed the image as an inline part with this MIME type and content
+    Embedded(String, Vec<u8>),
+    Unchanged,
+    Removed,
+}
+
+/// Decide what to do with an image of the HTML body, fetching it from the server if needed
+fn image_part(url: &str, account: &str, session: &mut Option<&mut Session>) -> Result<Image> {
+    if let Some(data) = url.strip_prefix("data:") {
+        let Some((mime_type, data)) = data.split_once(";base64,") else {
+            return Ok(Image::Unchanged);
+        };
+        return Ok(match STANDARD.decode(data.trim()) {
+            Ok(contents) => Image::Embedded(mime_type.to_string(), contents),
+            Err(_) => Image::Unchanged,
+        });
+    }
+
+    // Quoted images of large parts are only on the server
+    let Some(location) = inline_images::parse_part_url(url) else {
+        return Ok(Image::Unchanged);
+    };
+    let Some(session) = session
+        .as_deref_mut()
+        .filter(|_| location.account == account)
+    else {
+        return Ok(Image::Removed);
+    };
+
+    let (mime_type, contents) =
+        attachments::fetch_part(session, &location.mailbox, location.uid, &location.part)?;
+    Ok(Image::Embedded(mime_type, contents))
+}
+
+fn content_type(mime_type: &str) -> Result<ContentType> {
+    ContentType::parse(mime_type)
+        .or_else(|_| ContentType::parse("application/octet-stream"))
+        .map_err(|e| Error::from(e.to_string()))
+}
+
+/// The size of content once base64 encoded, with line breaks every 76 characters
+fn encoded_size(size: usize) -> usize {
+    let encoded = size.div_ceil(3) * 4;
+    encoded + encoded / 76 * 2
+}
+
+fn check_size(size: usize, max_size: usize) -> Result<()> {
+    if size <= max_size {
+        return Ok(());
+    }
+
+    let megabytes = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "The message is {:.1} MB, larger than the {:.1} MB the server accepts",
+            megabytes(size),
+            megabytes(max_size)
+        ),
+    ))
+}
+
+/// Guess the MIME type of a file from its extension
+fn guess_mime_type(filename: &str) -> &'static str {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "txt" | "log" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "ics" => "text/calendar",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "json" => "application/json",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "eml" => "message/rfc822",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Read a draft back from the message stored on the server
+///
+/// Inline images are turned back into data URIs, so the body can be edited and
+/// saved again without referring to the replaced message.
+///
+/// # Arguments
+/// * `raw` - The raw RFC822 message
+/// # Returns
+/// * `Result<Draft>` - The draft
+///
+pub fn parse_draft(raw: &[u8]) -> Result<Draft> {
+    let parsed = MessageParser::new()
+        .parse(raw)
+        .ok_or(Error::from("Could not parse draft"))?;
+
+    let (mut html, text) = email::message_bodies(&parsed);
+    let mut attachments = vec![];
+    for part in parsed.attachments() {
+        let mime_type = attachments::mime_type(part);
+        let content_id = part
+            .content_id()
+            .map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string());
+
+        if let Some(content_id) = content_id.as_ref().filter(|id| html.contains(id.as_str())) {
+            let data_uri = format!(
+                "data:{};base64,{}",
+                mime_type,
+                STANDARD.encode(part.contents())
+            );
+            html = html.replace(&format!("cid:{}", content_id), &data_uri);
+            continue;
+        }
+
+        attachments.push(OutgoingAttachment {
+            filename: part.attachment_name().unwrap_or("attachment").to_string(),
+            mime_type: Some(mime_type),
+            path: None,
+            data: Some(STANDARD.encode(part.contents())),
+            content_id,
+        });
+    }
+
+    let message_ids = |value: &HeaderValue| match value {
+        HeaderValue::Text(id) => vec![id.to_string()],
+        HeaderValue::TextList(ids) => ids.iter().map(|id| id.to_string()).collect(),
+        _ => vec![],
+    };
+
+    let has_html = parsed.html_body.iter().any(|id| {
+        parsed
+            .part(*id)
+            .is_some_and(|part| matches!(part.body, PartType::Html(_)))
+    });
+
+    Ok(Draft {
+        to: email::parse_addrs(parsed.to()).unwrap_or_default(),
+        cc: email::parse_addrs(parsed.cc()).unwrap_or_default(),
+        bcc: email::parse_addrs(parsed.bcc()).unwrap_or_default(),
+        subject: parsed.subject().unwrap_or_default().to_string(),
+        // The text body is derived from the HTML body again when it is saved
+        text: if has_html { String::new() } else { text },
+        body: html,
+        in_reply_to: message_ids(parsed.in_reply_to()).into_iter().next(),
+        references: message_ids(parsed.references()),
+        forwarded: vec![],
+        attachments,
+    })
+}
+
+/// Format Message-IDs for the `In-Reply-To` and `References` headers
+pub fn message_ids_header<'a>(ids: impl IntoIterator<Item = &'a String>) -> String {
+    ids.into_iter()
+        .map(|id| format!("<{}>", id.trim_matches(|c| c == '<' || c == '>')))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The References of a message, falling back to In-Reply-To (RFC 5322 section 3.6.4)
+fn references(message: &Message) -> Vec<String> {
+    let header = |name: &str| {
+        message
+            .headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    header("References")
+        .or_else(|| header("In-Reply-To"))
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Drop our own addresses and addresses already in `seen`
+fn unique_recipients(
+    recipients: Vec<EmailAddress>,
+    is_own: &impl Fn(&EmailAddress) -> bool,
+    seen: &mut Vec<String>,
+) -> Vec<EmailAddress> {
+    recipients
+        .into_iter()
+        .filter(|recipient| {
+            let address = recipient.address.to_lowercase();
+            if is_own(recipient) || seen.contains(&address) {
+                return false;
+            }
+            seen.push(address);
+            true
+        })
+        .collect()
+}
+
+/// Add a prefix to a subject, unless it already has one of the given prefixes
+fn prefix_subject(subject: Option<&str>, prefix: &str, prefixes: &[&str]) -> String {
+    let subject = subject.unwrap_or_default().trim();
+    if has_prefix(subject, prefixes) {
+        subject.to_string()
+    } else {
+        format!("{}: {}", prefix, subject)
+    }
+}
+
+/// Whether a subject starts with a prefix like `Re:`, `RE[2]:` or `Fwd :`
+fn has_prefix(subject: &str, prefixes: &[&str]) -> bool {
+    let Some((start, _)) = subject.split_once(':') else {
+        return false;
+    };
+    let start = start.trim();
+    // Some clients count replies, e.g. "Re[2]" or "Re^2"
+    let start = start
+        .split(|c| c == '[' || c == '^')
+        .next()
+        .unwrap_or_default();
+
+    prefixes
+        .iter()
+        .any(|prefix| start.eq_ignore_ascii_case(prefix))
+}
+
+/// The line introducing a quoted message, e.g. "On Mon, Jan 6, 2025 at 09:30, Jane <jane@example.com> wrote:"
+fn attribution(message: &Message) -> String {
+    let from = message
+        .from
+        .first()
+        .map(format_address)
+        .unwrap_or_else(|| "Unknown sender".to_string());
+
+    match message.date.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(date)) => format!(
+            "On {}, {} wrote:",
+            date.format("%a, %b %-d, %Y at %H:%M"),
+            from
+        ),
+        _ => format!("{} wrote:", from),
+    }
+}
+
+fn forwarded_headers(message: &Message) -> Vec<(&'static str, String)> {
+    let addresses = |addresses: &[EmailAddress]| {
+        addresses
+            .iter()
+            .map(format_address)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut headers = vec![("From", addresses(&message.from))];
+    if let Some(date) = message
+        .date
+        .as_deref()
+        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+    {
+        headers.push(("Date", date.format("%a, %b %-d, %Y at %H:%M").to_string()));
+    }
+    headers.push(("Subject", message.subject.clone().unwrap_or_default()));
+    headers.push(("To", addresses(&message.to)));
+    if !message.cc.is_empty() {
+        headers.push(("Cc", addresses(&message.cc)));
+    }
+
+    headers
+}
+
+fn format_address(address: &EmailAddress) -> String {
+    match &address.name {
+        Some(name) if !name.is_empty() => format!("{} <{}>", name, address.address),
+        _ => address.address.clone(),
+    }
+}
+
+/// Quote plain text with `>`, as in RFC 3676
+fn quote_text(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            if line.starts_with('>') || line.is_empty() {
+                format!(">{}", line)
+            } else {
+                format!("> {}", line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let name = name.trim();
+
+    if name.is_empty() {
+        "message".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> EmailAddress {
+        EmailAddress {
+            name: None,
+            address: address.to_string(),
+        }
+    }
+
+    fn draft(to: &[&str]) -> Draft {
+        Draft {
+            to: to.iter().map(|to| address(to)).collect(),
+            subject: "Hello".to_string(),
+            body: "<p>Hello</p>".to_string(),
+            ..Draft::default()
+        }
+    }
+
+    fn is_invalid_address<T>(result: &Result<T>) -> bool {
+        matches!(result, Err(e) if matches!(e.kind(), ErrorKind::InvalidAddress))
+    }
+
+    #[test]
+    fn rejects_malformed_recipients() {
+        let malformed = [
+            "",
+            "not an address",
+            "@example.com",
+            "user@",
+            "user@@example.com",
+            "a@b@example.com",
+            "<user@example.com>",
+            "user@exa mple.com",
+        ];
+
+        for to in malformed {
+            let result = build_message("me@example.com", &draft(&[to]), None, usize::MAX);
+            assert!(is_invalid_address(&result), "accepted recipient {:?}", to);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_cc_and_bcc() {
+        let mut cc = draft(&["you@example.com"]);
+        cc.cc = vec![address("not an address")];
+        assert!(is_invalid_address(&build_message(
+            "me@example.com",
+            &cc,
+            None,
+            usize::MAX
+        )));
+
+        let mut bcc = draft(&["you@example.com"]);
+        bcc.bcc = vec![address("user@")];
+        assert!(is_invalid_address(&build_draft(
+            "me@example.com",
+            &bcc,
+            None
+        )));
+    }
+
+    #[test]
+    fn rejects_malformed_sender() {
+        for from in ["", "me", "me@", "me @example.com"] {
+            let result = build_message(from, &draft(&["you@example.com"]), None, usize::MAX);
+            assert!(is_invalid_address(&result), "accepted sender {:?}", from);
+        }
+    }
+
+    #[test]
+    fn rejects_message_without_recipients() {
+        let result = build_message("me@example.com", &draft(&[]), None, usize::MAX);
+        assert!(is_invalid_address(&result));
+    }
+
+    #[test]
+    fn saves_draft_without_recipients() {
+        let message = build_draft("me@example.com", &draft(&[]), None).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(formatted.contains("From: me@example.com"));
+        assert!(!formatted.contains("To:"));
+    }
+
+    #[test]
+    fn accepts_well_formed_addresses() {
+        let mut draft = draft(&["you@example.com"]);
+        draft.cc = vec![EmailAddress {
+            name: Some("Someone, Else".to_string()),
+            address: "else@example.com".to_string(),
+        }];
+
+        assert!(build_message("me@example.com", &draft, None, usize::MAX).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_attachments() {
+        let mut draft = draft(&["you@example.com"]);
+        draft.attachments = vec![OutgoingAttachment {
+            filename: "file.txt".to_string(),
+            mime_type: None,
+            path: None,
+            data: Some("not base64!".to_string()),
+            content_id: None,
+        }];
+        assert!(build_message("me@example.com", &draft, None, usize::MAX).is_err());
+
+        draft.attachments[0].data = None;
+        assert!(build_message("me@example.com", &draft, None, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn forwarding_without_session_is_an_error() {
+        let mut draft = draft(&["you@example.com"]);
+        draft.forwarded = vec![ForwardedPart {
+            mailbox: "INBOX".to_string(),
+            uid: 1,
+            part: None,
+            filename: "message.eml".to_string(),
+            mime_type: "message/rfc822".to_string(),
+            size: None,
+        }];
+
+        assert!(build_message("me@example.com", &draft, None, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn parses_malformed_drafts_without_panicking() {
+        let malformed: [&[u8]; 5] = [
+            b"",
+            b"\xff\xfe\x00garbage",
+            b"Content-Type: multipart/mixed; boundary=\"x\"\r\n\r\n--x\r\n",
+            b"Subject: =?utf-8?B?!!!?=\r\n\r\n<img src=\"cid:missing\">",
+            b"Content-Type: text/html\r\nContent-Transfer-Encoding: base64\r\n\r\n%%%",
+        ];
+
+        for raw in malformed {
+            // Only a panic fails the test, both results are fine
+            let _ = parse_draft(raw);
+        }
+    }
+
+    fn embed(html: &str) -> (String, Vec<Part>) {
+        let mut inline = vec![];
+        let embedded = embed_images(html, "me@example.com", &mut None, &mut inline).unwrap();
+        (embedded, inline)
+    }
+
+    #[test]
+    fn embeds_data_uri_images() {
+        let (html, inline) = embed(r#"<p>Hi</p><IMG alt="x" SRC='data:image/png;base64,aGk='>"#);
+
+        assert_eq!(inline.len(), 1);
+        assert_eq!(inline[0].mime_type, "image/png");
+        assert_eq!(inline[0].contents, b"hi");
+        let content_id = inline[0].content_id.as_ref().unwrap();
+        assert_eq!(
+            html,
+            format!(r#"<p>Hi</p><IMG alt="x" SRC='cid:{}'>"#, content_id)
+        );
+    }
+
+    #[test]
+    fn only_rewrites_src_of_img_tags() {
+        let html = concat!(
+            r#"<p>Set src="data:image/png;base64,aGk=" in the editor</p>"#,
+            r#"<img data-src="data:image/png;base64,aGk=" alt="src=x">"#,
+            r#"<iframe src="data:image/png;base64,aGk="></iframe>"#,
+            r#"<imgx src="data:image/png;base64,aGk=">"#,
+        );
+
+        let (embedded, inline) = embed(html);
+
+        assert_eq!(embedded, html);
+        assert!(inline.is_empty());
+    }
+
+    #[test]
+    fn removes_images_of_other_accounts() {
+        let url = inline_images::part_url("other@example.com", "INBOX", 7, "2");
+        let html = format!(r#"<p>Quoted</p><img src="{}" alt="logo"><p>End</p>"#, url);
+
+        let (embedded, inline) = embed(&html);
+
+        assert_eq!(embedded, "<p>Quoted</p><p>End</p>");
+        assert!(inline.is_empty());
+    }
+}
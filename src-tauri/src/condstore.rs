@@ -0,0 +1,299 @@
+// This module implements the parts of CONDSTORE and QRESYNC (RFC 7162) the
+// imap crate does not support. Commands are sent raw and the untagged
+// responses are parsed here.
+use crate::email::{self, Session};
+use crate::error::Result;
+
+/// The state reported when selecting a mailbox
+#[derive(Debug, Default)]
+pub struct SelectInfo {
+    pub exists: u32,
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    /// `None` if the server does not support CONDSTORE or the mailbox has no mod-sequences
+    pub highest_modseq: Option<u64>,
+    /// UIDs expunged since the known mod-sequence, only with QRESYNC
+    pub vanished: UidRanges,
+    /// Messages whose flags changed since the known mod-sequence, only with QRESYNC
+    pub changed: Vec<FlagUpdate>,
+}
+
+/// A set of UIDs like `1:3,7`
+///
+/// The ranges are not expanded, since servers may report ranges spanning the
+/// whole UID space, e.g. `VANISHED (EARLIER) 1:4294967295`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UidRanges(Vec<(u32, u32)>);
+
+impl UidRanges {
+    /// Add the ranges of a sequence set like `1:3,7`, ignoring malformed parts
+    fn extend_parsed(&mut self, set: &str) {
+        for part in set.split(',') {
+            let (start, end) = part.split_once(':').unwrap_or((part, part));
+            if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+                self.0.push((start.min(end), start.max(end)));
+            }
+        }
+    }
+
+    pub fn contains(&self, uid: u32) -> bool {
+        self.0
+            .iter()
+            .any(|&(start, end)| start <= uid && uid <= end)
+    }
+
+    /// The UIDs of the set that are in `uids`
+    pub fn intersect(&self, uids: &[u32]) -> Vec<u32> {
+        uids.iter()
+            .copied()
+            .filter(|&uid| self.contains(uid))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagUpdate {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub modseq: Option<u64>,
+}
+
+/// The extensions supported by the server
+#[derive(Debug, Clone, Copy)]
+pub struct Support {
+    pub condstore: bool,
+    pub qresync: bool,
+}
+
+pub fn get_support(session: &mut Session) -> Result<Support> {
+    let capabilities = session.capabilities()?;
+    let qresync = capabilities.has_str("QRESYNC");
+
+    Ok(Support {
+        // QRESYNC implies CONDSTORE
+        condstore: qresync || capabilities.has_str("CONDSTORE"),
+        qresync,
+    })
+}
+
+/// Enable QRESYNC for the session, required before using it in SELECT
+///
+/// The server then reports expunged messages with VANISHED instead of EXPUNGE,
+/// which the `imap` crate cannot parse, so the session is closed once used.
+pub fn enable_qresync(session: &mut Session) -> Result<()> {
+    session.close_after_use();
+    session.run_command_and_check_ok("ENABLE QRESYNC")?;
+    Ok(())
+}
+
+/// Select a mailbox, using CONDSTORE or QRESYNC if supported
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// * `support` - The extensions supported by the server
+/// * `known` - The UIDVALIDITY and HIGHESTMODSEQ from the last sync, used for QRESYNC
+/// # Returns
+/// * `Result<SelectInfo>` - The mailbox state and, with QRESYNC, the changes since `known`
+///
+pub fn select(
+    session: &mut Session,
+    mailbox: &str,
+    support: Support,
+    known: Option<(u32, u64)>,
+) -> Result<SelectInfo> {
+    let mailbox = email::imap_string(mailbox)?;
+    let command = match (support.qresync, support.condstore, known) {
+        (true, _, Some((uid_validity, modseq))) => {
+            format!("SELECT {} (QRESYNC ({} {}))", mailbox, uid_validity, modseq)
+        }
+        (_, true, _) => format!("SELECT {} (CONDSTORE)", mailbox),
+        _ => format!("SELECT {}", mailbox),
+    };
+
+    let response =
+        session.with_literals(|session| session.run_command_and_read_response(command))?;
+    Ok(parse_select(&response))
+}
+
+/// Get the flags of messages changed since a mod-sequence (CONDSTORE)
+pub fn fetch_changed_flags(session: &mut Session, since: u64) -> Result<Vec<FlagUpdate>> {
+    let response = session
+        .run_command_and_read_response(format!("UID FETCH 1:* (FLAGS) (CHANGEDSINCE {})", since))?;
+
+    Ok(lines(&response).filter_map(parse_fetch).collect())
+}
+
+/// Get the flags of the messages in the selected mailbox from a UID on
+pub fn fetch_flags_from(session: &mut Session, from_uid: u32) -> Result<Vec<FlagUpdate>> {
+    let response =
+        session.run_command_and_read_response(format!("UID FETCH {}:* (FLAGS)", from_uid))?;
+
+    Ok(lines(&response).filter_map(parse_fetch).collect())
+}
+
+/// Split a raw response into lines
+pub fn lines(response: &[u8]) -> impl Iterator<Item = &str> {
+    response
+        .split(|b| *b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .map(|line| line.trim_end_matches('\r'))
+}
+
+fn parse_select(response: &[u8]) -> SelectInfo {
+    let mut info = SelectInfo::default();
+
+    for line in lines(response) {
+        let Some(line) = line.strip_prefix("* ") else {
+            continue;
+        };
+
+        if let Some(value) = response_code(line, "HIGHESTMODSEQ") {
+            info.highest_modseq = value.parse().ok();
+        } else if let Some(value) = response_code(line, "UIDVALIDITY") {
+            info.uid_validity = value.parse().ok();
+        } else if let Some(value) = response_code(line, "UIDNEXT") {
+            info.uid_next = value.parse().ok();
+        } else if let Some(set) = line.strip_prefix("VANISHED ") {
+            let set = set.strip_prefix("(EARLIER) ").unwrap_or(set);
+            info.vanished.extend_parsed(set.trim());
+        } else if let Some(count) = line.strip_suffix(" EXISTS") {
+            info.exists = count.parse().unwrap_or_default();
+        } else if let Some(update) = parse_fetch(line) {
+            info.changed.push(update);
+        }
+    }
+
+    info
+}
+
+/// Get the value of a response code like `OK [UIDNEXT 42] Predicted next UID`
+fn response_code<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!("[{} ", name))? + name.len() + 2;
+    let end = line[start..].find(']')? + start;
+    Some(line[start..end].trim())
+}
+
+/// Parse an untagged `n FETCH (UID x FLAGS (...) MODSEQ (m))` response
+fn parse_fetch(line: &str) -> Option<FlagUpdate> {
+    let line = line.strip_prefix("* ").unwrap_or(line);
+    let (_, attributes) = line.split_once(" FETCH (")?;
+
+    let uid = attributes
+        .split_once("UID ")?
+        .1
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+
+    let flags = attributes
+        .split_once("FLAGS (")?
+        .1
+        .split_once(')')?
+        .0
+        .split_whitespace()
+        .map(|f| f.to_string())
+        .collect();
+
+    let modseq = attributes
+        .split_once("MODSEQ (")
+        .and_then(|(_, rest)| rest.split_once(')'))
+        .and_then(|(modseq, _)| modseq.trim().parse().ok());
+
+    Some(FlagUpdate { uid, flags, modseq })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_qresync_select() {
+        let response = b"* 172 EXISTS\r\n\
+* 1 RECENT\r\n\
+* OK [UIDVALIDITY 3857529045] UIDs valid\r\n\
+* OK [UIDNEXT 4392] Predicted next UID\r\n\
+* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n\
+* OK [HIGHESTMODSEQ 715194045007] Highest\r\n\
+* VANISHED (EARLIER) 41,43:116,118,120:211\r\n\
+* 49 FETCH (UID 117 FLAGS (\\Seen \\Answered) MODSEQ (90060115194045001))\r\n\
+A02 OK [READ-WRITE] Sorry, UIDVALIDITY mismatch\r\n";
+
+        let info = parse_select(response);
+
+        assert_eq!(info.exists, 172);
+        assert_eq!(info.uid_validity, Some(3857529045));
+        assert_eq!(info.uid_next, Some(4392));
+        assert_eq!(info.highest_modseq, Some(715194045007));
+        assert_eq!(
+            info.vanished
+                .intersect(&[40, 41, 42, 43, 100, 117, 118, 150, 212]),
+            vec![41, 43, 100, 118, 150]
+        );
+        assert_eq!(
+            info.changed,
+            vec![FlagUpdate {
+                uid: 117,
+                flags: vec!["\\Seen".to_string(), "\\Answered".to_string()],
+                modseq: Some(90060115194045001),
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_select_without_condstore() {
+        let response = b"* 3 EXISTS\r\n\
+* OK [UIDVALIDITY 1] UIDs valid\r\n\
+* OK [UIDNEXT 4] Predicted next UID\r\n\
+A01 OK [READ-WRITE] SELECT completed\r\n";
+
+        let info = parse_select(response);
+
+        assert_eq!(info.exists, 3);
+        assert_eq!(info.highest_modseq, None);
+        assert_eq!(info.vanished, UidRanges::default());
+        assert!(info.changed.is_empty());
+    }
+
+    #[test]
+    fn keeps_vanished_ranges_unexpanded() {
+        let info = parse_select(b"* VANISHED (EARLIER) 1:4294967295\r\n");
+
+        assert_eq!(info.vanished, UidRanges(vec![(1, u32::MAX)]));
+        assert_eq!(
+            info.vanished.intersect(&[1, 7, u32::MAX]),
+            vec![1, 7, u32::MAX]
+        );
+    }
+
+    #[test]
+    fn parses_fetch() {
+        assert_eq!(
+            parse_fetch("* 12 FETCH (UID 42 MODSEQ (65402) FLAGS ())"),
+            Some(FlagUpdate {
+                uid: 42,
+                flags: vec![],
+                modseq: Some(65402),
+            })
+        );
+        assert_eq!(
+            parse_fetch("* 3 FETCH (FLAGS ($Label \\Flagged) UID 7)"),
+            Some(FlagUpdate {
+                uid: 7,
+                flags: vec!["$Label".to_string(), "\\Flagged".to_string()],
+                modseq: None,
+            })
+        );
+        assert_eq!(parse_fetch("* 3 EXISTS"), None);
+        assert_eq!(parse_fetch("* 3 FETCH (FLAGS (\\Seen))"), None);
+    }
+
+    #[test]
+    fn reads_response_codes() {
+        let line = "OK [UIDNEXT 4392] Predicted next UID";
+        assert_eq!(response_code(line, "UIDNEXT"), Some("4392"));
+        assert_eq!(response_code(line, "UIDVALIDITY"), None);
+        assert_eq!(response_code("OK [UIDNEXT 4392 Predicted", "UIDNEXT"), None);
+    }
+}
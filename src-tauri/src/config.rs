@@ -1,4 +1,10 @@
-use crate::error::{Error, Result};
+use crate::constants::{
+    DEFAULT_MAX_MESSAGE_SIZE, GOOGLE_IMAP_HOST, GOOGLE_MAX_MESSAGE_SIZE, GOOGLE_SMTP_HOST,
+    MICROSOFT_MAX_MESSAGE_SIZE, MICROSOFT_SMTP_HOST,
+};
+use crate::email::Message;
+use crate::error::{Error, ErrorKind, Result};
+use crate::oauth_provider::OAuthProvider;
 use serde::{Deserialize, Serialize};
 use std::fs::{create_dir_all, OpenOptions};
 use std::io::{Read, Seek, Write};
@@ -12,7 +18,172 @@ pub struct Config {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Account {
-    email: String,
+    pub email: String,
+    #[serde(default = "ServerConfig::google_imap")]
+    pub imap: ServerConfig,
+    #[serde(default = "ServerConfig::google_smtp")]
+    pub smtp: ServerConfig,
+    #[serde(default)]
+    pub remote_content: RemoteContent,
+    /// Whether links wrapped by click trackers are replaced with their destinations
+    #[serde(default)]
+    pub unwrap_tracking_links: bool,
+    /// Other addresses that deliver to this account, left out when replying to all
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// The largest message the SMTP server accepts in bytes, if it differs from the provider default
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+    /// Whether sent messages are copied to the Sent mailbox, by default unless the server does it
+    #[serde(default)]
+    pub save_sent: Option<bool>,
+}
+
+/// Senders and messages whose remote images and resources are loaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RemoteContent {
+    /// Sender email addresses
+    #[serde(default)]
+    pub senders: Vec<String>,
+    /// Message-IDs
+    #[serde(default)]
+    pub messages: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: Security,
+    pub auth: AuthMethod,
+    /// The login name, if it differs from the email address
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Security {
+    /// Implicit TLS, e.g. IMAPS on port 993 or SMTPS on port 465
+    Tls,
+    /// Plain connection upgraded with STARTTLS
+    StartTls,
+    /// Unencrypted connection, only meant for local test servers
+    Plain,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AuthMethod {
+    Login,
+    Plain,
+    XOAuth2,
+}
+
+impl RemoteContent {
+    /// Whether remote content may be loaded for a message
+    pub fn allows(&self, message: &Message) -> bool {
+        let sender_allowed = message.from.iter().any(|from| {
+            self.senders
+                .iter()
+                .any(|sender| sender.eq_ignore_ascii_case(&from.address))
+        });
+        let message_allowed = message
+            .message_id()
+            .is_some_and(|id| self.messages.contains(&id));
+
+        sender_allowed || message_allowed
+    }
+
+    pub fn allow_sender(&mut self, sender: &str) {
+        if !self.senders.iter().any(|s| s.eq_ignore_ascii_case(sender)) {
+            self.senders.push(sender.to_string());
+        }
+    }
+
+    pub fn allow_message(&mut self, message_id: &str) {
+        if !self.messages.iter().any(|m| m == message_id) {
+            self.messages.push(message_id.to_string());
+        }
+    }
+}
+
+impl Account {
+    /// Create an account which authenticates with an OAuth2 provider
+    pub fn from_provider(email: String, provider: OAuthProvider) -> Self {
+        Account {
+            email,
+            imap: provider.imap_server(),
+            smtp: provider.smtp_server(),
+            remote_content: RemoteContent::default(),
+            unwrap_tracking_links: false,
+            aliases: vec![],
+            max_message_size: None,
+            save_sent: None,
+        }
+    }
+
+    /// Whether this is a Gmail account, where the server copies sent messages itself
+    pub fn is_gmail(&self) -> bool {
+        self.imap.host == GOOGLE_IMAP_HOST || self.smtp.host == GOOGLE_SMTP_HOST
+    }
+
+    /// Whether sent messages should be appended to the Sent mailbox
+    pub fn saves_sent_messages(&self) -> bool {
+        self.save_sent.unwrap_or(!self.is_gmail())
+    }
+
+    /// The largest message the SMTP server accepts in bytes, including the encoding overhead
+    pub fn max_message_size(&self) -> usize {
+        if let Some(size) = self.max_message_size {
+            return size;
+        }
+
+        match self.smtp.host.as_str() {
+            GOOGLE_SMTP_HOST => GOOGLE_MAX_MESSAGE_SIZE,
+            MICROSOFT_SMTP_HOST => MICROSOFT_MAX_MESSAGE_SIZE,
+            _ => DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Whether an address is the address of this account or one of its aliases
+    pub fn owns_address(&self, address: &str) -> bool {
+        self.email.eq_ignore_ascii_case(address)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(address))
+    }
+
+    /// Check that the email address of the account can be parsed
+    pub fn check_email(&self) -> Result<()> {
+        match self.email.parse::<lettre::Address>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidAddress,
+                format!("Invalid email address: {}", self.email),
+            )),
+        }
+    }
+
+    /// Whether the credentials of this account are OAuth tokens
+    pub fn uses_oauth(&self) -> bool {
+        self.imap.auth == AuthMethod::XOAuth2 || self.smtp.auth == AuthMethod::XOAuth2
+    }
+}
+
+impl ServerConfig {
+    // Accounts created before server settings existed are Gmail accounts
+    fn google_imap() -> Self {
+        OAuthProvider::Google.imap_server()
+    }
+
+    fn google_smtp() -> Self {
+        OAuthProvider::Google.smtp_server()
+    }
+
+    /// The name used to log in, falls back to the email address
+    pub fn username<'a>(&'a self, email: &'a str) -> &'a str {
+        self.username.as_deref().unwrap_or(email)
+    }
 }
 
 impl Config {
@@ -50,12 +221,15 @@ impl Config {
         Ok(config)
     }
 
-    pub fn add_account(&mut self, email: String) -> Result<()> {
-        let account = Account { email };
+    pub fn add_account(&mut self, account: Account) -> Result<()> {
+        account.check_email()?;
 
         // Check if the account already exists
-        if self.accounts.contains(&account) {
-            return Err(Error::from("Account already exists"));
+        if self.account(&account.email).is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Account already exists",
+            ));
         }
 
         // Add the new account
@@ -64,19 +238,27 @@ impl Config {
         Ok(())
     }
 
-    pub fn remove_account(&mut self, email: &str) -> Result<()> {
-        let account = Account {
-            email: email.to_string(),
-        };
+    pub fn update_account(&mut self, account: Account) -> Result<()> {
+        let existing = self
+            .accounts
+            .iter_mut()
+            .find(|a| a.email == account.email)
+            .ok_or(Error::new(ErrorKind::NotFound, "Account not found"))?;
+
+        *existing = account;
+        self.save_config()?;
+        Ok(())
+    }
 
+    pub fn remove_account(&mut self, email: &str) -> Result<()> {
         // Check if the account exists
-        if let Some(pos) = self.accounts.iter().position(|x| *x == account) {
+        if let Some(pos) = self.accounts.iter().position(|x| x.email == email) {
             // Remove the account
             self.accounts.remove(pos);
             self.save_config()?;
             Ok(())
         } else {
-            Err(Error::from("Account not found"))
+            Err(Error::new(ErrorKind::NotFound, "Account not found"))
         }
     }
 
@@ -101,4 +283,52 @@ impl Config {
     pub fn accounts(&self) -> &[Account] {
         &self.accounts
     }
+
+    pub fn account(&self, email: &str) -> Option<&Account> {
+        self.accounts.iter().find(|a| a.email == email)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(email: &str) -> Account {
+        Account::from_provider(email.to_string(), OAuthProvider::Google)
+    }
+
+    #[test]
+    fn accepts_well_formed_email() {
+        assert!(account("user@example.com").check_email().is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_email() {
+        for email in [
+            "",
+            "user",
+            "user@",
+            "@example.com",
+            "a@b@example.com",
+            "user @example.com",
+        ] {
+            let result = account(email).check_email();
+            assert!(
+                matches!(&result, Err(e) if matches!(e.kind(), ErrorKind::InvalidAddress)),
+                "accepted {:?}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn does_not_add_malformed_accounts() {
+        let mut config = Config {
+            accounts: vec![],
+            path: std::env::temp_dir().join("mail-client-test-config.json"),
+        };
+
+        assert!(config.add_account(account("not an address")).is_err());
+        assert!(config.accounts().is_empty());
+    }
 }
@@ -0,0 +1,109 @@
+// This module decides how to recover from a failed IMAP connection and tells
+// the frontend whether an account is connected.
+//
+// Network failures are retried with exponential backoff, a connection closed
+// by the server (e.g. after BYE) is reopened right away, and rejected
+// credentials are reported so the user can log in again.
+use std::time::Duration;
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use crate::error::{Error, ErrorCode, ErrorKind};
+
+pub const CONNECTION_STATE_EVENT: &str = "connection-state";
+
+/// How many times connecting is attempted before the account is offline
+pub const CONNECT_ATTEMPTS: u32 = 4;
+
+/// The delay before the first reconnection attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The state of the connection to the IMAP server of an account
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    /// Waiting before the given attempt to connect again
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The server could not be reached, even after retrying
+    Offline,
+    /// The server rejected the credentials, so the user has to log in again
+    AuthFailed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEvent {
+    pub email: String,
+    #[serde(flatten)]
+    pub state: ConnectionState,
+}
+
+/// What went wrong with a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// The credentials were rejected
+    Auth,
+    /// The server could not be reached or stopped responding
+    Network,
+    /// The server closed the connection, e.g. after BYE
+    Closed,
+    /// The server or the client rejected the command itself
+    Other,
+}
+
+impl Failure {
+    /// Whether a new connection may succeed where the old one failed
+    pub fn is_connection_failure(&self) -> bool {
+        matches!(self, Failure::Network | Failure::Closed)
+    }
+}
+
+/// Classify an error to decide how to recover from it
+///
+/// # Arguments
+/// * `error` - The error of a connection attempt or a command
+/// # Returns
+/// * `Failure` - What went wrong
+///
+pub fn classify(error: &Error) -> Failure {
+    match error.kind() {
+        ErrorKind::Auth | ErrorKind::OAuth | ErrorKind::RequestTokenError => Failure::Auth,
+        ErrorKind::Imap(imap::Error::ConnectionLost) => Failure::Closed,
+        // Only socket failures, not e.g. a missing file read while a session is in use
+        ErrorKind::Io(_) | ErrorKind::Imap(imap::Error::Io(_))
+            if error.code() == ErrorCode::Network =>
+        {
+            Failure::Network
+        }
+        _ => Failure::Other,
+    }
+}
+
+/// The delay before a reconnection attempt
+///
+/// # Arguments
+/// * `attempt` - The number of failed attempts so far, starting at 1
+/// # Returns
+/// * `Duration` - How long to wait before connecting again
+///
+pub fn backoff(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Tell the frontend that the connection state of an account changed
+pub fn emit(handle: &AppHandle, email: &str, state: ConnectionState) {
+    let payload = ConnectionEvent {
+        email: email.to_string(),
+        state,
+    };
+    if let Err(e) = handle.emit(CONNECTION_STATE_EVENT, payload) {
+        println!("Failed to emit {}: {}", CONNECTION_STATE_EVENT, e);
+    }
+}
@@ -14,5 +14,35 @@ pub const GOOGLE_PROFILE_API: &str = "https://www.googleapis.com/oauth2/v3/useri
 pub const GOOGLE_IMAP_HOST: &str = "imap.gmail.com";
 pub const GOOGLE_IMAP_PORT: u16 = 993;
 pub const GOOGLE_SMTP_HOST: &str = "smtp.gmail.com";
+pub const GOOGLE_SMTP_PORT: u16 = 465;
+pub const GOOGLE_MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
+
+// The Microsoft identity platform app is registered as a public client, so it has no secret
+pub const MICROSOFT_CLIENT_ID: &str = match option_env!("MICROSOFT_CLIENT_ID") {
+    Some(client_id) => client_id,
+    None => "",
+};
+
+pub const MICROSOFT_AUTH_URI: &str =
+    "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
+pub const MICROSOFT_TOKEN_URI: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+
+pub const MICROSOFT_IMAP_SCOPE: &str = "https://outlook.office.com/IMAP.AccessAsUser.All";
+pub const MICROSOFT_SMTP_SCOPE: &str = "https://outlook.office.com/SMTP.Send";
+pub const MICROSOFT_OFFLINE_SCOPE: &str = "offline_access";
+pub const MICROSOFT_OPENID_SCOPE: &str = "openid";
+pub const MICROSOFT_PROFILE_MAIL_SCOPE: &str = "email";
+
+pub const MICROSOFT_IMAP_HOST: &str = "outlook.office365.com";
+pub const MICROSOFT_IMAP_PORT: u16 = 993;
+pub const MICROSOFT_SMTP_HOST: &str = "smtp.office365.com";
+pub const MICROSOFT_SMTP_PORT: u16 = 587;
+// Outlook.com has the lowest limit of the Microsoft services
+pub const MICROSOFT_MAX_MESSAGE_SIZE: usize = 20 * 1024 * 1024;
+
+// Most providers accept at least this much when they do not advertise a limit
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
 
 pub const CONFIG_FILE_NAME: &str = "account-config.json";
+pub const CACHE_FILE_NAME: &str = "mail-cache.sqlite";
+pub const SEARCH_INDEX_DIR_NAME: &str = "search-index";
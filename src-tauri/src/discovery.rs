@@ -0,0 +1,852 @@
+// This module discovers the IMAP and SMTP settings of an email address.
+//
+// The sources are tried in order of reliability:
+// 1. Mozilla-style autoconfig XML served by the mail provider
+// 2. RFC 6186 SRV records (_imaps._tcp, _imap._tcp, _submissions._tcp, _submission._tcp)
+// 3. Heuristics based on the MX records of the domain
+//
+// Network access goes through the `Fetcher` and `Resolver` traits, so the
+// discovery logic can run against a local HTTP stub and a fake resolver.
+use hickory_resolver::TokioAsyncResolver;
+use oauth2::url::form_urlencoded;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AuthMethod, Security, ServerConfig};
+use crate::error::{Error, ErrorKind, Result};
+
+/// Where a candidate setting was discovered
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum DiscoverySource {
+    Autoconfig,
+    Srv,
+    Mx,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Candidate {
+    pub server: ServerConfig,
+    pub source: DiscoverySource,
+}
+
+/// Candidate settings, ordered from most to least preferred
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DiscoveredSettings {
+    pub imap: Vec<Candidate>,
+    pub smtp: Vec<Candidate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MxRecord {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+/// Fetches documents over HTTP(S)
+pub trait Fetcher {
+    /// Get the body of `url`, `None` if the document does not exist
+    async fn get(&self, url: &str) -> Result<Option<String>>;
+}
+
+/// Resolves the DNS records used for discovery
+pub trait Resolver {
+    async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>>;
+    async fn mx(&self, domain: &str) -> Result<Vec<MxRecord>>;
+}
+
+/// `Fetcher` backed by reqwest
+pub struct HttpFetcher {
+    client: reqwest::Client,
+}
+
+impl HttpFetcher {
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+        HttpFetcher { client }
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    async fn get(&self, url: &str) -> Result<Option<String>> {
+        let response = match self.client.get(url).send().await {
+            Ok(response) => response,
+            // Unreachable hosts are expected, most domains have no autoconfig server
+            Err(_) => return Ok(None),
+        };
+
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::from(format!("Failed to read {}: {}", url, e)))?;
+        Ok(Some(body))
+    }
+}
+
+/// `Resolver` backed by the system DNS configuration
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsResolver {
+    pub fn new() -> Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| Error::from(format!("Failed to create DNS resolver: {}", e)))?;
+        Ok(DnsResolver { resolver })
+    }
+}
+
+impl Resolver for DnsResolver {
+    async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        // A missing record is not an error for discovery
+        let Ok(lookup) = self.resolver.srv_lookup(name).await else {
+            return Ok(vec![]);
+        };
+
+        Ok(lookup
+            .iter()
+            .map(|srv| SrvRecord {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8(),
+            })
+            .collect())
+    }
+
+    async fn mx(&self, domain: &str) -> Result<Vec<MxRecord>> {
+        let Ok(lookup) = self.resolver.mx_lookup(domain).await else {
+            return Ok(vec![]);
+        };
+
+        Ok(lookup
+            .iter()
+            .map(|mx| MxRecord {
+                preference: mx.preference(),
+                exchange: mx.exchange().to_utf8(),
+            })
+            .collect())
+    }
+}
+
+pub struct Discovery<F: Fetcher, R: Resolver> {
+    fetcher: F,
+    resolver: R,
+    /// Base URLs of the autoconfig documents, `{domain}` and `{email}` are substituted
+    autoconfig_urls: Vec<String>,
+}
+
+impl Discovery<HttpFetcher, DnsResolver> {
+    /// Discovery using the network
+    pub fn system() -> Result<Self> {
+        Ok(Discovery::new(HttpFetcher::new(), DnsResolver::new()?))
+    }
+}
+
+impl<F: Fetcher, R: Resolver> Discovery<F, R> {
+    pub fn new(fetcher: F, resolver: R) -> Self {
+        Discovery {
+            fetcher,
+            resolver,
+            autoconfig_urls: vec![
+                "https://autoconfig.{domain}/mail/config-v1.1.xml?emailaddress={email}".to_string(),
+                "https://{domain}/.well-known/autoconfig/mail/config-v1.1.xml?emailaddress={email}"
+                    .to_string(),
+                "https://autoconfig.thunderbird.net/v1.1/{domain}".to_string(),
+            ],
+        }
+    }
+
+    /// Replace the autoconfig URL templates, e.g. to point at a local server
+    #[cfg(test)]
+    pub fn with_autoconfig_urls(mut self, urls: Vec<String>) -> Self {
+        self.autoconfig_urls = urls;
+        self
+    }
+
+    /// Discover the server settings for an email address
+    ///
+    /// # Arguments
+    /// * `email` - The email address
+    /// # Returns
+    /// * `Result<DiscoveredSettings>` - The candidate settings, possibly empty
+    ///
+    pub async fn discover(&self, email: &str) -> Result<DiscoveredSettings> {
+        let (local_part, domain) = email
+            .rsplit_once('@')
+            .filter(|(local, domain)| !local.is_empty() && !domain.is_empty())
+            .ok_or(Error::new(
+                ErrorKind::InvalidAddress,
+                format!("Invalid email address: {}", email),
+            ))?;
+        let domain = domain.to_lowercase();
+
+        let mut settings = DiscoveredSettings::default();
+
+        if let Some(autoconfig) = self.autoconfig(email, local_part, &domain, &domain).await? {
+            settings.imap.extend(autoconfig.imap);
+            settings.smtp.extend(autoconfig.smtp);
+        }
+
+        let srv = self.srv(&domain).await?;
+        settings.imap.extend(srv.imap);
+        settings.smtp.extend(srv.smtp);
+
+        let mx = self.mx(email, local_part, &domain).await?;
+        settings.imap.extend(mx.imap);
+        settings.smtp.extend(mx.smtp);
+
+        dedup_candidates(&mut settings.imap);
+        dedup_candidates(&mut settings.smtp);
+
+        Ok(settings)
+    }
+
+    /// Get the settings from the first valid autoconfig document
+    ///
+    /// # Arguments
+    /// * `email` - The email address
+    /// * `local_part` - The part of the address before the `@`
+    /// * `domain` - The domain of the address, used to fill in the placeholders
+    /// * `lookup_domain` - The domain whose documents are fetched, e.g. the provider's
+    /// # Returns
+    /// * `Result<Option<DiscoveredSettings>>` - The settings, `None` if no document lists an IMAP server
+    ///
+    async fn autoconfig(
+        &self,
+        email: &str,
+        local_part: &str,
+        domain: &str,
+        lookup_domain: &str,
+    ) -> Result<Option<DiscoveredSettings>> {
+        // Addresses may contain `+`, which would be decoded as a space
+        let query_email: String = form_urlencoded::byte_serialize(email.as_bytes()).collect();
+
+        for template in &self.autoconfig_urls {
+            let url = template
+                .replace("{domain}", lookup_domain)
+                .replace("{email}", &query_email);
+
+            let Some(xml) = self.fetcher.get(&url).await? else {
+                continue;
+            };
+
+            // Providers sometimes serve HTML error pages with status 200
+            if let Ok(settings) = parse_autoconfig(&xml, email, local_part, domain) {
+                if !settings.imap.is_empty() {
+                    return Ok(Some(settings));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn srv(&self, domain: &str) -> Result<DiscoveredSettings> {
+        let mut settings = DiscoveredSettings::default();
+
+        let lookups = [
+            ("_imaps._tcp", Security::Tls, true),
+            ("_imap._tcp", Security::StartTls, true),
+            ("_submissions._tcp", Security::Tls, false),
+            ("_submission._tcp", Security::StartTls, false),
+        ];
+
+        for (service, security, is_imap) in lookups {
+            let mut records = self
+                .resolver
+                .srv(&format!("{}.{}", service, domain))
+                .await?;
+            records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+
+            for record in records {
+                let host = record.target.trim_end_matches('.').to_string();
+
+                // RFC 6186: a target of "." means the service is not available
+                if host.is_empty() || record.port == 0 {
+                    continue;
+                }
+
+                let candidate = Candidate {
+                    server: ServerConfig {
+                        host,
+                        port: record.port,
+                        security,
+                        auth: AuthMethod::Plain,
+                        username: None,
+                    },
+                    source: DiscoverySource::Srv,
+                };
+
+                if is_imap {
+                    settings.imap.push(candidate);
+                } else {
+                    settings.smtp.push(candidate);
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    async fn mx(&self, email: &str, local_part: &str, domain: &str) -> Result<DiscoveredSettings> {
+        let mut records = self.resolver.mx(domain).await?;
+        records.sort_by_key(|mx| mx.preference);
+
+        let mut settings = DiscoveredSettings::default();
+
+        let Some(mx) = records.first() else {
+            return Ok(settings);
+        };
+
+        // e.g. aspmx.l.google.com -> google.com
+        let mx_host = mx.exchange.trim_end_matches('.').to_lowercase();
+        let mx_domain = base_domain(&mx_host);
+
+        // Hosted domains are often listed in the ISPDB under the provider's domain
+        if mx_domain != domain {
+            if let Some(autoconfig) = self
+                .autoconfig(email, local_part, domain, &mx_domain)
+                .await?
+            {
+                settings
+                    .imap
+                    .extend(with_source(autoconfig.imap, DiscoverySource::Mx));
+                settings
+                    .smtp
+                    .extend(with_source(autoconfig.smtp, DiscoverySource::Mx));
+                return Ok(settings);
+            }
+        }
+
+        for guess_domain in [domain, mx_domain.as_str()] {
+            settings.imap.push(Candidate {
+                server: ServerConfig {
+                    host: format!("imap.{}", guess_domain),
+                    port: 993,
+                    security: Security::Tls,
+                    auth: AuthMethod::Plain,
+                    username: None,
+                },
+                source: DiscoverySource::Mx,
+            });
+            settings.smtp.push(Candidate {
+                server: ServerConfig {
+                    host: format!("smtp.{}", guess_domain),
+                    port: 587,
+                    security: Security::StartTls,
+                    auth: AuthMethod::Plain,
+                    username: None,
+                },
+                source: DiscoverySource::Mx,
+            });
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Parse a Mozilla autoconfig document (config-v1.1.xml)
+///
+/// # Arguments
+/// * `xml` - The document
+/// * `email` - The email address, used to fill in the username placeholders
+/// # Returns
+/// * `Result<DiscoveredSettings>` - The IMAP and SMTP servers listed in the document
+///
+pub fn parse_autoconfig(
+    xml: &str,
+    email: &str,
+    local_part: &str,
+    domain: &str,
+) -> Result<DiscoveredSettings> {
+    let document = roxmltree::Document::parse(xml)
+        .map_err(|e| Error::from(format!("Failed to parse autoconfig: {}", e)))?;
+
+    let mut settings = DiscoveredSettings::default();
+
+    for node in document.descendants() {
+        let is_imap = node.has_tag_name("incomingServer") && node.attribute("type") == Some("imap");
+        let is_smtp = node.has_tag_name("outgoingServer") && node.attribute("type") == Some("smtp");
+        if !is_imap && !is_smtp {
+            continue;
+        }
+
+        let child_text = |name: &str| {
+            node.children()
+                .find(|child| child.has_tag_name(name))
+                .and_then(|child| child.text())
+                .map(|text| text.trim().to_string())
+        };
+
+        let Some(host) = child_text("hostname") else {
+            continue;
+        };
+        let Some(port) = child_text("port").and_then(|p| p.parse::<u16>().ok()) else {
+            continue;
+        };
+
+        let security = match child_text("socketType").as_deref() {
+            Some("SSL") => Security::Tls,
+            Some("STARTTLS") => Security::StartTls,
+            Some("plain") => Security::Plain,
+            _ => continue,
+        };
+
+        // A server may list several methods, prefer OAuth2 if offered
+        let methods: Vec<String> = node
+            .children()
+            .filter(|child| child.has_tag_name("authentication"))
+            .filter_map(|child| child.text().map(|t| t.trim().to_string()))
+            .collect();
+        let auth = if methods.iter().any(|m| m == "OAuth2") {
+            AuthMethod::XOAuth2
+        } else if methods.is_empty() || methods.iter().any(|m| m == "password-cleartext") {
+            AuthMethod::Plain
+        } else {
+            continue;
+        };
+
+        let username = child_text("username")
+            .map(|u| {
+                u.replace("%EMAILADDRESS%", email)
+                    .replace("%EMAILLOCALPART%", local_part)
+                    .replace("%EMAILDOMAIN%", domain)
+            })
+            .filter(|u| u != email);
+
+        let candidate = Candidate {
+            server: ServerConfig {
+                host: host.replace("%EMAILDOMAIN%", domain),
+                port,
+                security,
+                auth,
+                username,
+            },
+            source: DiscoverySource::Autoconfig,
+        };
+
+        if is_imap {
+            settings.imap.push(candidate);
+        } else {
+            settings.smtp.push(candidate);
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Strip subdomains from a host name, keeping the last two labels
+fn base_domain(host: &str) -> String {
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() <= 2 {
+        return host.to_string();
+    }
+    labels[labels.len() - 2..].join(".")
+}
+
+fn with_source(candidates: Vec<Candidate>, source: DiscoverySource) -> Vec<Candidate> {
+    candidates
+        .into_iter()
+        .map(|candidate| Candidate {
+            source,
+            ..candidate
+        })
+        .collect()
+}
+
+/// Remove candidates pointing at the same server, keeping the first occurrence
+fn dedup_candidates(candidates: &mut Vec<Candidate>) {
+    let mut seen = Vec::new();
+    candidates.retain(|candidate| {
+        let key = (
+            candidate.server.host.to_lowercase(),
+            candidate.server.port,
+            candidate.server.security,
+        );
+        if seen.contains(&key) {
+            false
+        } else {
+            seen.push(key);
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    const AUTOCONFIG_URL: &str = "https://autoconfig.test/{domain}?emailaddress={email}";
+
+    #[derive(Default)]
+    struct FakeFetcher {
+        documents: HashMap<String, String>,
+    }
+
+    impl FakeFetcher {
+        fn with_autoconfig(mut self, domain: &str, email: &str, xml: &str) -> Self {
+            let email: String = form_urlencoded::byte_serialize(email.as_bytes()).collect();
+            let url = AUTOCONFIG_URL
+                .replace("{domain}", domain)
+                .replace("{email}", &email);
+            self.documents.insert(url, xml.to_string());
+            self
+        }
+    }
+
+    impl Fetcher for FakeFetcher {
+        async fn get(&self, url: &str) -> Result<Option<String>> {
+            Ok(self.documents.get(url).cloned())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeResolver {
+        srv: HashMap<String, Vec<SrvRecord>>,
+        mx: HashMap<String, Vec<MxRecord>>,
+    }
+
+    impl Resolver for FakeResolver {
+        async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+            Ok(self.srv.get(name).cloned().unwrap_or_default())
+        }
+
+        async fn mx(&self, domain: &str) -> Result<Vec<MxRecord>> {
+            Ok(self.mx.get(domain).cloned().unwrap_or_default())
+        }
+    }
+
+    fn discover(fetcher: FakeFetcher, resolver: FakeResolver, email: &str) -> DiscoveredSettings {
+        let discovery = Discovery::new(fetcher, resolver)
+            .with_autoconfig_urls(vec![AUTOCONFIG_URL.to_string()]);
+        tauri::async_runtime::block_on(discovery.discover(email)).unwrap()
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.to_string(),
+        }
+    }
+
+    fn candidate(host: &str, port: u16, security: Security, source: DiscoverySource) -> Candidate {
+        Candidate {
+            server: ServerConfig {
+                host: host.to_string(),
+                port,
+                security,
+                auth: AuthMethod::Plain,
+                username: None,
+            },
+            source,
+        }
+    }
+
+    const AUTOCONFIG: &str = r#"<?xml version="1.0"?>
+<clientConfig version="1.1">
+  <emailProvider id="example.com">
+    <incomingServer type="imap">
+      <hostname>imap.%EMAILDOMAIN%</hostname>
+      <port>993</port>
+      <socketType>SSL</socketType>
+      <authentication>password-cleartext</authentication>
+      <username>%EMAILADDRESS%</username>
+    </incomingServer>
+    <incomingServer type="pop3">
+      <hostname>pop.example.com</hostname>
+      <port>995</port>
+      <socketType>SSL</socketType>
+    </incomingServer>
+    <outgoingServer type="smtp">
+      <hostname>smtp.example.com</hostname>
+      <port>587</port>
+      <socketType>STARTTLS</socketType>
+      <authentication>password-cleartext</authentication>
+      <username>%EMAILLOCALPART%</username>
+    </outgoingServer>
+  </emailProvider>
+</clientConfig>"#;
+
+    #[test]
+    fn uses_autoconfig_with_placeholders() {
+        let fetcher =
+            FakeFetcher::default().with_autoconfig("example.com", "alice@example.com", AUTOCONFIG);
+
+        let settings = discover(fetcher, FakeResolver::default(), "alice@example.com");
+
+        let imap = candidate(
+            "imap.example.com",
+            993,
+            Security::Tls,
+            DiscoverySource::Autoconfig,
+        );
+        let mut smtp = candidate(
+            "smtp.example.com",
+            587,
+            Security::StartTls,
+            DiscoverySource::Autoconfig,
+        );
+        smtp.server.username = Some("alice".to_string());
+        assert_eq!(settings.imap, vec![imap]);
+        assert_eq!(settings.smtp, vec![smtp]);
+    }
+
+    #[test]
+    fn encodes_email_in_autoconfig_url() {
+        let mut fetcher = FakeFetcher::default();
+        fetcher.documents.insert(
+            "https://autoconfig.test/example.com?emailaddress=alice%2Btag%40example.com"
+                .to_string(),
+            AUTOCONFIG.to_string(),
+        );
+
+        let settings = discover(fetcher, FakeResolver::default(), "alice+tag@example.com");
+
+        assert_eq!(settings.imap.len(), 1);
+        assert_eq!(settings.imap[0].server.host, "imap.example.com");
+    }
+
+    #[test]
+    fn fetches_autoconfig_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Serve a single request and report its request line
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                AUTOCONFIG.len(),
+                AUTOCONFIG
+            )
+            .unwrap();
+            request_line
+        });
+
+        let discovery = Discovery::new(HttpFetcher::new(), FakeResolver::default())
+            .with_autoconfig_urls(vec![format!(
+                "http://127.0.0.1:{}/{{domain}}?emailaddress={{email}}",
+                port
+            )]);
+        let settings =
+            tauri::async_runtime::block_on(discovery.discover("alice+tag@example.com")).unwrap();
+
+        assert_eq!(
+            server.join().unwrap().trim_end(),
+            "GET /example.com?emailaddress=alice%2Btag%40example.com HTTP/1.1"
+        );
+        assert_eq!(settings.imap.len(), 1);
+        assert_eq!(settings.imap[0].server.host, "imap.example.com");
+    }
+
+    #[test]
+    fn skips_invalid_autoconfig() {
+        let fetcher = FakeFetcher::default().with_autoconfig(
+            "example.com",
+            "alice@example.com",
+            "<html>Not found</html>",
+        );
+
+        let settings = discover(fetcher, FakeResolver::default(), "alice@example.com");
+
+        assert!(settings.imap.is_empty());
+        assert!(settings.smtp.is_empty());
+    }
+
+    #[test]
+    fn orders_srv_records_by_priority_then_weight() {
+        let mut resolver = FakeResolver::default();
+        resolver.srv.insert(
+            "_imaps._tcp.example.com".to_string(),
+            vec![
+                srv(10, 5, 993, "backup.example.com."),
+                srv(0, 1, 993, "light.example.com."),
+                srv(0, 10, 993, "heavy.example.com."),
+            ],
+        );
+        resolver.srv.insert(
+            "_submission._tcp.example.com".to_string(),
+            vec![srv(0, 0, 587, "smtp.example.com.")],
+        );
+
+        let settings = discover(FakeFetcher::default(), resolver, "alice@example.com");
+
+        let source = DiscoverySource::Srv;
+        assert_eq!(
+            settings.imap,
+            vec![
+                candidate("heavy.example.com", 993, Security::Tls, source),
+                candidate("light.example.com", 993, Security::Tls, source),
+                candidate("backup.example.com", 993, Security::Tls, source),
+            ]
+        );
+        assert_eq!(
+            settings.smtp,
+            vec![candidate(
+                "smtp.example.com",
+                587,
+                Security::StartTls,
+                source
+            )]
+        );
+    }
+
+    #[test]
+    fn skips_unavailable_srv_services() {
+        let mut resolver = FakeResolver::default();
+        resolver.srv.insert(
+            "_imaps._tcp.example.com".to_string(),
+            vec![srv(0, 0, 0, ".")],
+        );
+        resolver.srv.insert(
+            "_imap._tcp.example.com".to_string(),
+            vec![srv(0, 0, 143, "imap.example.com.")],
+        );
+
+        let settings = discover(FakeFetcher::default(), resolver, "alice@example.com");
+
+        assert_eq!(
+            settings.imap,
+            vec![candidate(
+                "imap.example.com",
+                143,
+                Security::StartTls,
+                DiscoverySource::Srv
+            )]
+        );
+    }
+
+    #[test]
+    fn guesses_servers_from_mx() {
+        let mut resolver = FakeResolver::default();
+        resolver.mx.insert(
+            "example.org".to_string(),
+            vec![
+                MxRecord {
+                    preference: 20,
+                    exchange: "mx.backup.net.".to_string(),
+                },
+                MxRecord {
+                    preference: 10,
+                    exchange: "mx1.mailhost.com.".to_string(),
+                },
+            ],
+        );
+
+        let settings = discover(FakeFetcher::default(), resolver, "bob@example.org");
+
+        let source = DiscoverySource::Mx;
+        assert_eq!(
+            settings.imap,
+            vec![
+                candidate("imap.example.org", 993, Security::Tls, source),
+                candidate("imap.mailhost.com", 993, Security::Tls, source),
+            ]
+        );
+        assert_eq!(
+            settings.smtp,
+            vec![
+                candidate("smtp.example.org", 587, Security::StartTls, source),
+                candidate("smtp.mailhost.com", 587, Security::StartTls, source),
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_autoconfig_of_mx_provider() {
+        let fetcher =
+            FakeFetcher::default().with_autoconfig("example.com", "bob@example.org", AUTOCONFIG);
+        let mut resolver = FakeResolver::default();
+        resolver.mx.insert(
+            "example.org".to_string(),
+            vec![MxRecord {
+                preference: 10,
+                exchange: "mx.example.com.".to_string(),
+            }],
+        );
+
+        let settings = discover(fetcher, resolver, "bob@example.org");
+
+        // The placeholders are filled in with the address, not the provider's domain
+        let imap = candidate("imap.example.org", 993, Security::Tls, DiscoverySource::Mx);
+        let mut smtp = candidate(
+            "smtp.example.com",
+            587,
+            Security::StartTls,
+            DiscoverySource::Mx,
+        );
+        smtp.server.username = Some("bob".to_string());
+        assert_eq!(settings.imap, vec![imap]);
+        assert_eq!(settings.smtp, vec![smtp]);
+    }
+
+    #[test]
+    fn removes_duplicate_candidates() {
+        let mut candidates = vec![
+            candidate(
+                "imap.example.com",
+                993,
+                Security::Tls,
+                DiscoverySource::Autoconfig,
+            ),
+            candidate("IMAP.example.com", 993, Security::Tls, DiscoverySource::Srv),
+            candidate(
+                "imap.example.com",
+                143,
+                Security::StartTls,
+                DiscoverySource::Srv,
+            ),
+            candidate("imap.example.com", 993, Security::Tls, DiscoverySource::Mx),
+        ];
+
+        dedup_candidates(&mut candidates);
+
+        assert_eq!(
+            candidates,
+            vec![
+                candidate(
+                    "imap.example.com",
+                    993,
+                    Security::Tls,
+                    DiscoverySource::Autoconfig
+                ),
+                candidate(
+                    "imap.example.com",
+                    143,
+                    Security::StartTls,
+                    DiscoverySource::Srv
+                ),
+            ]
+        );
+    }
+}
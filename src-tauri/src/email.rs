@@ -1,17 +1,302 @@
-use crate::error::{Error, Result};
-use std::{collections::HashMap, net::TcpStream};
+use crate::attachments::{self, Attachment};
+use crate::auth_store::{AccountCredentials, PlainAuthenticator};
+use crate::config::{Account, AuthMethod, Security};
+use crate::error::{Error, ErrorKind, Result};
+use crate::inline_images;
+use crate::trackers::Tracker;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{
+    collections::HashMap,
+    net::{TcpStream, ToSocketAddrs},
+};
 use utf7_imap::decode_utf7_imap;
 
-use imap::types::NameAttribute;
-use imap::Authenticator;
-use mail_parser::{Address, MessageParser};
-use native_tls::TlsStream;
+use imap::extensions::idle::SetReadTimeout;
+use imap::types::{Flag, NameAttribute};
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::SmtpTransport;
+use mail_parser::decoders::html::{html_to_text, text_to_html};
+use mail_parser::{Address, HeaderValue, MessageParser};
+use native_tls::{TlsConnector, TlsStream};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
-use crate::constants::{GOOGLE_IMAP_HOST, GOOGLE_IMAP_PORT};
+/// How long to wait for the TCP connection to the server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
 
-pub type Session = imap::Session<TlsStream<TcpStream>>;
+/// How long a single read or write may block, so a dead connection fails instead of hanging
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An authenticated IMAP session
+///
+/// The `imap` crate drops the tagged status line of responses, which carries
+/// response codes like APPENDUID (RFC 4315). `capture` records what the server
+/// sends during a command so those codes can be read.
+pub struct Session {
+    session: imap::Session<Connection>,
+    capture: Capture,
+    literals: Literals,
+    reusable: bool,
+}
+
+impl Session {
+    fn new(session: imap::Session<Connection>, capture: Capture, literals: Literals) -> Self {
+        Session {
+            session,
+            capture,
+            literals,
+            reusable: true,
+        }
+    }
+
+    /// Close the session once the current command is done instead of reusing it,
+    /// e.g. after enabling an extension that changes how the server responds
+    pub fn close_after_use(&mut self) {
+        self.reusable = false;
+    }
+
+    /// Whether the session may run other commands once the current one is done
+    pub fn is_reusable(&self) -> bool {
+        self.reusable
+    }
+
+    /// Run a command whose arguments may contain literals made by `imap_string`
+    ///
+    /// # Arguments
+    /// * `command` - The command to run on the session
+    /// # Returns
+    /// * `R` - The result of the command
+    ///
+    pub fn with_literals<R>(
+        &mut self,
+        command: impl FnOnce(&mut imap::Session<Connection>) -> R,
+    ) -> R {
+        self.literals.0.store(true, Ordering::SeqCst);
+        let result = command(&mut self.session);
+        self.literals.0.store(false, Ordering::SeqCst);
+        result
+    }
+
+    /// Run a command and get the raw response, including the tagged status line
+    ///
+    /// # Arguments
+    /// * `command` - The command to run on the session
+    /// # Returns
+    /// * `(R, Vec<u8>)` - The result of the command and everything the server sent
+    ///
+    pub fn capture<R>(
+        &mut self,
+        command: impl FnOnce(&mut imap::Session<Connection>) -> R,
+    ) -> (R, Vec<u8>) {
+        self.capture.start();
+        let result = command(&mut self.session);
+        (result, self.capture.stop())
+    }
+}
+
+impl Deref for Session {
+    type Target = imap::Session<Connection>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.session
+    }
+}
+
+/// The stream underlying an IMAP session
+#[derive(Debug)]
+pub struct Connection {
+    stream: Stream,
+    capture: Capture,
+    literals: Literals,
+    /// The bytes of the current literal that are still to be written
+    literal_remaining: usize,
+}
+
+/// Either an encrypted or a plain stream
+#[derive(Debug)]
+enum Stream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+/// The bytes read from a connection while capturing, shared with its session
+#[derive(Debug, Clone, Default)]
+struct Capture(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl Capture {
+    fn start(&self) {
+        if let Ok(mut captured) = self.0.lock() {
+            *captured = Some(vec![]);
+        }
+    }
+
+    fn stop(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .ok()
+            .and_then(|mut captured| captured.take())
+            .unwrap_or_default()
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        if let Ok(mut captured) = self.0.lock() {
+            if let Some(captured) = captured.as_mut() {
+                captured.extend_from_slice(bytes);
+            }
+        }
+    }
+}
+
+/// Whether the commands written to a connection contain synchronizing literals
+///
+/// The `imap` crate writes a command in one go, but the server has to accept
+/// each literal with a continuation request before its content is sent. While
+/// set, the connection stops after every `{n}` announcement and waits for it.
+#[derive(Debug, Clone, Default)]
+struct Literals(Arc<AtomicBool>);
+
+impl Connection {
+    fn new(stream: Stream) -> Self {
+        Connection {
+            stream,
+            capture: Capture::default(),
+            literals: Literals::default(),
+            literal_remaining: 0,
+        }
+    }
+
+    fn write_stream(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    /// Read the server's answer to a literal announcement, which has to be a continuation request
+    fn read_continuation(&mut self) -> io::Result<()> {
+        let mut line = vec![];
+        let mut byte = [0u8];
+        while !line.ends_with(b"\n") {
+            let read = match &mut self.stream {
+                Stream::Tls(stream) => stream.read(&mut byte)?,
+                Stream::Plain(stream) => stream.read(&mut byte)?,
+            };
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            line.push(byte[0]);
+        }
+
+        if line.starts_with(b"+") {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Server did not accept the literal: {}",
+                    String::from_utf8_lossy(&line).trim_end()
+                ),
+            ))
+        }
+    }
+}
+
+/// Find the first literal announcement like `{12}\r\n` in a command
+///
+/// # Arguments
+/// * `buf` - The bytes of the command
+/// # Returns
+/// * `Option<(usize, usize)>` - The end of the announcement and the length of the literal
+///
+fn literal_announcement(buf: &[u8]) -> Option<(usize, usize)> {
+    (0..buf.len().saturating_sub(2))
+        .filter(|&close| buf[close..].starts_with(b"}\r\n"))
+        .find_map(|close| {
+            let digits = buf[..close]
+                .iter()
+                .rev()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+            if digits == 0 || close == digits || buf[close - digits - 1] != b'{' {
+                return None;
+            }
+            let length = std::str::from_utf8(&buf[close - digits..close])
+                .ok()?
+                .parse()
+                .ok()?;
+            Some((close + 3, length))
+        })
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.stream {
+            Stream::Tls(stream) => stream.read(buf)?,
+            Stream::Plain(stream) => stream.read(buf)?,
+        };
+        self.capture.record(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl SetReadTimeout for Connection {
+    // IDLE clears the timeout once it is done, which restores the command timeout instead
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::Result<()> {
+        let timeout = timeout.or(Some(COMMAND_TIMEOUT));
+        match &self.stream {
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout)?,
+            Stream::Plain(stream) => stream.set_read_timeout(timeout)?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.literals.0.load(Ordering::SeqCst) {
+            return self.write_stream(buf);
+        }
+        // The content of a literal is sent as is, it may look like an announcement
+        if self.literal_remaining > 0 {
+            let length = buf.len().min(self.literal_remaining);
+            let written = self.write_stream(&buf[..length])?;
+            self.literal_remaining -= written;
+            return Ok(written);
+        }
+        let Some((end, length)) = literal_announcement(buf) else {
+            return self.write_stream(buf);
+        };
+
+        // Send the command up to the announcement, the rest is written once the server is ready
+        let mut written = 0;
+        while written < end {
+            match self.write_stream(&buf[written..end])? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                count => written += count,
+            }
+        }
+        self.flush()?;
+        self.read_continuation()?;
+        self.literal_remaining = length;
+        Ok(end)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Plain(stream) => stream.flush(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailAddress {
@@ -53,7 +338,36 @@ pub struct Message {
     pub headers: HashMap<String, String>,
     pub flags: Vec<String>,
     pub mailbox_name: String,
+    /// The HTML body, converted from the plain text body if there is none
     pub body: String,
+    /// The plain text body, converted from the HTML body if there is none
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// The number of remote images and resources removed from the body
+    #[serde(default)]
+    pub blocked_remote_content: usize,
+    /// The tracking pixels and tracked links found in the body
+    #[serde(default)]
+    pub trackers: Vec<Tracker>,
+    #[serde(default)]
+    pub reply_to: Vec<EmailAddress>,
+}
+
+impl Message {
+    /// The Message-ID of the message, without angle brackets
+    pub fn message_id(&self) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Message-ID"))
+            .map(|(_, id)| {
+                id.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    }
 }
 
 /// Get the list of mailboxes
@@ -96,6 +410,56 @@ pub fn get_mailboxes(session: &mut Session) -> Result<Vec<Mailbox>> {
     Ok(mailbox_names)
 }
 
+/// Keep a copy of a sent message in the `\Sent` mailbox
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `message` - The RFC822 message, exactly as it was sent
+/// # Returns
+/// * `Result<String>` - The name of the Sent mailbox
+///
+pub fn save_sent(session: &mut Session, message: &[u8]) -> Result<String> {
+    let sent = get_mailboxes(session)?
+        .into_iter()
+        .find(|mailbox| {
+            mailbox
+                .attributes
+                .iter()
+                .any(|attribute| attribute.eq_ignore_ascii_case("\\Sent"))
+        })
+        .ok_or(Error::new(ErrorKind::NotFound, "Sent mailbox not found"))?;
+
+    session.append_with_flags(&sent.name, message, &[Flag::Seen])?;
+    Ok(sent.name)
+}
+
+/// The header fields shown in envelope lists
+const ENVELOPE_HEADER_FIELDS: &str =
+    "DATE FROM TO CC BCC SUBJECT MESSAGE-ID IN-REPLY-TO REFERENCES";
+
+/// Which envelopes of a mailbox to fetch
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum PageQuery {
+    /// Messages with sequence numbers in `start..=end`
+    Sequence { start: u32, end: u32 },
+    /// Messages with UIDs in `start..=end`
+    Uid { start: u32, end: u32 },
+    /// The newest `count` messages, optionally only those older than `before_uid`
+    Newest { count: u32, before_uid: Option<u32> },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EnvelopePage {
+    pub envelopes: Vec<Envelope>,
+    /// The number of messages in the mailbox, unknown when served from the cache
+    pub total: Option<u32>,
+    /// The number of unseen messages, unknown when served from the cache
+    pub unseen: Option<u32>,
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+}
+
 /// Get the list of envelopes
 ///
 /// # Arguments
@@ -105,62 +469,278 @@ pub fn get_mailboxes(session: &mut Session) -> Result<Vec<Mailbox>> {
 /// * `Result<Vec<Envelope>>` - The list of envelopes
 ///
 pub fn get_envelopes(session: &mut Session, mailbox: &str) -> Result<Vec<Envelope>> {
-    session.select(mailbox)?;
-    let responses = session.fetch("1:*", "(UID FLAGS RFC822.HEADER)")?;
-    let parser = MessageParser::default();
+    let selected = session.select(mailbox)?;
+    if selected.exists == 0 {
+        return Ok(vec![]);
+    }
 
+    let responses = session.fetch("1:*", envelope_fetch_query())?;
     let envelopes = responses
         .iter()
-        .filter_map(|fetch| {
-            let header_bytes = fetch.header()?;
-            let message = parser.parse(header_bytes)?;
-            let uid = fetch.uid;
-            let date = message.date().map(|d| d.to_string());
-
-            Some(Envelope {
-                uid,
-                date,
-                from: parse_addrs(message.from()).unwrap_or_default(),
-                to: parse_addrs(message.to()).unwrap_or_default(),
-                cc: parse_addrs(message.cc()).unwrap_or_default(),
-                bcc: parse_addrs(message.bcc()).unwrap_or_default(),
-                subject: message.subject().map(|s| s.to_string()),
-                headers: message
-                    .headers()
-                    .iter()
-                    .map(|h| {
-                        (
-                            h.name().to_string(),
-                            h.value().as_text().unwrap_or_default().to_string(),
-                        )
-                    })
-                    .collect(),
-                flags: fetch.flags().iter().map(|f| f.to_string()).collect(),
-                mailbox_name: mailbox.to_string(),
-            })
-        })
+        .filter_map(|fetch| parse_envelope(fetch, mailbox))
+        .collect();
+
+    Ok(envelopes)
+}
+
+/// Get a page of envelopes
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// * `query` - The range of messages to fetch
+/// # Returns
+/// * `Result<EnvelopePage>` - The envelopes, newest first, and the mailbox counts
+///
+pub fn get_envelope_page(
+    session: &mut Session,
+    mailbox: &str,
+    query: &PageQuery,
+) -> Result<EnvelopePage> {
+    // SELECT only reports the first unseen message, STATUS reports the count
+    let status = session.status(mailbox, "(UNSEEN)")?;
+    let selected = session.select(mailbox)?;
+
+    let mut page = EnvelopePage {
+        envelopes: vec![],
+        total: Some(selected.exists),
+        unseen: Some(status.unseen.unwrap_or_default()),
+        uid_validity: selected.uid_validity,
+        uid_next: selected.uid_next,
+    };
+
+    if selected.exists == 0 {
+        return Ok(page);
+    }
+
+    let responses = match *query {
+        PageQuery::Sequence { start, end } => {
+            let start = start.max(1);
+            let end = end.min(selected.exists);
+            if start > end {
+                return Ok(page);
+            }
+            session.fetch(format!("{}:{}", start, end), envelope_fetch_query())?
+        }
+        PageQuery::Uid { start, end } => {
+            if start > end {
+                return Ok(page);
+            }
+            session.uid_fetch(format!("{}:{}", start.max(1), end), envelope_fetch_query())?
+        }
+        PageQuery::Newest {
+            count,
+            before_uid: None,
+        } => {
+            if count == 0 {
+                return Ok(page);
+            }
+            let start = selected.exists.saturating_sub(count) + 1;
+            session.fetch(
+                format!("{}:{}", start, selected.exists),
+                envelope_fetch_query(),
+            )?
+        }
+        PageQuery::Newest {
+            count,
+            before_uid: Some(before_uid),
+        } => {
+            if count == 0 || before_uid <= 1 {
+                return Ok(page);
+            }
+            let mut uids: Vec<u32> = session
+                .uid_search(format!("UID 1:{}", before_uid - 1))?
+                .into_iter()
+                .collect();
+            uids.sort_unstable();
+
+            let uids = &uids[uids.len().saturating_sub(count as usize)..];
+            if uids.is_empty() {
+                return Ok(page);
+            }
+            session.uid_fetch(uid_set(uids), envelope_fetch_query())?
+        }
+    };
+
+    page.envelopes = responses
+        .iter()
+        .filter_map(|fetch| parse_envelope(fetch, mailbox))
+        .collect();
+    page.envelopes.sort_by(|a, b| b.uid.cmp(&a.uid));
+
+    Ok(page)
+}
+
+/// Fetch the envelopes of messages in the selected mailbox
+///
+/// # Arguments
+/// * `session` - The IMAP session, with `mailbox` selected
+/// * `mailbox` - The selected mailbox
+/// * `uid_set` - The UIDs to fetch, e.g. `42:*`
+/// # Returns
+/// * `Result<Vec<Envelope>>` - The envelopes
+///
+pub fn fetch_envelopes(
+    session: &mut Session,
+    mailbox: &str,
+    uid_set: &str,
+) -> Result<Vec<Envelope>> {
+    let responses = session.uid_fetch(uid_set, envelope_fetch_query())?;
+
+    let envelopes = responses
+        .iter()
+        .filter_map(|fetch| parse_envelope(fetch, mailbox))
         .collect();
 
     Ok(envelopes)
 }
 
+/// Fetch the envelopes of the newest messages in the selected mailbox
+///
+/// # Arguments
+/// * `session` - The IMAP session, with `mailbox` selected
+/// * `mailbox` - The selected mailbox
+/// * `exists` - The number of messages in the mailbox, as reported by SELECT
+/// * `count` - The number of messages to fetch
+/// # Returns
+/// * `Result<Vec<Envelope>>` - The envelopes
+///
+pub fn fetch_newest_envelopes(
+    session: &mut Session,
+    mailbox: &str,
+    exists: u32,
+    count: u32,
+) -> Result<Vec<Envelope>> {
+    if exists == 0 || count == 0 {
+        return Ok(vec![]);
+    }
+
+    // Sequence numbers are contiguous, unlike UIDs, so the window is known without a SEARCH
+    let start = exists.saturating_sub(count) + 1;
+    let responses = session.fetch(format!("{}:{}", start, exists), envelope_fetch_query())?;
+
+    let envelopes = responses
+        .iter()
+        .filter_map(|fetch| parse_envelope(fetch, mailbox))
+        .collect();
+
+    Ok(envelopes)
+}
+
+fn envelope_fetch_query() -> String {
+    format!(
+        "(UID FLAGS BODY.PEEK[HEADER.FIELDS ({})])",
+        ENVELOPE_HEADER_FIELDS
+    )
+}
+
+/// Format a value as an IMAP string argument
+///
+/// ASCII values are sent as quoted strings. Quoted strings are 7-bit, so other
+/// values are sent as literals, which requires running the command with
+/// `Session::with_literals`. Control characters could end the command early
+/// and are rejected.
+///
+/// # Arguments
+/// * `value` - The value to send
+/// # Returns
+/// * `Result<String>` - The quoted string or literal
+///
+pub fn imap_string(value: &str) -> Result<String> {
+    if value.chars().any(char::is_control) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid characters in {:?}", value),
+        ));
+    }
+
+    if value.is_ascii() {
+        Ok(format!(
+            "\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    } else {
+        Ok(format!("{{{}}}\r\n{}", value.len(), value))
+    }
+}
+
+/// Build a compact UID set, e.g. `1:3,7,9:10`, from sorted UIDs
+pub fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn parse_envelope(fetch: &imap::types::Fetch, mailbox: &str) -> Option<Envelope> {
+    let header_bytes = fetch.header()?;
+    let message = MessageParser::default().parse_headers(header_bytes)?;
+
+    Some(Envelope {
+        uid: fetch.uid,
+        date: message.date().map(|d| d.to_string()),
+        from: parse_addrs(message.from()).unwrap_or_default(),
+        to: parse_addrs(message.to()).unwrap_or_default(),
+        cc: parse_addrs(message.cc()).unwrap_or_default(),
+        bcc: parse_addrs(message.bcc()).unwrap_or_default(),
+        subject: message.subject().map(|s| s.to_string()),
+        headers: message
+            .headers()
+            .iter()
+            .map(|h| (h.name().to_string(), header_text(h.value())))
+            .collect(),
+        flags: fetch.flags().iter().map(|f| f.to_string()).collect(),
+        mailbox_name: mailbox.to_string(),
+    })
+}
+
+/// Convert a header value to text, keeping every entry of lists like References
+fn header_text(value: &HeaderValue) -> String {
+    match value {
+        HeaderValue::TextList(list) => list.join(" "),
+        value => value.as_text().unwrap_or_default().to_string(),
+    }
+}
+
 /// Get the content of a mail
 ///
 /// # Arguments
 /// * `session` - The IMAP session
+/// * `account` - The account, used in the URLs of large inline images
 /// * `mailbox` - The mailbox to select
 /// * `uid` - The UID of the mail
 /// # Returns
 /// * `Result<String>` - The content of the mail as HTML
 ///
-pub fn get_message(session: &mut Session, mailbox: &str, uid: u32) -> Result<Message> {
+pub fn get_message(
+    session: &mut Session,
+    account: &str,
+    mailbox: &str,
+    uid: u32,
+) -> Result<Message> {
     session.select(mailbox)?;
 
     let response = session.uid_fetch(uid.to_string(), "(UID FLAGS RFC822)")?;
 
-    let message = response
-        .first()
-        .ok_or(Error::from("Could not get mail content"))?;
+    let message = response.first().ok_or(Error::new(
+        ErrorKind::NotFound,
+        "Could not get mail content",
+    ))?;
 
     let body = message
         .body()
@@ -170,10 +750,10 @@ pub fn get_message(session: &mut Session, mailbox: &str, uid: u32) -> Result<Mes
 
     let parsed = parser.parse(body).ok_or("Could not parse message")?;
 
-    let html = parsed
-        .body_html(0)
-        .map(|s| s.to_string())
-        .unwrap_or_default();
+    let (html, text) = message_bodies(&parsed);
+    let attachments = attachments::parse_attachments(&parsed);
+    let html =
+        inline_images::resolve_cid_references(&html, &parsed, &attachments, account, mailbox, uid);
 
     Ok(Message {
         uid: message.uid,
@@ -186,19 +766,55 @@ pub fn get_message(session: &mut Session, mailbox: &str, uid: u32) -> Result<Mes
         headers: parsed
             .headers()
             .iter()
-            .map(|h| {
-                (
-                    h.name().to_string(),
-                    h.value().as_text().unwrap_or_default().to_string(),
-                )
-            })
+            .map(|h| (h.name().to_string(), header_text(h.value())))
             .collect(),
         flags: message.flags().iter().map(|f| f.to_string()).collect(),
         mailbox_name: mailbox.to_string(),
         body: html,
+        text,
+        attachments,
+        blocked_remote_content: 0,
+        trackers: vec![],
+        reply_to: parse_addrs(parsed.reply_to()).unwrap_or_default(),
     })
 }
 
+/// Get the HTML and plain text bodies of a message
+///
+/// Messages can have several body parts, e.g. text sections between
+/// attachments, which are joined in order. When a message has only one of the
+/// alternatives, the other is converted from it.
+///
+/// # Arguments
+/// * `parsed` - The parsed message
+/// # Returns
+/// * `(String, String)` - The HTML and plain text bodies
+///
+pub fn message_bodies(parsed: &mail_parser::Message) -> (String, String) {
+    let html_parts: Vec<String> = (0..parsed.html_body.len())
+        .filter_map(|i| parsed.body_html(i))
+        .map(|part| part.into_owned())
+        .collect();
+    let text_parts: Vec<String> = (0..parsed.text_body.len())
+        .filter_map(|i| parsed.body_text(i))
+        .map(|part| part.into_owned())
+        .collect();
+
+    match (html_parts.is_empty(), text_parts.is_empty()) {
+        (true, true) => (String::new(), String::new()),
+        (false, true) => {
+            let html = html_parts.join("\n");
+            let text = html_to_text(&html);
+            (html, text)
+        }
+        (true, false) => {
+            let text = text_parts.join("\n\n");
+            (text_to_html(&text), text)
+        }
+        (false, false) => (html_parts.join("\n"), text_parts.join("\n\n")),
+    }
+}
+
 /// Add flags to a message
 ///
 /// # Arguments
@@ -268,31 +884,278 @@ pub fn move_mail(session: &mut Session, mailbox: &str, uid: u32, destination: &s
 /// Get an authenticated IMAP session
 ///
 /// # Arguments
-/// * `auth` - The authenticator to use for authentication
+/// * `account` - The account holding the IMAP server settings
+/// * `credentials` - The credentials to authenticate with
 /// # Returns
 /// * `Result<Session>` - The IMAP session
 ///
-pub fn get_imap_session(auth: impl Authenticator) -> Result<Session> {
-    let domain = GOOGLE_IMAP_HOST;
-    let port = GOOGLE_IMAP_PORT;
+pub fn get_imap_session(account: &Account, credentials: &AccountCredentials) -> Result<Session> {
+    let (session, _) = get_imap_session_with_socket(account, credentials)?;
+    Ok(session)
+}
+
+/// Get an authenticated IMAP session along with a handle to its socket
+///
+/// The socket handle can be shut down from another thread to interrupt a
+/// blocking read, e.g. to stop an IDLE command.
+///
+/// # Arguments
+/// * `account` - The account holding the IMAP server settings
+/// * `credentials` - The credentials to authenticate with
+/// # Returns
+/// * `Result<(Session, TcpStream)>` - The IMAP session and its socket
+///
+pub fn get_imap_session_with_socket(
+    account: &Account,
+    credentials: &AccountCredentials,
+) -> Result<(Session, TcpStream)> {
+    let server = &account.imap;
+    let host = server.host.as_str();
 
-    let tls = native_tls::TlsConnector::new().unwrap();
+    let tcp = connect(host, server.port)?;
+    let socket = tcp.try_clone()?;
 
-    let client = imap::connect((domain, port), domain, &tls)?;
+    let connection = |stream| {
+        let connection = Connection::new(stream);
+        let taps = (connection.capture.clone(), connection.literals.clone());
+        (connection, taps)
+    };
 
-    let session = client.authenticate("XOAUTH2", &auth).map_err(|e| e.0)?;
+    let (client, (capture, literals)) = match server.security {
+        Security::Tls => {
+            let tls = TlsConnector::new().map_err(|e| Error::from(e.to_string()))?;
+            let stream = tls
+                .connect(host, tcp)
+                .map_err(|e| Error::new(ErrorKind::Tls, format!("TLS handshake failed: {}", e)))?;
+            let (connection, capture) = connection(Stream::Tls(stream));
+            let mut client = imap::Client::new(connection);
+            client.read_greeting()?;
+            (client, capture)
+        }
+        Security::StartTls => {
+            let tcp = start_tls(tcp)?;
+            let tls = TlsConnector::new().map_err(|e| Error::from(e.to_string()))?;
+            let stream = tls
+                .connect(host, tcp)
+                .map_err(|e| Error::new(ErrorKind::Tls, format!("TLS handshake failed: {}", e)))?;
+            let (connection, capture) = connection(Stream::Tls(stream));
+            (imap::Client::new(connection), capture)
+        }
+        Security::Plain => {
+            let (connection, capture) = connection(Stream::Plain(tcp));
+            let mut client = imap::Client::new(connection);
+            client.read_greeting()?;
+            (client, capture)
+        }
+    };
 
-    Ok(session)
+    let username = server.username(&account.email);
+
+    let session = match (server.auth, credentials) {
+        (AuthMethod::XOAuth2, AccountCredentials::OAuth(oauth)) => client
+            .authenticate("XOAUTH2", &oauth)
+            .map_err(|e| login_error(e.0))?,
+        (AuthMethod::Login, AccountCredentials::Password(password)) => client
+            .login(username, password.password())
+            .map_err(|e| login_error(e.0))?,
+        (AuthMethod::Plain, AccountCredentials::Password(password)) => {
+            let auth = PlainAuthenticator {
+                user: username,
+                password: password.password(),
+            };
+            client
+                .authenticate("PLAIN", &auth)
+                .map_err(|e| login_error(e.0))?
+        }
+        _ => {
+            return Err(Error::from(
+                "Stored credentials do not match the authentication method",
+            ))
+        }
+    };
+
+    Ok((Session::new(session, capture, literals), socket))
+}
+
+/// Tell rejected credentials apart from connection failures during login
+fn login_error(error: imap::Error) -> Error {
+    match error {
+        imap::Error::No(message) | imap::Error::Bad(message) => Error::new(
+            ErrorKind::Auth,
+            format!("Authentication failed: {}", message),
+        ),
+        error => error.into(),
+    }
+}
+
+/// Open a TCP connection to a server
+///
+/// Every address of the host is tried in turn. The socket gets read and write
+/// timeouts, which also bound the TLS handshake and the login.
+///
+/// # Arguments
+/// * `host` - The host name of the server
+/// * `port` - The port of the server
+/// # Returns
+/// * `Result<TcpStream>` - The connected socket
+///
+fn connect(host: &str, port: u16) -> Result<TcpStream> {
+    let mut last_error = None;
+
+    let addresses = (host, port).to_socket_addrs().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("Could not resolve {}: {}", host, e),
+        )
+    })?;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(tcp) => {
+                tcp.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+                tcp.set_write_timeout(Some(COMMAND_TIMEOUT))?;
+                return Ok(tcp);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("No address found for {}", host),
+        )
+        .into()),
+    }
+}
+
+/// Upgrade a plain IMAP connection with STARTTLS
+///
+/// Reads the server greeting and issues the STARTTLS command. The returned
+/// stream is ready for the TLS handshake.
+fn start_tls(tcp: TcpStream) -> Result<TcpStream> {
+    let mut reader = BufReader::new(tcp.try_clone()?);
+    let mut writer = tcp;
+    let mut line = String::new();
+
+    // Server greeting
+    reader.read_line(&mut line)?;
+    if !line.starts_with("* OK") {
+        return Err(Error::from(format!("Unexpected greeting: {}", line.trim())));
+    }
+
+    writer.write_all(b"a0 STARTTLS\r\n")?;
+    writer.flush()?;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::from("Connection closed during STARTTLS"));
+        }
+        if line.starts_with("a0 ") {
+            break;
+        }
+    }
+
+    if !line.starts_with("a0 OK") {
+        return Err(Error::from(format!("STARTTLS rejected: {}", line.trim())));
+    }
+
+    Ok(writer)
+}
+
+/// Get an SMTP transport for sending mail
+///
+/// # Arguments
+/// * `account` - The account holding the SMTP server settings
+/// * `credentials` - The credentials to authenticate with
+/// # Returns
+/// * `Result<SmtpTransport>` - The SMTP transport
+///
+pub fn get_smtp_transport(
+    account: &Account,
+    credentials: &AccountCredentials,
+) -> Result<SmtpTransport> {
+    let server = &account.smtp;
+    let host = server.host.as_str();
+
+    let builder = match server.security {
+        Security::Tls => SmtpTransport::relay(host)?,
+        Security::StartTls => SmtpTransport::starttls_relay(host)?,
+        Security::Plain => SmtpTransport::builder_dangerous(host),
+    };
+
+    let (mechanism, credentials): (Mechanism, Credentials) = match (server.auth, credentials) {
+        (AuthMethod::XOAuth2, AccountCredentials::OAuth(oauth)) => {
+            (Mechanism::Xoauth2, oauth.into())
+        }
+        (AuthMethod::Login, AccountCredentials::Password(password)) => (
+            Mechanism::Login,
+            Credentials::new(
+                server.username(&account.email).to_string(),
+                password.password().to_string(),
+            ),
+        ),
+        (AuthMethod::Plain, AccountCredentials::Password(password)) => (
+            Mechanism::Plain,
+            Credentials::new(
+                server.username(&account.email).to_string(),
+                password.password().to_string(),
+            ),
+        ),
+        _ => {
+            return Err(Error::from(
+                "Stored credentials do not match the authentication method",
+            ))
+        }
+    };
+
+    let transport = builder
+        .port(server.port)
+        .timeout(Some(COMMAND_TIMEOUT))
+        .authentication(vec![mechanism])
+        .credentials(credentials)
+        .build();
+
+    Ok(transport)
+}
+
+/// Fetch the raw RFC822 content of a message without marking it as seen
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// * `uid` - The UID of the message
+/// # Returns
+/// * `Result<Vec<u8>>` - The raw message
+///
+pub fn fetch_raw_message(session: &mut Session, mailbox: &str, uid: u32) -> Result<Vec<u8>> {
+    session.select(mailbox)?;
+
+    let responses = session.uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")?;
+    let fetch = responses.first().ok_or(Error::new(
+        ErrorKind::NotFound,
+        "Could not get mail content",
+    ))?;
+
+    Ok(fetch
+        .body()
+        .ok_or(Error::from("Message is missing body"))?
+        .to_vec())
 }
 
 /// Save a draft message
 ///
+/// The new draft is appended before the old one is removed, so a failure in
+/// between leaves a duplicate rather than losing the draft. With UIDPLUS the
+/// UID comes from APPENDUID and only the old draft is expunged.
+///
 /// # Arguments
 /// * `imap_session` - The IMAP session
 /// * `mailbox` - The mailbox to save the draft in
-/// * `uid` - The UID of the draft to update, if any
-/// * `subject` - The subject of the draft
-/// * `body` - The body of the draft
+/// * `uid` - The UID of the draft to replace, if any
+/// * `message` - The RFC822 message, see `compose::build_draft`
+/// * `message_id` - The Message-ID of the message, used to find its UID without UIDPLUS
 /// # Returns
 /// * `Result<u32>` - The new UID of the saved draft
 ///
@@ -300,81 +1163,56 @@ pub fn save_draft(
     imap_session: &mut Session,
     mailbox: &str,
     uid: Option<u32>,
-    subject: Option<&str>,
-    body: Option<&str>,
-    to: Option<Vec<EmailAddress>>,
-    cc: Option<Vec<EmailAddress>>,
-    bcc: Option<Vec<EmailAddress>>,
+    message: &[u8],
+    message_id: &str,
 ) -> Result<u32> {
-    // Select the mailbox
-    imap_session.select(mailbox)?;
-
-    // If UID is provided, fetch and delete the existing draft
-    if let Some(uid) = uid {
-        let fetch_result = imap_session.uid_fetch(uid.to_string(), "RFC822")?;
-        if fetch_result.is_empty() {
-            return Err(Error::from("Draft not found"));
-        }
+    let uidplus = imap_session.capabilities()?.has_str("UIDPLUS");
 
-        // Delete the existing draft
-        imap_session.uid_store(uid.to_string(), "+FLAGS (\\Deleted)")?;
-        imap_session.expunge()?;
-    }
+    let (appended, response) =
+        imap_session.capture(|session| session.append_with_flags(mailbox, message, &[Flag::Draft]));
+    appended?;
 
-    // Generate a unique Message-ID
-    let message_id = format!("<{}@mail-client>", Uuid::new_v4());
+    let new_uid = match append_uid(&response).filter(|_| uidplus) {
+        Some(new_uid) => new_uid,
+        None => {
+            imap_session.select(mailbox)?;
+            let uids = imap_session.uid_search(format!("HEADER Message-ID {}", message_id))?;
+            *uids
+                .iter()
+                .max()
+                .ok_or(Error::from("Failed to retrieve UID of the new draft"))?
+        }
+    };
 
-    // Create the raw email content
-    let mut raw_email = format!(
-        "Message-ID: {}\r\nSubject: {}\r\n",
-        message_id,
-        subject.unwrap_or("")
-    );
+    if let Some(uid) = uid.filter(|uid| *uid != new_uid) {
+        imap_session.select(mailbox)?;
+        imap_session.uid_store(uid.to_string(), "+FLAGS.SILENT (\\Deleted)")?;
 
-    // set the mime type to html
-    raw_email.push_str("Content-Type: text/html; charset=UTF-8\r\n");
-    if let Some(to) = to {
-        raw_email.push_str(&format!("To: {}\r\n", parse_addrs_to_string(to)));
-    }
-    if let Some(cc) = cc {
-        raw_email.push_str(&format!("Cc: {}\r\n", parse_addrs_to_string(cc)));
-    }
-    if let Some(bcc) = bcc {
-        raw_email.push_str(&format!("Bcc: {}\r\n", parse_addrs_to_string(bcc)));
+        if uidplus {
+            imap_session.uid_expunge(uid.to_string())?;
+        } else {
+            // EXPUNGE removes every deleted message, so only use it if the old draft is the only one
+            let deleted = imap_session.uid_search("DELETED")?;
+            if deleted.len() == 1 && deleted.contains(&uid) {
+                imap_session.expunge()?;
+            }
+        }
     }
 
-    raw_email.push_str(&format!("\r\n{}", body.unwrap_or("")));
-
-    // Append the new draft to the mailbox
-    imap_session.append(mailbox, raw_email.as_bytes())?;
-
-    // Search for the newly created draft using the Message-ID
-    imap_session.select(mailbox)?;
-    let uids = imap_session.uid_search(format!("HEADER Message-ID {}", message_id))?;
-    if let Some(new_uid) = uids.iter().max() {
-        // Set the \Draft flag for the new draft
-        imap_session.uid_store(new_uid.to_string(), "+FLAGS (\\Draft)")?;
-        Ok(*new_uid)
-    } else {
-        Err(Error::from("Failed to retrieve UID of the new draft"))
-    }
+    Ok(new_uid)
 }
 
-fn parse_addrs_to_string(addrs: Vec<EmailAddress>) -> String {
-    addrs
-        .into_iter()
-        .map(|addr| {
-            if let Some(name) = addr.name {
-                format!("{} <{}>", name, addr.address)
-            } else {
-                addr.address
-            }
-        })
-        .collect::<Vec<String>>()
-        .join(", ")
+/// Get the UID from the APPENDUID response code of an APPEND (RFC 4315)
+fn append_uid(response: &[u8]) -> Option<u32> {
+    let response = String::from_utf8_lossy(response);
+    let start = response.to_ascii_uppercase().rfind("[APPENDUID ")? + "[APPENDUID ".len();
+    let code = response[start..].split(']').next()?;
+
+    // The UID validity, then the UID
+    code.split_whitespace().nth(1)?.parse().ok()
 }
 
-fn parse_addrs<'x>(addrs: Option<&Address<'x>>) -> Option<Vec<EmailAddress>> {
+pub fn parse_addrs<'x>(addrs: Option<&Address<'x>>) -> Option<Vec<EmailAddress>> {
     let addr = addrs?;
     Some(
         addr.iter()
@@ -391,3 +1229,42 @@ fn parse_addrs<'x>(addrs: Option<&Address<'x>>) -> Option<Vec<EmailAddress>> {
             .collect(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_ascii_strings() {
+        assert_eq!(imap_string("Hello").unwrap(), "\"Hello\"");
+        assert_eq!(imap_string(r#"a "b" \c"#).unwrap(), r#""a \"b\" \\c""#);
+    }
+
+    #[test]
+    fn sends_non_ascii_as_literal() {
+        assert_eq!(imap_string("Grüße").unwrap(), "{7}\r\nGrüße");
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for value in ["a\r\nA1 DELETE INBOX", "a\nb", "a\0b", "Grüße\r\n"] {
+            let result = imap_string(value);
+            assert!(
+                matches!(&result, Err(e) if matches!(e.kind(), ErrorKind::InvalidInput)),
+                "accepted {:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn finds_literal_announcements() {
+        let command = b"A1 UID SEARCH CHARSET UTF-8 SUBJECT {7}\r\nGr\xc3\xbc\xc3\x9fe ALL\r\n";
+        assert_eq!(literal_announcement(command), Some((41, 7)));
+        assert_eq!(
+            literal_announcement(b"A1 UID SEARCH SUBJECT \"{7}\" ALL\r\n"),
+            None
+        );
+        assert_eq!(literal_announcement(b"A1 NOOP\r\n"), None);
+    }
+}
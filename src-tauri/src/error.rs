@@ -1,7 +1,7 @@
 use std::{
     error::{self, Error as StdError},
     fmt,
-    io::Error as IoError,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
     result,
     str::Utf8Error,
 };
@@ -11,13 +11,70 @@ use keyring::Error as KeyringError;
 use lettre::transport::smtp::Error as SmtpError;
 use oauth2::reqwest::Error as ReqwestError;
 use oauth2::{url::ParseError as UrlParseError, ErrorResponse, RequestTokenError};
+use rusqlite::Error as SqliteError;
 use serde::{ser::SerializeStruct, Serialize};
 use serde_json::Error as JsonError;
+use tantivy::TantivyError;
 
 #[derive(Debug)]
 pub struct Error {
     message: String,
     kind: ErrorKind,
+    context: ErrorContext,
+}
+
+/// A stable code for the frontend to decide how to recover from an error
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum ErrorCode {
+    /// The credentials are missing or were rejected, the user has to log in again
+    Auth,
+    /// The server could not be reached or stopped responding
+    Network,
+    /// The secure connection could not be established
+    Tls,
+    /// The account, mailbox or message does not exist
+    NotFound,
+    /// The server refused the request
+    ServerRejected,
+    /// The mailbox or the account is full
+    Quota,
+    /// The request itself is invalid, e.g. a malformed address
+    InvalidInput,
+    /// The local cache, search index, keyring or file system failed
+    Storage,
+    /// Anything else, most likely a bug
+    Internal,
+}
+
+/// What an operation was working on when it failed
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ErrorContext {
+    pub account: Option<String>,
+    pub mailbox: Option<String>,
+    pub uid: Option<u32>,
+}
+
+impl ErrorContext {
+    pub fn account(account: &str) -> Self {
+        Self {
+            account: Some(account.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn mailbox(account: &str, mailbox: &str) -> Self {
+        Self {
+            mailbox: Some(mailbox.to_string()),
+            ..Self::account(account)
+        }
+    }
+
+    pub fn message(account: &str, mailbox: &str, uid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            ..Self::mailbox(account, mailbox)
+        }
+    }
 }
 
 impl Error {
@@ -25,12 +82,130 @@ impl Error {
         Self {
             message: msg.into(),
             kind,
+            context: ErrorContext::default(),
         }
     }
 
     pub fn kind(&self) -> &ErrorKind {
         &self.kind
     }
+
+    /// Add context to the error, keeping what was already known
+    pub fn with_context(mut self, context: ErrorContext) -> Self {
+        self.context.account = self.context.account.or(context.account);
+        self.context.mailbox = self.context.mailbox.or(context.mailbox);
+        self.context.uid = self.context.uid.or(context.uid);
+        self
+    }
+
+    /// The code of the error, derived from its kind
+    pub fn code(&self) -> ErrorCode {
+        match &self.kind {
+            ErrorKind::Io(e) => io_code(e),
+            ErrorKind::Reqwest(_) => ErrorCode::Network,
+            ErrorKind::Json(_) | ErrorKind::Utf8(_) => ErrorCode::Internal,
+            ErrorKind::UrlParse(_) => ErrorCode::InvalidInput,
+            ErrorKind::Imap(e) => imap_code(e),
+            ErrorKind::Smtp(e) => smtp_code(e),
+            ErrorKind::Keyring(KeyringError::NoEntry) => ErrorCode::Auth,
+            ErrorKind::Keyring(_) | ErrorKind::Sqlite(_) | ErrorKind::Tantivy(_) => {
+                ErrorCode::Storage
+            }
+            ErrorKind::RequestTokenError | ErrorKind::Auth | ErrorKind::OAuth => ErrorCode::Auth,
+            ErrorKind::Tls => ErrorCode::Tls,
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::InvalidInput | ErrorKind::InvalidAddress => ErrorCode::InvalidInput,
+            ErrorKind::Generic(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// Whether trying again later may succeed without changing anything
+    pub fn is_retryable(&self) -> bool {
+        match &self.kind {
+            ErrorKind::Smtp(e) => e.is_transient() || e.is_timeout(),
+            _ => self.code() == ErrorCode::Network,
+        }
+    }
+}
+
+/// Attach what an operation was working on to its error
+pub trait Context<T> {
+    fn context(self, context: ErrorContext) -> Result<T>;
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context(self, context: ErrorContext) -> Result<T> {
+        self.map_err(|e| e.with_context(context))
+    }
+}
+
+fn io_code(error: &IoError) -> ErrorCode {
+    match error.kind() {
+        IoErrorKind::TimedOut
+        | IoErrorKind::WouldBlock
+        | IoErrorKind::ConnectionRefused
+        | IoErrorKind::ConnectionReset
+        | IoErrorKind::ConnectionAborted
+        | IoErrorKind::NotConnected
+        | IoErrorKind::AddrNotAvailable
+        | IoErrorKind::BrokenPipe
+        | IoErrorKind::UnexpectedEof => ErrorCode::Network,
+        IoErrorKind::NotFound => ErrorCode::NotFound,
+        IoErrorKind::PermissionDenied | IoErrorKind::AlreadyExists | IoErrorKind::WriteZero => {
+            ErrorCode::Storage
+        }
+        IoErrorKind::InvalidInput | IoErrorKind::InvalidData => ErrorCode::InvalidInput,
+        _ => ErrorCode::Internal,
+    }
+}
+
+fn imap_code(error: &ImapError) -> ErrorCode {
+    match error {
+        ImapError::Io(e) => match io_code(e) {
+            // The socket of the session failed in some other way, e.g. in the TLS layer
+            ErrorCode::Internal => ErrorCode::Network,
+            code => code,
+        },
+        ImapError::ConnectionLost => ErrorCode::Network,
+        ImapError::Tls(_) | ImapError::TlsHandshake(_) => ErrorCode::Tls,
+        ImapError::No(message) | ImapError::Bad(message) => {
+            // The response code is dropped by the imap crate, so look at the text
+            let message = message.to_lowercase();
+            if message.contains("quota") {
+                ErrorCode::Quota
+            } else if message.contains("nonexistent")
+                || message.contains("not exist")
+                || message.contains("no such")
+            {
+                ErrorCode::NotFound
+            } else {
+                ErrorCode::ServerRejected
+            }
+        }
+        ImapError::Validate(_) => ErrorCode::InvalidInput,
+        ImapError::Append => ErrorCode::ServerRejected,
+        _ => ErrorCode::Internal,
+    }
+}
+
+fn smtp_code(error: &SmtpError) -> ErrorCode {
+    if error.is_tls() {
+        return ErrorCode::Tls;
+    }
+    if error.is_timeout() {
+        return ErrorCode::Network;
+    }
+    if error.is_client() {
+        return ErrorCode::InvalidInput;
+    }
+
+    match error.status().map(|code| code.to_string()).as_deref() {
+        Some("530" | "534" | "535") => ErrorCode::Auth,
+        Some("452" | "552") => ErrorCode::Quota,
+        Some(_) => ErrorCode::ServerRejected,
+        None if error.is_response() => ErrorCode::ServerRejected,
+        None => ErrorCode::Network,
+    }
 }
 
 impl From<KeyringError> for Error {
@@ -116,16 +291,31 @@ impl From<SmtpError> for Error {
     }
 }
 
+impl From<SqliteError> for Error {
+    fn from(sqlite_error: SqliteError) -> Self {
+        Error::new(ErrorKind::Sqlite(sqlite_error), "Cache database error")
+    }
+}
+
+impl From<TantivyError> for Error {
+    fn from(tantivy_error: TantivyError) -> Self {
+        Error::new(ErrorKind::Tantivy(tantivy_error), "Search index error")
+    }
+}
+
 impl Serialize for Error {
     fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         let source = self.source().unwrap_or(&self);
-        let mut state = serializer.serialize_struct("Error", 2)?;
+        let mut state = serializer.serialize_struct("Error", 5)?;
 
         state.serialize_field("message", &source.to_string())?;
         state.serialize_field("kind", "MailError")?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("retryable", &self.is_retryable())?;
+        state.serialize_field("context", &self.context)?;
         state.end()
     }
 }
@@ -141,6 +331,8 @@ impl StdError for Error {
             ErrorKind::Utf8(e) => Some(e),
             ErrorKind::Smtp(e) => Some(e),
             ErrorKind::Keyring(e) => Some(e),
+            ErrorKind::Sqlite(e) => Some(e),
+            ErrorKind::Tantivy(e) => Some(e),
             ErrorKind::Generic(_e) => None,
             _ => None,
         }
@@ -161,7 +353,21 @@ pub enum ErrorKind {
     Utf8(Utf8Error),
     Smtp(SmtpError),
     Keyring(KeyringError),
+    Sqlite(SqliteError),
+    Tantivy(TantivyError),
     RequestTokenError,
+    /// The server rejected the credentials
+    Auth,
+    /// The secure connection could not be established
+    Tls,
+    /// The account, mailbox or message does not exist
+    NotFound,
+    /// The request itself is invalid
+    InvalidInput,
+    /// An email address could not be parsed
+    InvalidAddress,
+    /// The OAuth flow failed, e.g. the profile or the token response was malformed
+    OAuth,
     Generic(String),
 }
 
@@ -0,0 +1,309 @@
+// This module keeps a dedicated IMAP connection per watched mailbox in IDLE
+// and emits events to the frontend when the mailbox changes.
+//
+// The worker keeps the UIDs of the mailbox and, with CONDSTORE, its
+// HIGHESTMODSEQ. When IDLE returns, only messages above the last UID and flags
+// changed since that mod-sequence are fetched. The UIDs are listed again only
+// once the server reported an expunge.
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use imap::extensions::idle::WaitOutcome;
+use imap::types::UnsolicitedResponse;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use crate::{
+    auth_store::AccountCredentials,
+    condstore,
+    config::Account,
+    email::{self, Session},
+    error::{Error, ErrorKind, Result},
+};
+
+/// Servers may drop IDLE connections after 30 minutes (RFC 2177), so IDLE is re-issued before that
+const IDLE_TIMEOUT: Duration = Duration::from_secs(25 * 60);
+
+/// Delay before reconnecting after the connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+pub const NEW_MESSAGES_EVENT: &str = "new-messages";
+pub const MESSAGES_EXPUNGED_EVENT: &str = "messages-expunged";
+pub const FLAGS_CHANGED_EVENT: &str = "flags-changed";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MailboxEvent {
+    pub email: String,
+    pub mailbox: String,
+    pub uids: Vec<u32>,
+}
+
+struct IdleWorker {
+    stop: Arc<AtomicBool>,
+    socket: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl IdleWorker {
+    fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        // Interrupt the blocking IDLE read, the socket is still valid if a holder panicked
+        let socket = self.socket.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(socket) = socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// The running IDLE workers, keyed by account and mailbox
+pub struct IdleWorkers {
+    workers: HashMap<(String, String), IdleWorker>,
+}
+
+impl IdleWorkers {
+    pub fn new() -> Self {
+        Self {
+            workers: HashMap::new(),
+        }
+    }
+
+    /// Start watching a mailbox, does nothing if it is already watched
+    pub fn watch(&mut self, handle: AppHandle, account: Account, mailbox: &str) {
+        let key = (account.email.clone(), mailbox.to_string());
+        if self.workers.contains_key(&key) {
+            return;
+        }
+
+        let worker = IdleWorker {
+            stop: Arc::new(AtomicBool::new(false)),
+            socket: Arc::new(Mutex::new(None)),
+        };
+
+        let stop = worker.stop.clone();
+        let socket = worker.socket.clone();
+        let mailbox = mailbox.to_string();
+
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                if let Err(e) = run_idle(&handle, &account, &mailbox, &stop, &socket) {
+                    println!("IDLE on {} {} failed: {}", account.email, mailbox, e);
+                }
+
+                if !stop.load(Ordering::SeqCst) {
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+            println!("Stopped IDLE on {} {}", account.email, mailbox);
+        });
+
+        self.workers.insert(key, worker);
+    }
+
+    /// Stop watching a mailbox
+    pub fn unwatch(&mut self, email: &str, mailbox: &str) {
+        if let Some(worker) = self
+            .workers
+            .remove(&(email.to_string(), mailbox.to_string()))
+        {
+            worker.stop();
+        }
+    }
+
+    /// Stop watching all mailboxes of an account
+    pub fn unwatch_account(&mut self, email: &str) {
+        self.workers.retain(|(worker_email, _), worker| {
+            if worker_email == email {
+                worker.stop();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Connect, then IDLE until the connection fails or the worker is stopped
+fn run_idle(
+    handle: &AppHandle,
+    account: &Account,
+    mailbox: &str,
+    stop: &AtomicBool,
+    socket: &Mutex<Option<TcpStream>>,
+) -> Result<()> {
+    let mut credentials = AccountCredentials::load(account)
+        .ok_or(Error::new(ErrorKind::Auth, "Credentials not found"))?;
+    tauri::async_runtime::block_on(credentials.refresh())?;
+
+    let (mut session, tcp) = email::get_imap_session_with_socket(account, &credentials)?;
+    *socket.lock().unwrap_or_else(|e| e.into_inner()) = Some(tcp);
+
+    // The worker may have been stopped while connecting
+    if stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let support = condstore::get_support(&mut session)?;
+    let selected = condstore::select(&mut session, mailbox, support, None)?;
+    let mut view = MailboxView {
+        uids: session.uid_search("ALL")?.into_iter().collect(),
+        highest_modseq: selected.highest_modseq,
+    };
+
+    println!("Started IDLE on {} {}", account.email, mailbox);
+
+    while !stop.load(Ordering::SeqCst) {
+        let (outcome, response) = session.capture(|session| {
+            session
+                .idle()
+                .and_then(|idle| idle.wait_with_timeout(IDLE_TIMEOUT))
+        });
+        if let WaitOutcome::TimedOut = outcome? {
+            continue;
+        }
+
+        let changes = view.update(&mut session, &response)?;
+        emit(handle, NEW_MESSAGES_EVENT, account, mailbox, changes.new);
+        emit(
+            handle,
+            MESSAGES_EXPUNGED_EVENT,
+            account,
+            mailbox,
+            changes.expunged,
+        );
+        emit(
+            handle,
+            FLAGS_CHANGED_EVENT,
+            account,
+            mailbox,
+            changes.changed,
+        );
+    }
+
+    let _ = session.logout();
+    Ok(())
+}
+
+/// What the worker knows about the watched mailbox
+struct MailboxView {
+    uids: BTreeSet<u32>,
+    /// `None` without CONDSTORE
+    highest_modseq: Option<u64>,
+}
+
+/// The UIDs that changed while idling
+struct Changes {
+    new: Vec<u32>,
+    expunged: Vec<u32>,
+    changed: Vec<u32>,
+}
+
+impl MailboxView {
+    /// Find out what changed, fetching only what the responses sent during IDLE point to
+    ///
+    /// # Arguments
+    /// * `session` - The IMAP session, with the mailbox selected
+    /// * `response` - What the server sent during IDLE
+    /// # Returns
+    /// * `Result<Changes>` - The new, expunged and changed messages
+    ///
+    fn update(&mut self, session: &mut Session, response: &[u8]) -> Result<Changes> {
+        let mut expunged_seen = false;
+        let mut fetched_seqs = vec![];
+        for line in condstore::lines(response) {
+            let Some(line) = line.strip_prefix("* ") else {
+                continue;
+            };
+            if line.ends_with(" EXPUNGE") || line.starts_with("VANISHED ") {
+                expunged_seen = true;
+            } else if let Some((seq, _)) = line.split_once(" FETCH (") {
+                fetched_seqs.extend(seq.parse::<u32>());
+            }
+        }
+        // Responses sent while running the commands below end up here
+        while let Ok(unsolicited) = session.unsolicited_responses.try_recv() {
+            if let UnsolicitedResponse::Expunge(_) = unsolicited {
+                expunged_seen = true;
+            }
+        }
+
+        let last_uid = self.uids.last().copied().unwrap_or(0);
+        let new: Vec<u32> = session
+            .uid_search(format!("UID {}:*", last_uid + 1))?
+            .into_iter()
+            // n:* always matches the last message, even if its UID is below n
+            .filter(|uid| *uid > last_uid)
+            .collect();
+
+        let mut expunged = vec![];
+        if expunged_seen {
+            let on_server: BTreeSet<u32> = session.uid_search("ALL")?.into_iter().collect();
+            expunged = self.uids.difference(&on_server).copied().collect();
+            self.uids.retain(|uid| on_server.contains(uid));
+        }
+
+        let changed: Vec<u32> = match self.highest_modseq {
+            Some(modseq) => {
+                let updates = condstore::fetch_changed_flags(session, modseq)?;
+                self.highest_modseq = updates
+                    .iter()
+                    .filter_map(|update| update.modseq)
+                    .chain([modseq])
+                    .max();
+                updates.into_iter().map(|update| update.uid).collect()
+            }
+            // Without CONDSTORE the FETCH responses only carry sequence numbers
+            None if !fetched_seqs.is_empty() => {
+                fetched_seqs.sort_unstable();
+                fetched_seqs.dedup();
+                let seqs = fetched_seqs
+                    .iter()
+                    .map(|seq| seq.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",");
+                session
+                    .fetch(seqs, "UID")?
+                    .iter()
+                    .filter_map(|fetch| fetch.uid)
+                    .collect()
+            }
+            None => vec![],
+        };
+        let changed = changed
+            .into_iter()
+            .filter(|uid| self.uids.contains(uid))
+            .collect();
+
+        self.uids.extend(&new);
+
+        Ok(Changes {
+            new,
+            expunged,
+            changed,
+        })
+    }
+}
+
+fn emit(handle: &AppHandle, event: &str, account: &Account, mailbox: &str, mut uids: Vec<u32>) {
+    if uids.is_empty() {
+        return;
+    }
+    uids.sort();
+
+    let payload = MailboxEvent {
+        email: account.email.clone(),
+        mailbox: mailbox.to_string(),
+        uids,
+    };
+
+    if let Err(e) = handle.emit(event, payload) {
+        println!("Failed to emit {}: {}", event, e);
+    }
+}
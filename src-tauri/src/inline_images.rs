@@ -0,0 +1,252 @@
+// This module resolves the `cid:` references of HTML messages to the inline
+// parts they name (RFC 2392).
+//
+// Small images are embedded as data URIs. Larger ones, and anything beyond the
+// total budget of a message, are served by the `mail-part` URI scheme, which
+// fetches the part from the server only when the web view loads it.
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use mail_parser::MimeHeaders;
+use tauri::http::{header::CONTENT_TYPE, Request, Response, StatusCode};
+use tauri::AppHandle;
+
+use crate::attachments::{self, Attachment};
+use crate::error::{Error, ErrorKind, Result};
+use crate::{get_account_config, get_account_state};
+
+pub const PROTOCOL: &str = "mail-part";
+
+/// The largest image embedded as a data URI
+const DATA_URI_MAX_SIZE: usize = 256 * 1024;
+/// The most image data embedded as data URIs in a single message
+const DATA_URI_MAX_TOTAL_SIZE: usize = 2 * 1024 * 1024;
+
+/// Rewrite the `cid:` references of an HTML body
+///
+/// # Arguments
+/// * `html` - The HTML body
+/// * `message` - The parsed message containing the inline parts
+/// * `attachments` - The attachments of the message, see `attachments::parse_attachments`
+/// * `account` - The account of the message
+/// * `mailbox` - The mailbox of the message
+/// * `uid` - The UID of the message
+/// # Returns
+/// * `String` - The HTML body with data URIs or `mail-part` URLs
+///
+pub fn resolve_cid_references(
+    html: &str,
+    message: &mail_parser::Message,
+    attachments: &[Attachment],
+    account: &str,
+    mailbox: &str,
+    uid: u32,
+) -> String {
+    let mut resolved = String::with_capacity(html.len());
+    let mut embedded = 0;
+    let mut rest = html;
+
+    while let Some(start) = find_ignore_ascii_case(rest, "cid:") {
+        resolved.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest
+            .find(|c: char| matches!(c, '"' | '\'' | ')' | '>' | '<') || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let reference = &rest[..end];
+        let content_id = percent_decode(&reference[4..]);
+
+        match resolve(&content_id, message, attachments, &mut embedded) {
+            Some(Resolved::DataUri(uri)) => resolved.push_str(&uri),
+            Some(Resolved::Part(part)) => {
+                resolved.push_str(&part_url(account, mailbox, uid, &part))
+            }
+            None => resolved.push_str(reference),
+        }
+        rest = &rest[end..];
+    }
+    resolved.push_str(rest);
+
+    resolved
+}
+
+enum Resolved {
+    DataUri(String),
+    Part(String),
+}
+
+fn resolve(
+    content_id: &str,
+    message: &mail_parser::Message,
+    attachments: &[Attachment],
+    embedded: &mut usize,
+) -> Option<Resolved> {
+    let attachment = attachments
+        .iter()
+        .find(|a| a.content_id.as_deref().map(normalize_content_id) == Some(content_id))?;
+
+    let fits = attachment.size <= DATA_URI_MAX_SIZE
+        && *embedded + attachment.size <= DATA_URI_MAX_TOTAL_SIZE;
+    if !fits
+        || !attachment.mime_type.starts_with("image/")
+        || !is_safe_mime_type(&attachment.mime_type)
+    {
+        return Some(Resolved::Part(attachment.part.clone()));
+    }
+
+    let part = message
+        .parts
+        .iter()
+        .find(|part| part.content_id().map(normalize_content_id) == Some(content_id))?;
+    *embedded += attachment.size;
+
+    Some(Resolved::DataUri(format!(
+        "data:{};base64,{}",
+        attachment.mime_type,
+        STANDARD.encode(part.contents())
+    )))
+}
+
+/// Get the `mail-part` URL of a part of a message
+pub fn part_url(account: &str, mailbox: &str, uid: u32, part: &str) -> String {
+    format!(
+        "{}/{}/{}/{}/{}",
+        base_url(),
+        URL_SAFE_NO_PAD.encode(account),
+        URL_SAFE_NO_PAD.encode(mailbox),
+        uid,
+        part
+    )
+}
+
+/// The part of a message a `mail-part` URL points to
+#[derive(Debug, Clone)]
+pub struct PartLocation {
+    pub account: String,
+    pub mailbox: String,
+    pub uid: u32,
+    pub part: String,
+}
+
+/// Parse a `mail-part` URL, see `part_url`
+pub fn parse_part_url(url: &str) -> Option<PartLocation> {
+    parse_part_path(url.strip_prefix(&base_url())?)
+}
+
+fn parse_part_path(path: &str) -> Option<PartLocation> {
+    let decode = |segment: &str| {
+        URL_SAFE_NO_PAD
+            .decode(segment)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+    };
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let [account, mailbox, uid, part] = segments[..] else {
+        return None;
+    };
+
+    Some(PartLocation {
+        account: decode(account)?,
+        mailbox: decode(mailbox)?,
+        uid: uid.parse().ok()?,
+        part: part.to_string(),
+    })
+}
+
+fn base_url() -> String {
+    // Windows and Android only allow custom schemes as http://<scheme>.localhost
+    if cfg!(any(windows, target_os = "android")) {
+        format!("http://{}.localhost", PROTOCOL)
+    } else {
+        format!("{}://localhost", PROTOCOL)
+    }
+}
+
+/// Serve a request of the `mail-part` URI scheme
+pub async fn serve(handle: &AppHandle, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let response = match fetch(handle, request.uri().path()).await {
+        Ok((mime_type, contents)) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, mime_type)
+            .body(contents),
+        Err(e) => {
+            println!("Failed to serve {}: {}", request.uri(), e);
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into_bytes())
+        }
+    };
+
+    response.unwrap_or_default()
+}
+
+async fn fetch(handle: &AppHandle, path: &str) -> Result<(String, Vec<u8>)> {
+    let location = parse_part_path(path).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid {} URL: {}", PROTOCOL, path),
+        )
+    })?;
+
+    let account_config = get_account_config(handle, &location.account).await?;
+    let account = get_account_state(handle, &account_config).await?;
+
+    account
+        .with_imap_session_retried(move |imap_session| {
+            attachments::fetch_part(
+                imap_session,
+                &location.mailbox,
+                location.uid,
+                &location.part,
+            )
+        })
+        .await
+}
+
+fn normalize_content_id(content_id: &str) -> &str {
+    content_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+}
+
+/// Only embed MIME types that cannot break out of the data URI
+fn is_safe_mime_type(mime_type: &str) -> bool {
+    mime_type
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '+' | '-' | '.'))
+}
+
+fn find_ignore_ascii_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Decode the `%XX` escapes of a `cid:` URL
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -1,61 +1,244 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-use auth_store::PersistedCredentials;
+use auth_store::AccountCredentials;
+use lettre::SmtpTransport;
 use tauri::async_runtime::Mutex;
 
-use config::Config;
+use config::{Account, Config};
+use connection::ConnectionState;
 use email::Session;
+use error::ErrorContext;
+use pool::{PooledSession, SessionPool};
 use tauri::Manager;
 
+mod attachments;
 mod auth;
 mod auth_store;
+mod cache;
 mod commands;
+mod compose;
+mod condstore;
 mod config;
+mod connection;
 mod constants;
+mod discovery;
 mod email;
 mod error;
+mod idle;
+mod inline_images;
+mod oauth_provider;
+mod pool;
+mod sanitize;
+mod search;
+mod search_index;
+mod sync;
+mod threads;
+mod trackers;
 mod util;
 
 // Global states:
-// Mutex<AppState> - to manage the state of the accounts, including their credentials and IMAP sessions.
+// Mutex<AppState> - to look up the state of the accounts, each with its own credentials lock and pool of IMAP sessions.
 // Mutex<OAuthState> - to manage the OAuth Flow state, including pkce and csrf tokens
 // Mutex<config::Config> - to manage the account configuration, including the list of accounts and their settings.
+// Mutex<cache::Cache> - to manage the local message cache used for offline reading.
+// Mutex<IdleWorkers> - to manage the background IDLE connections watching mailboxes for changes.
 
 struct AccountState {
-    credentials: auth_store::OAuthCredentials,
-    imap_session: Option<Session>,
+    account: Account,
+    credentials: Arc<Mutex<AccountCredentials>>,
+    sessions: SessionPool,
+    connection: std::sync::Mutex<ConnectionState>,
+    handle: tauri::AppHandle,
 }
 
 struct AppState {
-    accounts: HashMap<String, AccountState>,
+    accounts: HashMap<String, Arc<AccountState>>,
 }
 
 impl AccountState {
-    async fn get_imap_session(&mut self) -> error::Result<&mut Session> {
-        if self.imap_session.is_some() {
-            // Test if the session is still valid
-            match self.imap_session.as_mut().unwrap().noop() {
-                Ok(_) => {
-                    return Ok(self.imap_session.as_mut().unwrap());
+    fn new(handle: tauri::AppHandle, account: Account, credentials: AccountCredentials) -> Self {
+        Self {
+            account,
+            credentials: Arc::new(Mutex::new(credentials)),
+            sessions: SessionPool::new(),
+            connection: std::sync::Mutex::new(ConnectionState::Connected),
+            handle,
+        }
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        self.connection
+            .lock()
+            .map(|state| *state)
+            .unwrap_or(ConnectionState::Connected)
+    }
+
+    /// Update the connection state, telling the frontend if it changed
+    fn set_connection_state(&self, state: ConnectionState) {
+        let changed = match self.connection.lock() {
+            Ok(mut current) if *current != state => {
+                *current = state;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            connection::emit(&self.handle, &self.account.email, state);
+        }
+    }
+
+    /// Get an IMAP session of the account, waiting if all of them are in use
+    ///
+    /// Connecting is retried with backoff while the server cannot be reached.
+    /// Once the account is offline a single attempt is made, so commands fail
+    /// fast and fall back to the cache.
+    async fn get_imap_session(&self) -> error::Result<PooledSession> {
+        let reservation = self.sessions.reserve().await?;
+
+        // Idle sessions are checked with a NOOP, which blocks on the network
+        let (reservation, idle) = run_blocking(move || {
+            let idle = reservation.take_idle();
+            Ok((reservation, idle))
+        })
+        .await?;
+        if let Some(session) = idle {
+            self.set_connection_state(ConnectionState::Connected);
+            return Ok(reservation.into_session(session));
+        }
+
+        let attempts = match self.connection_state() {
+            ConnectionState::Offline => 1,
+            _ => connection::CONNECT_ATTEMPTS,
+        };
+
+        let mut attempt = 1;
+        loop {
+            let error = match self.connect().await {
+                Ok(session) => {
+                    self.set_connection_state(ConnectionState::Connected);
+                    return Ok(reservation.into_session(session));
+                }
+                Err(e) => e,
+            };
+
+            match connection::classify(&error) {
+                connection::Failure::Auth => {
+                    self.set_connection_state(ConnectionState::AuthFailed);
+                    return Err(error);
                 }
-                Err(_) => {
-                    println!("IMAP session invalid, creating a new one");
-                    self.credentials.refresh().await?;
-                    self.imap_session = email::get_imap_session(&self.credentials).ok();
+                failure if failure.is_connection_failure() && attempt < attempts => {
+                    let delay = match failure {
+                        // The server hung up, e.g. with BYE, so it is reachable
+                        connection::Failure::Closed if attempt == 1 => Duration::ZERO,
+                        _ => connection::backoff(attempt),
+                    };
+                    println!(
+                        "Connecting to {} failed, retrying in {:?}: {}",
+                        self.account.imap.host, delay, error
+                    );
+
+                    attempt += 1;
+                    self.set_connection_state(ConnectionState::Reconnecting { attempt });
+                    tokio::time::sleep(delay).await;
+                }
+                failure => {
+                    if failure.is_connection_failure() {
+                        self.set_connection_state(ConnectionState::Offline);
+                    }
+                    return Err(error);
                 }
             }
-            return Ok(self.imap_session.as_mut().unwrap());
         }
+    }
 
-        if self.imap_session.is_none() {
-            println!("No IMAP session found, creating a new one");
+    /// Open a new authenticated IMAP session
+    async fn connect(&self) -> error::Result<Session> {
+        println!("No IMAP session available, creating a new one");
+        let mut credentials = self.credentials.clone().lock_owned().await;
+        credentials.refresh().await?;
 
-            self.credentials.refresh().await?;
-            let imap_session = email::get_imap_session(&self.credentials)?;
-            self.imap_session = Some(imap_session);
+        let account = self.account.clone();
+        run_blocking(move || email::get_imap_session(&account, &credentials)).await
+    }
+
+    /// Run IMAP commands on a session of the account
+    ///
+    /// The commands run on a blocking worker thread, so they do not hold up
+    /// the async runtime while waiting for the server. They are not retried,
+    /// use `with_imap_session_retried` for commands that are safe to repeat.
+    ///
+    /// # Arguments
+    /// * `command` - The commands to run with the session
+    /// # Returns
+    /// * `Result<T>` - The result of the commands
+    ///
+    async fn with_imap_session<T, F>(&self, command: F) -> error::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Session) -> error::Result<T> + Send + 'static,
+    {
+        let session = self.get_imap_session().await?;
+        self.run_on_session(session, command).await
+    }
+
+    /// Run idempotent IMAP commands, retrying them once on a new connection
+    ///
+    /// Only commands which leave the mailbox as it was when repeated, like
+    /// FETCH, SEARCH or STORE, may be retried. An APPEND or a MOVE could
+    /// otherwise be applied twice.
+    ///
+    /// # Arguments
+    /// * `command` - The commands to run with the session
+    /// # Returns
+    /// * `Result<T>` - The result of the commands
+    ///
+    async fn with_imap_session_retried<T, F>(&self, command: F) -> error::Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&mut Session) -> error::Result<T> + Send + Sync + 'static,
+    {
+        let command = Arc::new(command);
+
+        let session = self.get_imap_session().await?;
+        let first = command.clone();
+        match self.run_on_session(session, move |s| first(s)).await {
+            Err(e) if connection::classify(&e).is_connection_failure() => {
+                println!("IMAP connection lost, retrying on a new one: {}", e);
+                let session = self.get_imap_session().await?;
+                self.run_on_session(session, move |s| command(s)).await
+            }
+            result => result,
         }
+    }
 
-        Ok(self.imap_session.as_mut().unwrap())
+    /// Run commands on a session, dropping the session if the failure may have left it unusable
+    async fn run_on_session<T, F>(&self, mut session: PooledSession, command: F) -> error::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Session) -> error::Result<T> + Send + 'static,
+    {
+        let (session, result) = run_blocking(move || {
+            let result = command(&mut session);
+            Ok((session, result))
+        })
+        .await?;
+
+        if let Err(e) = &result {
+            if !pool::is_usable_after(e) {
+                session.discard();
+            }
+        }
+
+        result
+    }
+
+    /// Get an SMTP transport of the account
+    async fn get_smtp_transport(&self) -> error::Result<SmtpTransport> {
+        let mut credentials = self.credentials.lock().await;
+        credentials.refresh().await?;
+        email::get_smtp_transport(&self.account, &credentials)
     }
 }
 
@@ -66,31 +249,103 @@ impl AppState {
         }
     }
 
-    fn get_account(&mut self, email: &str) -> Option<&mut AccountState> {
+    fn get_account(
+        &mut self,
+        handle: &tauri::AppHandle,
+        account: &Account,
+    ) -> Option<Arc<AccountState>> {
+        let email = account.email.as_str();
+
+        // Drop the cached state if the server settings changed
+        if self.accounts.get(email).is_some_and(|state| {
+            state.account.imap != account.imap || state.account.smtp != account.smtp
+        }) {
+            self.remove_account(email);
+        }
+
         if !self.accounts.contains_key(email) {
-            let credentials = auth_store::OAuthCredentials::load(email)?;
-            let account_state = AccountState {
-                credentials,
-                imap_session: None,
-            };
-            self.accounts.insert(email.to_string(), account_state);
+            let credentials = AccountCredentials::load(account)?;
+            let account_state = AccountState::new(handle.clone(), account.clone(), credentials);
+            self.accounts
+                .insert(email.to_string(), Arc::new(account_state));
         }
 
-        Some(self.accounts.get_mut(email).unwrap())
+        self.accounts.get(email).cloned()
     }
 
     pub fn set_account(
         &mut self,
-        email: String,
-        credentials: auth_store::OAuthCredentials,
+        handle: &tauri::AppHandle,
+        account: Account,
+        credentials: AccountCredentials,
     ) -> error::Result<()> {
-        let account_state = AccountState {
-            credentials,
-            imap_session: None,
-        };
-        self.accounts.insert(email, account_state);
+        self.remove_account(&account.email);
+
+        let email = account.email.clone();
+        let account_state = AccountState::new(handle.clone(), account, credentials);
+        self.accounts.insert(email, Arc::new(account_state));
         Ok(())
     }
+
+    /// Forget an account, closing its idle sessions
+    pub fn remove_account(&mut self, email: &str) {
+        if let Some(state) = self.accounts.remove(email) {
+            state.sessions.close();
+        }
+    }
+}
+
+/// Get the state of an account
+///
+/// The app state is only locked while looking the account up, so commands on
+/// different accounts do not wait for each other.
+///
+/// # Arguments
+/// * `handle` - The app handle
+/// * `account` - The account configuration
+/// # Returns
+/// * `Result<Arc<AccountState>>` - The state holding the sessions and credentials of the account
+///
+async fn get_account_state(
+    handle: &tauri::AppHandle,
+    account: &Account,
+) -> error::Result<Arc<AccountState>> {
+    let app_state_mutex = handle.state::<Mutex<AppState>>();
+    let mut app_state = app_state_mutex.lock().await;
+
+    // The account is configured, so only its credentials can be missing
+    app_state.get_account(handle, account).ok_or(
+        error::Error::new(error::ErrorKind::Auth, "Credentials not found")
+            .with_context(ErrorContext::account(&account.email)),
+    )
+}
+
+/// Run blocking network or disk I/O on a worker thread
+///
+/// # Arguments
+/// * `work` - The blocking work
+/// # Returns
+/// * `Result<T>` - The result of the work
+///
+async fn run_blocking<T, F>(work: F) -> error::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> error::Result<T> + Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(work)
+        .await
+        .map_err(|e| error::Error::from(format!("Worker thread failed: {}", e)))?
+}
+
+/// Look up the configuration of an account by its email address
+async fn get_account_config(handle: &tauri::AppHandle, email: &str) -> error::Result<Account> {
+    let account_config_mutex = handle.state::<Mutex<Config>>();
+    let account_config = account_config_mutex.lock().await;
+
+    account_config.account(email).cloned().ok_or(
+        error::Error::new(error::ErrorKind::NotFound, "Account not found")
+            .with_context(ErrorContext::account(email)),
+    )
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -101,7 +356,17 @@ pub fn run() {
 
     builder
         .plugin(tauri_plugin_opener::init())
+        .register_asynchronous_uri_scheme_protocol(
+            inline_images::PROTOCOL,
+            |ctx, request, responder| {
+                let handle = ctx.app_handle().clone();
+                tauri::async_runtime::spawn(async move {
+                    responder.respond(inline_images::serve(&handle, request).await);
+                });
+            },
+        )
         .manage(Mutex::new(app_state))
+        .manage(Mutex::new(idle::IdleWorkers::new()))
         .setup(|app| {
             let config_path = app
                 .path()
@@ -111,15 +376,46 @@ pub fn run() {
             println!("Config path: {:?}", config_path);
             let config = Config::load(config_path).expect("Failed to load account config");
             app.manage(Mutex::new(config));
+
+            let cache_path = app
+                .path()
+                .app_data_dir()
+                .unwrap()
+                .join(constants::CACHE_FILE_NAME);
+            let cache = cache::Cache::open(cache_path).expect("Failed to open message cache");
+            app.manage(Mutex::new(cache));
+
+            let search_index_path = app
+                .path()
+                .app_data_dir()
+                .unwrap()
+                .join(constants::SEARCH_INDEX_DIR_NAME);
+            let search_index = search_index::SearchIndex::open(search_index_path)
+                .expect("Failed to open search index");
+            app.manage(Mutex::new(search_index));
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             commands::get_config,
             commands::config_add_account,
             commands::config_remove_account,
+            commands::discover_settings,
             commands::login_with_google,
+            commands::login_with_microsoft,
+            commands::login_with_password,
             commands::get_message,
-            commands::get_envelopes,
+            commands::allow_remote_content,
+            commands::set_unwrap_tracking_links,
+            commands::set_account_aliases,
+            commands::set_save_sent,
+            commands::reply_message,
+            commands::forward_message,
+            commands::save_attachment,
+            commands::save_attachment_to_temp,
+            commands::get_envelope_page,
+            commands::get_threads,
+            commands::search_messages,
+            commands::search_local,
             commands::send_email,
             commands::get_mailboxes,
             commands::remove_flags,
@@ -127,6 +423,9 @@ pub fn run() {
             commands::delete_message,
             commands::archive_message,
             commands::save_draft,
+            commands::open_draft,
+            commands::watch_mailbox,
+            commands::unwatch_mailbox,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,131 @@
+// This module describes the OAuth2 providers the loopback login flow can talk to.
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{AuthMethod, Security, ServerConfig},
+    constants::{
+        GOOGLE_AUTH_URI, GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET, GOOGLE_IMAP_HOST,
+        GOOGLE_IMAP_PORT, GOOGLE_MAIL_SCOPE, GOOGLE_PROFILE_API, GOOGLE_PROFILE_MAIL_SCOPE,
+        GOOGLE_PROFILE_SCOPE, GOOGLE_REVOKATION_URI, GOOGLE_SMTP_HOST, GOOGLE_SMTP_PORT,
+        GOOGLE_TOKEN_URI, MICROSOFT_AUTH_URI, MICROSOFT_CLIENT_ID, MICROSOFT_IMAP_HOST,
+        MICROSOFT_IMAP_PORT, MICROSOFT_IMAP_SCOPE, MICROSOFT_OFFLINE_SCOPE, MICROSOFT_OPENID_SCOPE,
+        MICROSOFT_PROFILE_MAIL_SCOPE, MICROSOFT_SMTP_HOST, MICROSOFT_SMTP_PORT,
+        MICROSOFT_SMTP_SCOPE, MICROSOFT_TOKEN_URI,
+    },
+};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum OAuthProvider {
+    #[default]
+    Google,
+    Microsoft,
+}
+
+/// Where the email address of the logged in user is read from
+pub enum ProfileSource {
+    /// A userinfo endpoint queried with the access token
+    UserInfo(&'static str),
+    /// The claims of the OpenID Connect id token
+    IdToken,
+}
+
+impl OAuthProvider {
+    pub fn client_id(&self) -> &'static str {
+        match self {
+            OAuthProvider::Google => GOOGLE_CLIENT_ID,
+            OAuthProvider::Microsoft => MICROSOFT_CLIENT_ID,
+        }
+    }
+
+    pub fn client_secret(&self) -> Option<&'static str> {
+        match self {
+            OAuthProvider::Google => Some(GOOGLE_CLIENT_SECRET),
+            OAuthProvider::Microsoft => None,
+        }
+    }
+
+    pub fn auth_uri(&self) -> &'static str {
+        match self {
+            OAuthProvider::Google => GOOGLE_AUTH_URI,
+            OAuthProvider::Microsoft => MICROSOFT_AUTH_URI,
+        }
+    }
+
+    pub fn token_uri(&self) -> &'static str {
+        match self {
+            OAuthProvider::Google => GOOGLE_TOKEN_URI,
+            OAuthProvider::Microsoft => MICROSOFT_TOKEN_URI,
+        }
+    }
+
+    pub fn revocation_uri(&self) -> Option<&'static str> {
+        match self {
+            OAuthProvider::Google => Some(GOOGLE_REVOKATION_URI),
+            OAuthProvider::Microsoft => None,
+        }
+    }
+
+    pub fn scopes(&self) -> &'static [&'static str] {
+        match self {
+            OAuthProvider::Google => &[
+                GOOGLE_MAIL_SCOPE,
+                GOOGLE_PROFILE_SCOPE,
+                GOOGLE_PROFILE_MAIL_SCOPE,
+            ],
+            OAuthProvider::Microsoft => &[
+                MICROSOFT_IMAP_SCOPE,
+                MICROSOFT_SMTP_SCOPE,
+                MICROSOFT_OFFLINE_SCOPE,
+                MICROSOFT_OPENID_SCOPE,
+                MICROSOFT_PROFILE_MAIL_SCOPE,
+            ],
+        }
+    }
+
+    pub fn profile_source(&self) -> ProfileSource {
+        match self {
+            OAuthProvider::Google => ProfileSource::UserInfo(GOOGLE_PROFILE_API),
+            // Tokens for the outlook resource are not accepted by the Graph userinfo
+            // endpoint, so the address is taken from the id token instead
+            OAuthProvider::Microsoft => ProfileSource::IdToken,
+        }
+    }
+
+    pub fn imap_server(&self) -> ServerConfig {
+        match self {
+            OAuthProvider::Google => ServerConfig {
+                host: GOOGLE_IMAP_HOST.to_string(),
+                port: GOOGLE_IMAP_PORT,
+                security: Security::Tls,
+                auth: AuthMethod::XOAuth2,
+                username: None,
+            },
+            OAuthProvider::Microsoft => ServerConfig {
+                host: MICROSOFT_IMAP_HOST.to_string(),
+                port: MICROSOFT_IMAP_PORT,
+                security: Security::Tls,
+                auth: AuthMethod::XOAuth2,
+                username: None,
+            },
+        }
+    }
+
+    pub fn smtp_server(&self) -> ServerConfig {
+        match self {
+            OAuthProvider::Google => ServerConfig {
+                host: GOOGLE_SMTP_HOST.to_string(),
+                port: GOOGLE_SMTP_PORT,
+                security: Security::Tls,
+                auth: AuthMethod::XOAuth2,
+                username: None,
+            },
+            OAuthProvider::Microsoft => ServerConfig {
+                host: MICROSOFT_SMTP_HOST.to_string(),
+                port: MICROSOFT_SMTP_PORT,
+                security: Security::StartTls,
+                auth: AuthMethod::XOAuth2,
+                username: None,
+            },
+        }
+    }
+}
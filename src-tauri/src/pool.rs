@@ -0,0 +1,157 @@
+// This module keeps a small pool of authenticated IMAP sessions per account,
+// so commands on one mailbox do not wait for a long fetch in another.
+//
+// Servers limit the number of connections per account, so at most
+// `MAX_SESSIONS` are open at once. Idle sessions are checked with NOOP before
+// they are handed out again, and sessions marked with `close_after_use` are
+// not returned at all.
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::connection;
+use crate::email::Session;
+use crate::error::{Error, ErrorKind, Result};
+
+/// The most IMAP sessions open at once per account
+const MAX_SESSIONS: usize = 3;
+
+#[derive(Clone)]
+pub struct SessionPool {
+    idle: Arc<Mutex<Vec<Session>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl SessionPool {
+    pub fn new() -> Self {
+        SessionPool {
+            idle: Arc::new(Mutex::new(vec![])),
+            permits: Arc::new(Semaphore::new(MAX_SESSIONS)),
+        }
+    }
+
+    /// Wait until a session may be used
+    pub async fn reserve(&self) -> Result<Reservation> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Error::from(e.to_string()))?;
+
+        Ok(Reservation {
+            pool: self.clone(),
+            permit,
+        })
+    }
+
+    /// Close all idle sessions, e.g. once the account is removed
+    pub fn close(&self) {
+        let sessions = match self.idle.lock() {
+            Ok(mut idle) => std::mem::take(&mut *idle),
+            Err(_) => return,
+        };
+        for mut session in sessions {
+            let _ = session.logout();
+        }
+    }
+}
+
+impl Default for SessionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The right to use one of the sessions of a pool
+pub struct Reservation {
+    pool: SessionPool,
+    permit: OwnedSemaphorePermit,
+}
+
+impl Reservation {
+    /// Take an idle session that is still alive, if any
+    pub fn take_idle(&self) -> Option<Session> {
+        loop {
+            let mut session = self.pool.idle.lock().ok()?.pop()?;
+            if session.noop().is_ok() {
+                return Some(session);
+            }
+            println!("IMAP session invalid, dropping it");
+        }
+    }
+
+    /// Use a session, which returns to the pool once dropped
+    pub fn into_session(self, session: Session) -> PooledSession {
+        PooledSession {
+            session: Some(session),
+            pool: self.pool,
+            _permit: self.permit,
+        }
+    }
+}
+
+/// Whether a session can run other commands after one failed with `error`
+///
+/// A command rejected with NO or BAD leaves the session in a known state. Other
+/// IMAP failures, e.g. a response that could not be parsed, may leave unread
+/// data on the connection.
+///
+/// # Arguments
+/// * `error` - The error of the command
+/// # Returns
+/// * `bool` - Whether the session may go back to the pool
+///
+pub fn is_usable_after(error: &Error) -> bool {
+    match error.kind() {
+        ErrorKind::Imap(imap::Error::No(_) | imap::Error::Bad(_) | imap::Error::Validate(_)) => {
+            true
+        }
+        ErrorKind::Imap(_) => false,
+        _ => !connection::classify(error).is_connection_failure(),
+    }
+}
+
+/// A session borrowed from a pool
+pub struct PooledSession {
+    /// Only taken when dropped
+    session: Option<Session>,
+    pool: SessionPool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledSession {
+    /// Close the session instead of returning it, e.g. once its connection failed
+    pub fn discard(mut self) {
+        self.close_after_use();
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        self.session.as_ref().expect("session taken before drop")
+    }
+}
+
+impl DerefMut for PooledSession {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.session.as_mut().expect("session taken before drop")
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        let Some(session) = self.session.take() else {
+            return;
+        };
+        if !session.is_reusable() {
+            return;
+        }
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.push(session);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// This module sanitizes the HTML of messages before it reaches the web view.
+//
+// Scripts, event handlers, forms and dangerous CSS are stripped. Remote images
+// and other resources are blocked unless the sender or the message is on the
+// account's allowlist, because loading them tells the sender the message was
+// opened. Tracking pixels and click trackers are reported, and tracked links
+// are unwrapped if the account asks for it.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use ammonia::{Builder, UrlRelative};
+
+use crate::config::Account;
+use crate::email::Message;
+use crate::inline_images;
+use crate::trackers;
+
+/// Attributes kept on every tag, mostly the presentational ones mail layouts rely on
+const GENERIC_ATTRIBUTES: &[&str] = &[
+    "style",
+    "align",
+    "valign",
+    "width",
+    "height",
+    "bgcolor",
+    "color",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "background",
+    "dir",
+    "face",
+    "size",
+];
+
+/// Declarations containing these are dropped from inline styles
+const DANGEROUS_CSS: &[&str] = &[
+    "expression(",
+    "javascript:",
+    "vbscript:",
+    "behavior",
+    "-moz-binding",
+    "@import",
+    // Escapes and comments can hide any of the above
+    "\\",
+    "/*",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct SanitizedHtml {
+    pub html: String,
+    /// The number of remote images and resources that were removed
+    pub blocked_remote_content: usize,
+}
+
+/// Sanitize the body of a message, allowing remote content if the account allows it
+pub fn sanitize_message(mut message: Message, account: &Account) -> Message {
+    let allow_remote = account.remote_content.allows(&message);
+    let trackers = trackers::detect(&message.body);
+    let link_rewrites = if account.unwrap_tracking_links {
+        trackers::link_rewrites(&trackers)
+    } else {
+        HashMap::new()
+    };
+    let sanitized = sanitize_html(&message.body, allow_remote, link_rewrites);
+
+    message.body = sanitized.html;
+    message.blocked_remote_content = sanitized.blocked_remote_content;
+    message.trackers = trackers;
+    message
+}
+
+/// Sanitize HTML
+///
+/// # Arguments
+/// * `html` - The HTML to sanitize
+/// * `allow_remote` - Whether to keep remote images and resources
+/// * `link_rewrites` - Links to replace, e.g. tracked links with their destinations
+/// # Returns
+/// * `SanitizedHtml` - The sanitized HTML and the number of blocked resources
+///
+pub fn sanitize_html(
+    html: &str,
+    allow_remote: bool,
+    link_rewrites: HashMap<String, String>,
+) -> SanitizedHtml {
+    let blocked = Arc::new(AtomicUsize::new(0));
+    let filter_blocked = blocked.clone();
+
+    let html = Builder::default()
+        .add_tags(&["font"])
+        .add_generic_attributes(GENERIC_ATTRIBUTES)
+        .add_url_schemes(&["data", "cid", inline_images::PROTOCOL])
+        .url_relative(UrlRelative::Deny)
+        .set_tag_attribute_value("a", "target", "_blank")
+        .attribute_filter(move |element, attribute, value| {
+            if attribute == "href" {
+                if let Some(destination) = link_rewrites.get(value) {
+                    return filter_attribute(
+                        element,
+                        attribute,
+                        destination,
+                        allow_remote,
+                        &filter_blocked,
+                    )
+                    .map(|value| value.into_owned().into());
+                }
+            }
+            filter_attribute(element, attribute, value, allow_remote, &filter_blocked)
+        })
+        .clean(html)
+        .to_string();
+
+    SanitizedHtml {
+        html,
+        blocked_remote_content: blocked.load(Ordering::Relaxed),
+    }
+}
+
+fn filter_attribute<'u>(
+    element: &str,
+    attribute: &str,
+    value: &'u str,
+    allow_remote: bool,
+    blocked: &AtomicUsize,
+) -> Option<Cow<'u, str>> {
+    match attribute {
+        "style" => Some(sanitize_style(value, allow_remote, blocked).into()),
+        "src" | "background" if is_remote(value) && !allow_remote => {
+            blocked.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        // Data URIs are only meant for embedded images
+        "href" if value.trim_start().to_ascii_lowercase().starts_with("data:") => None,
+        "src"
+            if element != "img" && value.trim_start().to_ascii_lowercase().starts_with("data:") =>
+        {
+            None
+        }
+        _ => Some(value.into()),
+    }
+}
+
+/// Drop dangerous declarations, and remote URLs unless allowed, from an inline style
+fn sanitize_style(style: &str, allow_remote: bool, blocked: &AtomicUsize) -> String {
+    style
+        .split(';')
+        .filter(|declaration| {
+            let lower = declaration.to_ascii_lowercase();
+            let Some((property, value)) = lower.split_once(':') else {
+                return false;
+            };
+
+            if DANGEROUS_CSS.iter().any(|pattern| lower.contains(pattern)) {
+                return false;
+            }
+            // Fixed elements can cover the app, e.g. with a fake login form
+            if property.trim() == "position" && value.contains("fixed") {
+                return false;
+            }
+            if !allow_remote && css_urls(value).any(is_remote) {
+                blocked.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// The URLs referenced with `url(...)` in a CSS value
+fn css_urls(value: &str) -> impl Iterator<Item = &str> {
+    value.split("url(").skip(1).map(|rest| {
+        rest.split(')')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'')
+    })
+}
+
+/// Whether loading a URL would reach a remote server
+fn is_remote(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    // On Windows the inline image protocol is served from http://mail-part.localhost
+    let local_part_url = format!("http://{}.localhost/", inline_images::PROTOCOL);
+
+    (url.starts_with("http:") || url.starts_with("https:") || url.starts_with("//"))
+        && !url.starts_with(&local_part_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(html: &str, allow_remote: bool) -> SanitizedHtml {
+        sanitize_html(html, allow_remote, HashMap::new())
+    }
+
+    #[test]
+    fn removes_scripts_and_event_handlers() {
+        let html = r#"<p onclick="steal()" onMouseOver="steal()">Hello</p>
+<script>steal()</script>
+<img src="cid:logo" onerror="steal()">
+<a href="javascript:steal()">Link</a>"#;
+
+        let sanitized = clean(html, false).html;
+
+        assert!(sanitized.contains("Hello"));
+        assert!(sanitized.contains("Link"));
+        assert!(!sanitized.contains("steal"), "{}", sanitized);
+        assert!(!sanitized.to_ascii_lowercase().contains("script"));
+    }
+
+    #[test]
+    fn keeps_cid_and_data_images() {
+        let html =
+            r#"<img src="cid:logo@example.com"><img src="data:image/png;base64,iVBORw0KGgo=">"#;
+
+        let sanitized = clean(html, false);
+
+        assert!(sanitized.html.contains(r#"src="cid:logo@example.com""#));
+        assert!(sanitized
+            .html
+            .contains(r#"src="data:image/png;base64,iVBORw0KGgo=""#));
+        assert_eq!(sanitized.blocked_remote_content, 0);
+    }
+
+    #[test]
+    fn removes_data_links() {
+        let html = r#"<a href="data:text/html;base64,PHNjcmlwdD4=">Open</a>"#;
+
+        let sanitized = clean(html, false).html;
+
+        assert!(sanitized.contains("Open"));
+        assert!(!sanitized.contains("data:"));
+    }
+
+    #[test]
+    fn blocks_remote_content_unless_allowed() {
+        let html = r#"<img src="https://tracker.example/p.gif">
+<p style="color: red; background: url('https://tracker.example/bg.png')">Text</p>"#;
+
+        let blocked = clean(html, false);
+        assert!(!blocked.html.contains("tracker.example"));
+        assert!(blocked.html.contains("color: red"));
+        assert_eq!(blocked.blocked_remote_content, 2);
+
+        let allowed = clean(html, true);
+        assert!(allowed.html.contains("https://tracker.example/p.gif"));
+        assert!(allowed.html.contains("https://tracker.example/bg.png"));
+        assert_eq!(allowed.blocked_remote_content, 0);
+    }
+
+    #[test]
+    fn removes_dangerous_css() {
+        let html = r#"<div style="color: blue; width: expression(alert(1)); position: fixed; top: 0">Text</div>"#;
+
+        let sanitized = clean(html, true).html;
+
+        assert!(sanitized.contains("color: blue"));
+        assert!(sanitized.contains("top: 0"));
+        assert!(!sanitized.contains("expression"));
+        assert!(!sanitized.contains("fixed"));
+    }
+
+    #[test]
+    fn rewrites_links() {
+        let html = r#"<a href="https://click.example/r?u=1">Offer</a>"#;
+        let rewrites = HashMap::from([(
+            "https://click.example/r?u=1".to_string(),
+            "https://shop.example/offer".to_string(),
+        )]);
+
+        let sanitized = sanitize_html(html, false, rewrites).html;
+
+        assert!(sanitized.contains(r#"href="https://shop.example/offer""#));
+        assert!(!sanitized.contains("click.example"));
+    }
+
+    #[test]
+    fn treats_only_network_urls_as_remote() {
+        assert!(is_remote("https://example.com/a.png"));
+        assert!(is_remote(" HTTP://example.com/a.png"));
+        assert!(is_remote("//example.com/a.png"));
+        assert!(!is_remote("cid:logo@example.com"));
+        assert!(!is_remote("data:image/png;base64,iVBORw0KGgo="));
+        assert!(!is_remote("http://mail-part.localhost/INBOX/1/2"));
+    }
+}
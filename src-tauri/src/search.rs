@@ -0,0 +1,352 @@
+// This module compiles structured search queries into IMAP SEARCH criteria.
+//
+// Gmail's IMAP SEARCH is limited, so on Gmail the text criteria are sent as a
+// single X-GM-RAW query using the web search syntax instead.
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::email::{self, Envelope, Session};
+use crate::error::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Text in the body of the message
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Messages sent on or after this date, formatted `YYYY-MM-DD`
+    #[serde(default)]
+    pub since: Option<String>,
+    /// Messages sent before this date, formatted `YYYY-MM-DD`
+    #[serde(default)]
+    pub before: Option<String>,
+    /// Flags the messages must have, e.g. `\Seen`
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Flags the messages must not have
+    #[serde(default)]
+    pub not_flags: Vec<String>,
+    /// Messages larger than this many bytes
+    #[serde(default)]
+    pub larger: Option<u32>,
+    /// Messages smaller than this many bytes
+    #[serde(default)]
+    pub smaller: Option<u32>,
+    #[serde(default)]
+    pub has_attachment: Option<bool>,
+    /// Only return the newest matches
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// Search a mailbox
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to search
+/// * `query` - The search query
+/// # Returns
+/// * `Result<Vec<Envelope>>` - The envelopes of the matching messages, newest first
+///
+pub fn search_messages(
+    session: &mut Session,
+    mailbox: &str,
+    query: &SearchQuery,
+) -> Result<Vec<Envelope>> {
+    let gmail = session.capabilities()?.has_str("X-GM-EXT-1");
+    let criteria = if gmail {
+        gmail_criteria(query)?
+    } else {
+        imap_criteria(query)?
+    };
+
+    let selected = session.select(mailbox)?;
+    if selected.exists == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut uids: Vec<u32> = session
+        .with_literals(|session| session.uid_search(criteria))?
+        .into_iter()
+        .collect();
+    uids.sort_unstable();
+    if let Some(limit) = query.limit {
+        uids = uids.split_off(uids.len().saturating_sub(limit as usize));
+    }
+    if uids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut envelopes = email::fetch_envelopes(session, mailbox, &email::uid_set(&uids))?;
+    envelopes.sort_by(|a, b| b.uid.cmp(&a.uid));
+
+    Ok(envelopes)
+}
+
+/// Compile a query into standard IMAP SEARCH criteria
+fn imap_criteria(query: &SearchQuery) -> Result<String> {
+    let mut criteria = vec![];
+
+    if let Some(from) = &query.from {
+        criteria.push(format!("FROM {}", email::imap_string(from)?));
+    }
+    if let Some(to) = &query.to {
+        criteria.push(format!("TO {}", email::imap_string(to)?));
+    }
+    if let Some(subject) = &query.subject {
+        criteria.push(format!("SUBJECT {}", email::imap_string(subject)?));
+    }
+    if let Some(body) = &query.body {
+        criteria.push(format!("BODY {}", email::imap_string(body)?));
+    }
+    // There is no attachment criterion, messages with attachments are multipart/mixed
+    match query.has_attachment {
+        Some(true) => criteria.push("HEADER Content-Type \"multipart/mixed\"".to_string()),
+        Some(false) => criteria.push("NOT HEADER Content-Type \"multipart/mixed\"".to_string()),
+        None => {}
+    }
+    criteria.extend(common_criteria(query)?);
+
+    Ok(finish(criteria))
+}
+
+/// Compile a query into Gmail criteria, with the text criteria in X-GM-RAW
+fn gmail_criteria(query: &SearchQuery) -> Result<String> {
+    let mut raw = vec![];
+
+    if let Some(from) = &query.from {
+        raw.push(format!("from:{}", gmail_phrase(from)?));
+    }
+    if let Some(to) = &query.to {
+        raw.push(format!("to:{}", gmail_phrase(to)?));
+    }
+    if let Some(subject) = &query.subject {
+        raw.push(format!("subject:{}", gmail_phrase(subject)?));
+    }
+    if let Some(body) = &query.body {
+        raw.push(gmail_phrase(body)?);
+    }
+    match query.has_attachment {
+        Some(true) => raw.push("has:attachment".to_string()),
+        Some(false) => raw.push("-has:attachment".to_string()),
+        None => {}
+    }
+
+    let mut criteria = vec![];
+    if !raw.is_empty() {
+        criteria.push(format!("X-GM-RAW {}", email::imap_string(&raw.join(" "))?));
+    }
+    criteria.extend(common_criteria(query)?);
+
+    Ok(finish(criteria))
+}
+
+/// Quote a value for the Gmail search syntax
+///
+/// Operators, parentheses and `OR` are literal text inside a quoted phrase.
+/// Gmail has no escape for quotes, so values containing one are rejected.
+///
+/// # Arguments
+/// * `value` - The text to search for
+/// # Returns
+/// * `Result<String>` - The quoted phrase
+///
+fn gmail_phrase(value: &str) -> Result<String> {
+    if value.contains('"') {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Quotes are not supported in Gmail searches: {}", value),
+        ));
+    }
+    Ok(format!("\"{}\"", value))
+}
+
+/// The criteria shared by both dialects: dates, flags and sizes
+fn common_criteria(query: &SearchQuery) -> Result<Vec<String>> {
+    let mut criteria = vec![];
+
+    if let Some(since) = &query.since {
+        criteria.push(format!("SENTSINCE {}", imap_date(since)?));
+    }
+    if let Some(before) = &query.before {
+        criteria.push(format!("SENTBEFORE {}", imap_date(before)?));
+    }
+    for flag in &query.flags {
+        criteria.push(flag_criterion(flag)?);
+    }
+    for flag in &query.not_flags {
+        criteria.push(format!("NOT {}", flag_criterion(flag)?));
+    }
+    if let Some(larger) = query.larger {
+        criteria.push(format!("LARGER {}", larger));
+    }
+    if let Some(smaller) = query.smaller {
+        criteria.push(format!("SMALLER {}", smaller));
+    }
+
+    Ok(criteria)
+}
+
+fn finish(criteria: Vec<String>) -> String {
+    if criteria.is_empty() {
+        return "ALL".to_string();
+    }
+
+    let criteria = criteria.join(" ");
+    // Non-ASCII values are sent as UTF-8 literals, which needs the charset
+    if criteria.is_ascii() {
+        criteria
+    } else {
+        format!("CHARSET UTF-8 {}", criteria)
+    }
+}
+
+/// Map a flag to its search key, e.g. `\Seen` to `SEEN` and `$Label` to `KEYWORD $Label`
+fn flag_criterion(flag: &str) -> Result<String> {
+    let criterion = match flag {
+        "\\Seen" => "SEEN".to_string(),
+        "\\Answered" => "ANSWERED".to_string(),
+        "\\Flagged" => "FLAGGED".to_string(),
+        "\\Deleted" => "DELETED".to_string(),
+        "\\Draft" => "DRAFT".to_string(),
+        _ if !flag.is_empty()
+            && !flag.starts_with('\\')
+            && flag
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"(){%*\"]".contains(c)) =>
+        {
+            format!("KEYWORD {}", flag)
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid flag: {}", flag),
+            ))
+        }
+    };
+
+    Ok(criterion)
+}
+
+/// Convert a `YYYY-MM-DD` date to the IMAP format, e.g. `7-Mar-2025`
+fn imap_date(date: &str) -> Result<String> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid date: {}", date)))?;
+    Ok(date.format("%-d-%b-%Y").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid_input<T>(result: &Result<T>) -> bool {
+        matches!(result, Err(e) if matches!(e.kind(), ErrorKind::InvalidInput))
+    }
+
+    #[test]
+    fn compiles_imap_criteria() {
+        let query = SearchQuery {
+            from: Some("alice@example.com".to_string()),
+            subject: Some("a \"b\"".to_string()),
+            since: Some("2025-03-07".to_string()),
+            flags: vec!["\\Seen".to_string()],
+            not_flags: vec!["$Junk".to_string()],
+            larger: Some(1024),
+            has_attachment: Some(true),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            imap_criteria(&query).unwrap(),
+            "FROM \"alice@example.com\" SUBJECT \"a \\\"b\\\"\" \
+             HEADER Content-Type \"multipart/mixed\" SENTSINCE 7-Mar-2025 SEEN \
+             NOT KEYWORD $Junk LARGER 1024"
+        );
+    }
+
+    #[test]
+    fn matches_all_without_criteria() {
+        assert_eq!(imap_criteria(&SearchQuery::default()).unwrap(), "ALL");
+        assert_eq!(gmail_criteria(&SearchQuery::default()).unwrap(), "ALL");
+    }
+
+    #[test]
+    fn sends_non_ascii_criteria_as_utf8() {
+        let query = SearchQuery {
+            subject: Some("Grüße".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            imap_criteria(&query).unwrap(),
+            "CHARSET UTF-8 SUBJECT {7}\r\nGrüße"
+        );
+    }
+
+    #[test]
+    fn compiles_gmail_criteria() {
+        let query = SearchQuery {
+            from: Some("alice".to_string()),
+            subject: Some("invoice) OR (label:secret".to_string()),
+            body: Some("-has:attachment".to_string()),
+            has_attachment: Some(true),
+            before: Some("2025-12-31".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            gmail_criteria(&query).unwrap(),
+            "X-GM-RAW \"from:\\\"alice\\\" subject:\\\"invoice) OR (label:secret\\\" \
+             \\\"-has:attachment\\\" has:attachment\" SENTBEFORE 31-Dec-2025"
+        );
+    }
+
+    #[test]
+    fn rejects_quotes_in_gmail_values() {
+        let query = SearchQuery {
+            to: Some("bob\" OR from:(mallory".to_string()),
+            ..Default::default()
+        };
+
+        assert!(is_invalid_input(&gmail_criteria(&query)));
+    }
+
+    #[test]
+    fn maps_flags_to_search_keys() {
+        assert_eq!(flag_criterion("\\Seen").unwrap(), "SEEN");
+        assert_eq!(flag_criterion("\\Answered").unwrap(), "ANSWERED");
+        assert_eq!(flag_criterion("\\Flagged").unwrap(), "FLAGGED");
+        assert_eq!(flag_criterion("\\Deleted").unwrap(), "DELETED");
+        assert_eq!(flag_criterion("\\Draft").unwrap(), "DRAFT");
+        assert_eq!(flag_criterion("$Label").unwrap(), "KEYWORD $Label");
+    }
+
+    #[test]
+    fn rejects_invalid_flags() {
+        for flag in ["", "\\Recent", "a b", "a)", "a\"", "a*", "Grüße"] {
+            assert!(
+                is_invalid_input(&flag_criterion(flag)),
+                "accepted {:?}",
+                flag
+            );
+        }
+    }
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(imap_date("2025-03-07").unwrap(), "7-Mar-2025");
+        assert_eq!(imap_date("2024-11-25").unwrap(), "25-Nov-2024");
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in ["", "07-03-2025", "2025-02-30", "2025-03-07 UID 1"] {
+            assert!(is_invalid_input(&imap_date(date)), "accepted {:?}", date);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+// This module keeps a local full-text index of cached mail, so every account
+// can be searched instantly and offline.
+//
+// Envelopes are indexed when a mailbox is synced, the body and attachment
+// names are added once the message itself has been fetched.
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use chrono::{DateTime as ChronoDateTime, NaiveDate};
+use serde::Serialize;
+use tantivy::{
+    collector::{Count, TopDocs},
+    query::{QueryParser, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT},
+    DateTime, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+
+use crate::email::{EmailAddress, Envelope, Message};
+use crate::error::Result;
+
+const INDEX_WRITER_MEMORY: usize = 50_000_000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub email: String,
+    pub mailbox: String,
+    pub uid: u32,
+    pub score: f32,
+}
+
+/// A hit together with its cached envelope
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalSearchResult {
+    pub email: String,
+    pub mailbox: String,
+    pub score: f32,
+    pub envelope: Envelope,
+}
+
+struct Fields {
+    /// Unique key of a message, see `message_id`
+    id: Field,
+    /// Key of the mailbox of a message, see `mailbox_id`
+    mailbox_id: Field,
+    account: Field,
+    mailbox: Field,
+    uid: Field,
+    subject: Field,
+    from: Field,
+    to: Field,
+    body: Field,
+    attachment: Field,
+    date: Field,
+}
+
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: IndexWriter,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        create_dir_all(&path)?;
+
+        let (schema, fields) = schema();
+        let index = if path.join("meta.json").exists() {
+            Index::open_in_dir(&path)?
+        } else {
+            Index::create_in_dir(&path, schema)?
+        };
+
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let writer = index.writer(INDEX_WRITER_MEMORY)?;
+
+        Ok(SearchIndex {
+            index,
+            reader,
+            writer,
+            fields,
+        })
+    }
+
+    /// Search all accounts
+    ///
+    /// Terms can be prefixed with a field, e.g. `from:alice subject:report`, and
+    /// `before:YYYY-MM-DD` and `after:YYYY-MM-DD` restrict the date.
+    ///
+    /// # Arguments
+    /// * `query` - The query
+    /// * `limit` - The maximum number of hits
+    /// # Returns
+    /// * `Result<Vec<SearchHit>>` - The hits, best match first
+    ///
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let f = &self.fields;
+
+        let mut parser = QueryParser::for_index(
+            &self.index,
+            vec![f.subject, f.from, f.to, f.body, f.attachment],
+        );
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(f.subject, 2.0);
+        parser.set_field_boost(f.from, 1.5);
+
+        // Search as much of the query as possible rather than failing on a typo
+        let (query, errors) = parser.parse_query_lenient(&rewrite_dates(query));
+        if !errors.is_empty() {
+            println!("Ignored parts of search query: {:?}", errors);
+        }
+
+        let searcher = self.reader.searcher();
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
+
+        let mut hits = vec![];
+        for (score, address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let email = doc.get_first(f.account).and_then(|v| v.as_str());
+            let mailbox = doc.get_first(f.mailbox).and_then(|v| v.as_str());
+            let uid = doc.get_first(f.uid).and_then(|v| v.as_u64());
+
+            if let (Some(email), Some(mailbox), Some(uid)) = (email, mailbox, uid) {
+                hits.push(SearchHit {
+                    email: email.to_string(),
+                    mailbox: mailbox.to_string(),
+                    uid: uid as u32,
+                    score,
+                });
+            }
+        }
+
+        Ok(hits)
+    }
+
+    /// Index envelopes, keeping messages whose body is already indexed
+    pub fn put_envelopes(
+        &mut self,
+        account: &str,
+        mailbox: &str,
+        envelopes: &[Envelope],
+    ) -> Result<()> {
+        let searcher = self.reader.searcher();
+
+        for envelope in envelopes {
+            let Some(uid) = envelope.uid else {
+                continue;
+            };
+
+            let indexed = TermQuery::new(
+                Term::from_field_text(self.fields.id, &message_id(account, mailbox, uid)),
+                IndexRecordOption::Basic,
+            );
+            if searcher.search(&indexed, &Count)? > 0 {
+                continue;
+            }
+
+            let doc = self.document(
+                account,
+                mailbox,
+                uid,
+                envelope.subject.as_deref(),
+                [&envelope.from],
+                [&envelope.to, &envelope.cc, &envelope.bcc],
+                envelope.date.as_deref(),
+            );
+            self.writer.add_document(doc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Index a message with its body and attachment names
+    pub fn put_message(&mut self, account: &str, mailbox: &str, message: &Message) -> Result<()> {
+        let Some(uid) = message.uid else {
+            return Ok(());
+        };
+
+        let mut doc = self.document(
+            account,
+            mailbox,
+            uid,
+            message.subject.as_deref(),
+            [&message.from],
+            [&message.to, &message.cc, &message.bcc],
+            message.date.as_deref(),
+        );
+        doc.add_text(self.fields.body, &message.text);
+        for name in message
+            .attachments
+            .iter()
+            .filter_map(|a| a.filename.as_ref())
+        {
+            doc.add_text(self.fields.attachment, name);
+        }
+
+        self.writer.delete_term(Term::from_field_text(
+            self.fields.id,
+            &message_id(account, mailbox, uid),
+        ));
+        self.writer.add_document(doc)?;
+
+        Ok(())
+    }
+
+    /// Remove expunged or moved messages
+    pub fn remove_messages(&mut self, account: &str, mailbox: &str, uids: &[u32]) {
+        for &uid in uids {
+            self.writer.delete_term(Term::from_field_text(
+                self.fields.id,
+                &message_id(account, mailbox, uid),
+            ));
+        }
+    }
+
+    /// Remove all messages of a mailbox, e.g. when its UIDVALIDITY changed
+    pub fn remove_mailbox(&mut self, account: &str, mailbox: &str) {
+        self.writer.delete_term(Term::from_field_text(
+            self.fields.mailbox_id,
+            &mailbox_id(account, mailbox),
+        ));
+    }
+
+    pub fn remove_account(&mut self, account: &str) {
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.account, account));
+    }
+
+    /// Persist the pending changes and make them visible to searches
+    pub fn commit(&mut self) -> Result<()> {
+        self.writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn document<'a>(
+        &self,
+        account: &str,
+        mailbox: &str,
+        uid: u32,
+        subject: Option<&str>,
+        from: impl IntoIterator<Item = &'a Vec<EmailAddress>>,
+        to: impl IntoIterator<Item = &'a Vec<EmailAddress>>,
+        date: Option<&str>,
+    ) -> TantivyDocument {
+        let f = &self.fields;
+        let mut doc = TantivyDocument::default();
+
+        doc.add_text(f.id, message_id(account, mailbox, uid));
+        doc.add_text(f.mailbox_id, mailbox_id(account, mailbox));
+        doc.add_text(f.account, account);
+        doc.add_text(f.mailbox, mailbox);
+        doc.add_u64(f.uid, uid as u64);
+
+        if let Some(subject) = subject {
+            doc.add_text(f.subject, subject);
+        }
+        for address in from.into_iter().flatten() {
+            doc.add_text(f.from, format_address(address));
+        }
+        for address in to.into_iter().flatten() {
+            doc.add_text(f.to, format_address(address));
+        }
+        if let Some(date) = date.and_then(parse_date) {
+            doc.add_date(f.date, date);
+        }
+
+        doc
+    }
+}
+
+fn schema() -> (Schema, Fields) {
+    let mut builder = Schema::builder();
+
+    let fields = Fields {
+        id: builder.add_text_field("id", STRING),
+        mailbox_id: builder.add_text_field("mailbox_id", STRING),
+        account: builder.add_text_field("account", STRING | STORED),
+        mailbox: builder.add_text_field("mailbox", STRING | STORED),
+        uid: builder.add_u64_field("uid", STORED),
+        subject: builder.add_text_field("subject", TEXT),
+        from: builder.add_text_field("from", TEXT),
+        to: builder.add_text_field("to", TEXT),
+        body: builder.add_text_field("body", TEXT),
+        attachment: builder.add_text_field("attachment", TEXT),
+        date: builder.add_date_field("date", INDEXED | FAST),
+    };
+
+    (builder.build(), fields)
+}
+
+fn message_id(account: &str, mailbox: &str, uid: u32) -> String {
+    format!("{}\0{}\0{}", account, mailbox, uid)
+}
+
+fn mailbox_id(account: &str, mailbox: &str) -> String {
+    format!("{}\0{}", account, mailbox)
+}
+
+fn format_address(address: &EmailAddress) -> String {
+    match &address.name {
+        Some(name) => format!("{} {}", name, address.address),
+        None => address.address.clone(),
+    }
+}
+
+fn parse_date(date: &str) -> Option<DateTime> {
+    let date = ChronoDateTime::parse_from_rfc3339(date)
+        .or_else(|_| ChronoDateTime::parse_from_rfc2822(date))
+        .ok()?;
+    Some(DateTime::from_timestamp_secs(date.timestamp()))
+}
+
+/// Rewrite `before:YYYY-MM-DD` and `after:YYYY-MM-DD` into date range queries
+fn rewrite_dates(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| {
+            let range = |prefix: &str| {
+                let date = term.strip_prefix(prefix)?;
+                NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+                Some(format!("{}T00:00:00Z", date))
+            };
+
+            if let Some(date) = range("before:") {
+                format!("date:[* TO {}}}", date)
+            } else if let Some(date) = range("after:") {
+                format!("date:[{} TO *]", date)
+            } else {
+                term.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
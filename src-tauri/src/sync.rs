@@ -0,0 +1,311 @@
+// This module keeps the local cache in sync with the IMAP server.
+//
+// Synchronization is incremental: the UIDVALIDITY, UIDNEXT and HIGHESTMODSEQ
+// of the last sync are kept in the cache. With QRESYNC the server reports
+// expunged and changed messages when selecting the mailbox, with CONDSTORE
+// changed flags are fetched with CHANGEDSINCE. Without either, the UIDs and
+// flags on the server are compared against the cache.
+use std::collections::HashSet;
+
+use serde::Serialize;
+use tauri::{async_runtime::Mutex, AppHandle, Emitter, Manager};
+
+use crate::{
+    cache::{Cache, MailboxState},
+    condstore::{self, FlagUpdate},
+    email::{self, Envelope, PageQuery, Session},
+    error::Result,
+    get_account_config, get_account_state,
+    search_index::SearchIndex,
+};
+
+pub const ENVELOPES_SYNCED_EVENT: &str = "envelopes-synced";
+
+/// The number of newest messages fetched by the first sync of a mailbox,
+/// older messages are fetched page by page with `load_older`
+pub const INITIAL_SYNC_COUNT: u32 = 200;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EnvelopesSynced {
+    pub email: String,
+    pub mailbox: String,
+    pub envelopes: Vec<Envelope>,
+}
+
+/// The changes on the server since the last sync
+struct MailboxChanges {
+    state: MailboxState,
+    /// The cached mailbox is invalid, `envelopes` holds the newest messages
+    full: bool,
+    envelopes: Vec<Envelope>,
+    vanished: Vec<u32>,
+    flags: Vec<FlagUpdate>,
+}
+
+/// Sync the envelopes of a mailbox with the server and store them in the cache
+///
+/// # Arguments
+/// * `handle` - The app handle, used to access the app state and the cache
+/// * `email` - The account
+/// * `mailbox` - The mailbox to sync
+/// # Returns
+/// * `Result<Vec<Envelope>>` - The envelopes of the mailbox, newest first
+///
+pub async fn sync_envelopes(
+    handle: &AppHandle,
+    email: &str,
+    mailbox: &str,
+) -> Result<Vec<Envelope>> {
+    let account_config = get_account_config(handle, email).await?;
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+
+    let (known, known_uids) = {
+        let cache = cache_mutex.lock().await;
+        (
+            cache.get_mailbox_state(email, mailbox)?,
+            cache.get_uids(email, mailbox)?,
+        )
+    };
+
+    let changes = {
+        let account = get_account_state(handle, &account_config).await?;
+        let mailbox = mailbox.to_string();
+
+        account
+            .with_imap_session_retried(move |imap_session| {
+                get_changes(imap_session, &mailbox, known.clone(), &known_uids)
+            })
+            .await?
+    };
+
+    let mut cache = cache_mutex.lock().await;
+
+    if changes.full {
+        // Clears the cached mailbox if the UIDVALIDITY changed
+        cache.replace_mailbox(email, mailbox, &changes.state, &changes.envelopes)?;
+    } else {
+        cache.remove_messages(email, mailbox, &changes.vanished)?;
+        for update in &changes.flags {
+            cache.set_flags(email, mailbox, update.uid, &update.flags)?;
+        }
+        cache.put_envelopes(email, mailbox, &changes.envelopes)?;
+
+        // Only advance the state once the changes are stored
+        cache.set_mailbox_state(email, mailbox, &changes.state)?;
+    }
+
+    let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+    let mut search_index = search_index_mutex.lock().await;
+    if changes.full {
+        search_index.remove_mailbox(email, mailbox);
+        // Commit the removal, otherwise the stale documents are kept below
+        search_index.commit()?;
+    } else {
+        search_index.remove_messages(email, mailbox, &changes.vanished);
+    }
+    search_index.put_envelopes(email, mailbox, &changes.envelopes)?;
+    search_index.commit()?;
+
+    cache.get_envelopes(email, mailbox)
+}
+
+/// Get the envelopes older than a message, fetching them if they are not cached yet
+///
+/// The cache holds the newest messages of a mailbox without gaps, so the
+/// missing envelopes are the ones older than the oldest cached message.
+///
+/// # Arguments
+/// * `handle` - The app handle, used to access the app state and the cache
+/// * `email` - The account
+/// * `mailbox` - The mailbox
+/// * `before_uid` - Only get envelopes with a lower UID
+/// * `count` - The maximum number of envelopes
+/// # Returns
+/// * `Result<Vec<Envelope>>` - The envelopes, newest first
+///
+pub async fn load_older(
+    handle: &AppHandle,
+    email: &str,
+    mailbox: &str,
+    before_uid: u32,
+    count: u32,
+) -> Result<Vec<Envelope>> {
+    let cache_mutex = handle.state::<Mutex<Cache>>();
+
+    let (known, cached, oldest_cached) = {
+        let cache = cache_mutex.lock().await;
+        (
+            cache.get_mailbox_state(email, mailbox)?,
+            cache.get_envelope_page(email, mailbox, Some(before_uid), count)?,
+            cache.get_uids(email, mailbox)?.first().copied(),
+        )
+    };
+    if cached.len() >= count as usize {
+        return Ok(cached);
+    }
+
+    let account_config = get_account_config(handle, email).await?;
+    let page = {
+        let account = get_account_state(handle, &account_config).await?;
+        let mailbox = mailbox.to_string();
+        let query = PageQuery::Newest {
+            count: count - cached.len() as u32,
+            before_uid: Some(oldest_cached.map_or(before_uid, |oldest| oldest.min(before_uid))),
+        };
+
+        account
+            .with_imap_session_retried(move |imap_session| {
+                email::get_envelope_page(imap_session, &mailbox, &query)
+            })
+            .await?
+    };
+
+    // The UIDs are only comparable while the UIDVALIDITY is unchanged, the next sync refreshes the cache
+    let known_uid_validity = known.and_then(|k| k.uid_validity);
+    if known_uid_validity.is_none() || known_uid_validity != page.uid_validity {
+        return Ok(cached);
+    }
+
+    let mut cache = cache_mutex.lock().await;
+    cache.put_envelopes(email, mailbox, &page.envelopes)?;
+
+    let search_index_mutex = handle.state::<Mutex<SearchIndex>>();
+    let mut search_index = search_index_mutex.lock().await;
+    search_index.put_envelopes(email, mailbox, &page.envelopes)?;
+    search_index.commit()?;
+
+    cache.get_envelope_page(email, mailbox, Some(before_uid), count)
+}
+
+/// Sync a mailbox without blocking the caller, the result is emitted as an event
+pub fn sync_envelopes_in_background(handle: AppHandle, email: String, mailbox: String) {
+    tauri::async_runtime::spawn(async move {
+        match sync_envelopes(&handle, &email, &mailbox).await {
+            Ok(envelopes) => {
+                let payload = EnvelopesSynced {
+                    email,
+                    mailbox,
+                    envelopes,
+                };
+                if let Err(e) = handle.emit(ENVELOPES_SYNCED_EVENT, payload) {
+                    println!("Failed to emit {}: {}", ENVELOPES_SYNCED_EVENT, e);
+                }
+            }
+            Err(e) => println!("Background sync of {} {} failed: {}", email, mailbox, e),
+        }
+    });
+}
+
+/// Get the changes of a mailbox since the known state
+fn get_changes(
+    session: &mut Session,
+    mailbox: &str,
+    known: Option<MailboxState>,
+    known_uids: &[u32],
+) -> Result<MailboxChanges> {
+    let support = condstore::get_support(session)?;
+
+    let qresync_state = known
+        .as_ref()
+        .and_then(|k| Some((k.uid_validity?, k.highest_modseq?)))
+        .filter(|_| support.qresync);
+    if qresync_state.is_some() {
+        condstore::enable_qresync(session)?;
+    }
+
+    let selected = condstore::select(session, mailbox, support, qresync_state)?;
+
+    let state = MailboxState {
+        uid_validity: selected.uid_validity,
+        uid_next: selected.uid_next,
+        highest_modseq: selected.highest_modseq,
+    };
+
+    // The cache can only be updated if the UIDs are still valid
+    let known = known.filter(|k| k.uid_validity.is_some() && k.uid_validity == state.uid_validity);
+    let Some(known_uid_next) = known.as_ref().and_then(|k| k.uid_next) else {
+        println!("Full sync of {}", mailbox);
+        let envelopes =
+            email::fetch_newest_envelopes(session, mailbox, selected.exists, INITIAL_SYNC_COUNT)?;
+
+        return Ok(MailboxChanges {
+            state,
+            full: true,
+            envelopes,
+            vanished: vec![],
+            flags: vec![],
+        });
+    };
+    let known_modseq = known.and_then(|k| k.highest_modseq);
+
+    // Only the newest messages are cached, older ones need not be checked
+    let Some(&oldest_known) = known_uids.first() else {
+        return Ok(MailboxChanges {
+            envelopes: new_envelopes(session, mailbox, state.uid_next, known_uid_next)?,
+            state,
+            full: false,
+            vanished: vec![],
+            flags: vec![],
+        });
+    };
+
+    let (vanished, flags) = match (known_modseq, state.highest_modseq) {
+        (Some(old), Some(new)) if old == new => (vec![], vec![]),
+        (Some(_), Some(_)) if qresync_state.is_some() => {
+            (selected.vanished.intersect(known_uids), selected.changed)
+        }
+        (Some(old), Some(_)) => {
+            let flags = condstore::fetch_changed_flags(session, old)?;
+
+            // CONDSTORE alone does not report expunged messages
+            let on_server: HashSet<u32> = session.uid_search(format!("UID {}:*", oldest_known))?;
+            let vanished = known_uids
+                .iter()
+                .filter(|uid| !on_server.contains(uid))
+                .copied()
+                .collect();
+
+            (vanished, flags)
+        }
+        _ => {
+            let flags = condstore::fetch_flags_from(session, oldest_known)?;
+
+            let on_server: HashSet<u32> = flags.iter().map(|f| f.uid).collect();
+            let vanished = known_uids
+                .iter()
+                .filter(|uid| !on_server.contains(uid))
+                .copied()
+                .collect();
+
+            (vanished, flags)
+        }
+    };
+
+    Ok(MailboxChanges {
+        envelopes: new_envelopes(session, mailbox, state.uid_next, known_uid_next)?,
+        state,
+        full: false,
+        vanished,
+        flags,
+    })
+}
+
+/// Fetch the messages added since the last sync
+fn new_envelopes(
+    session: &mut Session,
+    mailbox: &str,
+    uid_next: Option<u32>,
+    known_uid_next: u32,
+) -> Result<Vec<Envelope>> {
+    if uid_next.is_some_and(|uid_next| uid_next <= known_uid_next) {
+        return Ok(vec![]);
+    }
+
+    Ok(
+        email::fetch_envelopes(session, mailbox, &format!("{}:*", known_uid_next))?
+            .into_iter()
+            // n:* always matches the last message, even if its UID is below n
+            .filter(|e| e.uid.is_some_and(|uid| uid >= known_uid_next))
+            .collect(),
+    )
+}
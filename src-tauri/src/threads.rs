@@ -0,0 +1,745 @@
+// This module groups envelopes into conversation threads.
+//
+// Threads come from the server when possible: Gmail assigns every message an
+// X-GM-THRID and servers advertising THREAD=REFERENCES build the trees
+// themselves. Otherwise, and for messages the server did not cover, the trees
+// are built locally with the JWZ algorithm from Message-ID, In-Reply-To and
+// References. Grouping by subject (step 5 of JWZ) is left out, as it merges
+// unrelated conversations far too often.
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::condstore;
+use crate::email::{Envelope, Session};
+use crate::error::Result;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadNode {
+    /// `None` for a message that is referenced but not in the mailbox
+    pub envelope: Option<Envelope>,
+    pub children: Vec<ThreadNode>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Thread {
+    pub id: String,
+    pub subject: Option<String>,
+    pub messages: u32,
+    pub unread: u32,
+    pub flagged: bool,
+    /// The flags of all messages in the thread
+    pub flags: Vec<String>,
+    pub latest_date: Option<String>,
+    pub roots: Vec<ThreadNode>,
+}
+
+/// The threading information provided by the server
+pub enum ServerThreading {
+    /// The Gmail thread ID of each UID
+    Gmail(HashMap<u32, u64>),
+    /// The trees returned by `THREAD REFERENCES`
+    References(Vec<Vec<UidTree>>),
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UidTree {
+    /// `None` for a missing parent of several messages
+    pub uid: Option<u32>,
+    pub children: Vec<UidTree>,
+}
+
+/// Get the threading information from the server
+///
+/// # Arguments
+/// * `session` - The IMAP session
+/// * `mailbox` - The mailbox to select
+/// # Returns
+/// * `Result<ServerThreading>` - The threading information, `None` if unsupported
+///
+pub fn get_server_threading(session: &mut Session, mailbox: &str) -> Result<ServerThreading> {
+    let capabilities = session.capabilities()?;
+    let gmail = capabilities.has_str("X-GM-EXT-1");
+    let references = capabilities.has_str("THREAD=REFERENCES");
+
+    if !gmail && !references {
+        return Ok(ServerThreading::None);
+    }
+
+    let selected = session.select(mailbox)?;
+    if selected.exists == 0 {
+        return Ok(ServerThreading::None);
+    }
+
+    if gmail {
+        let response = session.run_command_and_read_response("UID FETCH 1:* (X-GM-THRID)")?;
+        let thread_ids = condstore::lines(&response)
+            .filter_map(parse_thread_id)
+            .collect();
+        return Ok(ServerThreading::Gmail(thread_ids));
+    }
+
+    let response = session.run_command_and_read_response("UID THREAD REFERENCES UTF-8 ALL")?;
+    let threads = condstore::lines(&response)
+        .filter_map(|line| line.strip_prefix("* THREAD"))
+        .flat_map(parse_thread_response)
+        .collect();
+    Ok(ServerThreading::References(threads))
+}
+
+/// Group envelopes into threads
+///
+/// # Arguments
+/// * `envelopes` - The envelopes of a mailbox
+/// * `server` - The threading information from the server
+/// # Returns
+/// * `Vec<Thread>` - The threads, most recently active first
+///
+pub fn build_threads(envelopes: Vec<Envelope>, server: ServerThreading) -> Vec<Thread> {
+    let mut threads = vec![];
+
+    let remaining = match server {
+        ServerThreading::Gmail(thread_ids) => {
+            let mut groups: HashMap<u64, Vec<Envelope>> = HashMap::new();
+            let mut remaining = vec![];
+            for envelope in envelopes {
+                match envelope.uid.and_then(|uid| thread_ids.get(&uid)) {
+                    Some(thread_id) => groups.entry(*thread_id).or_default().push(envelope),
+                    None => remaining.push(envelope),
+                }
+            }
+
+            for (thread_id, group) in groups {
+                // Gmail only groups the messages, the tree inside comes from the headers
+                let roots = jwz(group).into_iter().flatten().collect();
+                threads.push(thread(thread_id.to_string(), roots));
+            }
+            remaining
+        }
+        ServerThreading::References(trees) => {
+            let mut by_uid: HashMap<u32, Envelope> = envelopes
+                .into_iter()
+                .filter_map(|e| Some((e.uid?, e)))
+                .collect();
+
+            for roots in trees {
+                let roots: Vec<ThreadNode> = roots
+                    .into_iter()
+                    .flat_map(|tree| from_uid_tree(tree, &mut by_uid))
+                    .collect();
+                if let Some(id) = first_envelope(&roots).map(thread_id) {
+                    threads.push(thread(id, roots));
+                }
+            }
+
+            // Messages that arrived after the server built the threads
+            by_uid.into_values().collect()
+        }
+        ServerThreading::None => envelopes,
+    };
+
+    for roots in jwz(remaining) {
+        if let Some(id) = first_envelope(&roots).map(thread_id) {
+            threads.push(thread(id, roots));
+        }
+    }
+
+    threads.sort_by_cached_key(|t| std::cmp::Reverse(latest_key(&t.roots)));
+    threads
+}
+
+/// Build threads from message headers with the JWZ algorithm
+///
+/// See <https://www.jwz.org/doc/threading.html>
+fn jwz(envelopes: Vec<Envelope>) -> Vec<Vec<ThreadNode>> {
+    let mut table = ContainerTable::default();
+
+    for envelope in envelopes {
+        let references = references(&envelope);
+
+        let index = match header(&envelope, "Message-ID").and_then(normalize_id) {
+            Some(id) if !table.has_envelope(&id) => table.get_or_create(&id),
+            // A missing or duplicate Message-ID gets a container of its own
+            _ => table.create(),
+        };
+        table.containers[index].envelope = Some(envelope);
+
+        // Link the references together, each one is the parent of the next
+        let mut parent = None;
+        for reference in references {
+            let child = table.get_or_create(&reference);
+            if let Some(parent) = parent {
+                if table.containers[child].parent.is_none() {
+                    table.link(parent, child);
+                }
+            }
+            parent = Some(child);
+        }
+
+        // The last reference is the parent of the message, whatever was known before
+        table.unlink(index);
+        if let Some(parent) = parent {
+            table.link(parent, index);
+        }
+    }
+
+    let roots: Vec<usize> = (0..table.containers.len())
+        .filter(|&i| table.containers[i].parent.is_none())
+        .collect();
+
+    roots
+        .into_iter()
+        .map(|root| table.take_nodes(root))
+        .filter(|nodes| !nodes.is_empty())
+        .collect()
+}
+
+#[derive(Default)]
+struct Container {
+    envelope: Option<Envelope>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+#[derive(Default)]
+struct ContainerTable {
+    containers: Vec<Container>,
+    ids: HashMap<String, usize>,
+}
+
+impl ContainerTable {
+    fn create(&mut self) -> usize {
+        self.containers.push(Container::default());
+        self.containers.len() - 1
+    }
+
+    fn get_or_create(&mut self, id: &str) -> usize {
+        if let Some(&index) = self.ids.get(id) {
+            return index;
+        }
+        let index = self.create();
+        self.ids.insert(id.to_string(), index);
+        index
+    }
+
+    fn has_envelope(&self, id: &str) -> bool {
+        match self.ids.get(id) {
+            Some(&index) => self.containers[index].envelope.is_some(),
+            None => false,
+        }
+    }
+
+    /// Whether `ancestor` is `index` or one of its parents
+    fn is_ancestor(&self, ancestor: usize, index: usize) -> bool {
+        let mut current = Some(index);
+        while let Some(i) = current {
+            if i == ancestor {
+                return true;
+            }
+            current = self.containers[i].parent;
+        }
+        false
+    }
+
+    /// Make `parent` the parent of `child`, unless that would create a loop
+    fn link(&mut self, parent: usize, child: usize) {
+        if self.is_ancestor(child, parent) {
+            return;
+        }
+        self.unlink(child);
+        self.containers[child].parent = Some(parent);
+        self.containers[parent].children.push(child);
+    }
+
+    fn unlink(&mut self, child: usize) {
+        if let Some(parent) = self.containers[child].parent.take() {
+            self.containers[parent].children.retain(|&c| c != child);
+        }
+    }
+
+    /// Convert a container into nodes, replacing empty containers by their children
+    fn take_nodes(&mut self, index: usize) -> Vec<ThreadNode> {
+        let mut children: Vec<ThreadNode> = self.containers[index]
+            .children
+            .clone()
+            .into_iter()
+            .flat_map(|child| self.take_nodes(child))
+            .collect();
+        sort_nodes(&mut children);
+
+        match self.containers[index].envelope.take() {
+            Some(envelope) => vec![ThreadNode {
+                envelope: Some(envelope),
+                children,
+            }],
+            None => children,
+        }
+    }
+}
+
+fn from_uid_tree(tree: UidTree, envelopes: &mut HashMap<u32, Envelope>) -> Vec<ThreadNode> {
+    let mut children: Vec<ThreadNode> = tree
+        .children
+        .into_iter()
+        .flat_map(|child| from_uid_tree(child, envelopes))
+        .collect();
+    sort_nodes(&mut children);
+
+    // Messages the cache does not know about are dropped like empty containers
+    match tree.uid.and_then(|uid| envelopes.remove(&uid)) {
+        Some(envelope) => vec![ThreadNode {
+            envelope: Some(envelope),
+            children,
+        }],
+        None => children,
+    }
+}
+
+fn thread(id: String, mut roots: Vec<ThreadNode>) -> Thread {
+    sort_nodes(&mut roots);
+
+    let mut envelopes = vec![];
+    collect_envelopes(&roots, &mut envelopes);
+
+    let flags: BTreeSet<&String> = envelopes.iter().flat_map(|e| &e.flags).collect();
+    let latest = envelopes.iter().max_by_key(|e| date_key(e));
+
+    Thread {
+        id,
+        subject: envelopes.first().and_then(|e| e.subject.clone()),
+        messages: envelopes.len() as u32,
+        unread: envelopes
+            .iter()
+            .filter(|e| !e.flags.iter().any(|f| f == "\\Seen"))
+            .count() as u32,
+        flagged: flags.iter().any(|f| *f == "\\Flagged"),
+        latest_date: latest.and_then(|e| e.date.clone()),
+        flags: flags.into_iter().cloned().collect(),
+        roots,
+    }
+}
+
+fn collect_envelopes<'a>(nodes: &'a [ThreadNode], envelopes: &mut Vec<&'a Envelope>) {
+    for node in nodes {
+        envelopes.extend(&node.envelope);
+        collect_envelopes(&node.children, envelopes);
+    }
+}
+
+fn first_envelope(nodes: &[ThreadNode]) -> Option<&Envelope> {
+    nodes.iter().find_map(|node| {
+        node.envelope
+            .as_ref()
+            .or_else(|| first_envelope(&node.children))
+    })
+}
+
+fn thread_id(envelope: &Envelope) -> String {
+    header(envelope, "Message-ID")
+        .and_then(normalize_id)
+        .unwrap_or_else(|| format!("uid:{}", envelope.uid.unwrap_or_default()))
+}
+
+fn sort_nodes(nodes: &mut [ThreadNode]) {
+    nodes.sort_by_cached_key(|node| node.envelope.as_ref().map(date_key));
+}
+
+fn latest_key(nodes: &[ThreadNode]) -> Option<(i64, u32)> {
+    nodes
+        .iter()
+        .flat_map(|node| {
+            let own = node.envelope.as_ref().map(date_key);
+            [own, latest_key(&node.children)]
+        })
+        .flatten()
+        .max()
+}
+
+/// Order messages by date, and by UID if the date is missing or equal
+fn date_key(envelope: &Envelope) -> (i64, u32) {
+    let timestamp = envelope
+        .date
+        .as_deref()
+        .and_then(|date| {
+            DateTime::parse_from_rfc3339(date)
+                .or_else(|_| DateTime::parse_from_rfc2822(date))
+                .ok()
+        })
+        .map(|date| date.timestamp())
+        .unwrap_or_default();
+
+    (timestamp, envelope.uid.unwrap_or_default())
+}
+
+fn header<'a>(envelope: &'a Envelope, name: &str) -> Option<&'a str> {
+    envelope
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn normalize_id(id: &str) -> Option<String> {
+    let id = id.trim().trim_start_matches('<').trim_end_matches('>');
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+/// The ancestors of a message, oldest first
+fn references(envelope: &Envelope) -> Vec<String> {
+    let mut references: Vec<String> = header(envelope, "References")
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(normalize_id)
+        .collect();
+
+    // In-Reply-To names the parent when References is missing or truncated
+    if let Some(in_reply_to) = header(envelope, "In-Reply-To")
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(normalize_id)
+    {
+        if references.last() != Some(&in_reply_to) {
+            references.push(in_reply_to);
+        }
+    }
+
+    // A message must not reference itself
+    if let Some(id) = header(envelope, "Message-ID").and_then(normalize_id) {
+        references.retain(|reference| *reference != id);
+    }
+
+    references
+}
+
+/// Parse an untagged `n FETCH (X-GM-THRID t UID u)` response
+fn parse_thread_id(line: &str) -> Option<(u32, u64)> {
+    let (_, attributes) = line.split_once(" FETCH (")?;
+    let value = |name: &str| {
+        let mut tokens = attributes.split(|c: char| c.is_whitespace() || c == ')');
+        tokens.find(|token| *token == name)?;
+        tokens.next()
+    };
+
+    Some((
+        value("UID")?.parse().ok()?,
+        value("X-GM-THRID")?.parse().ok()?,
+    ))
+}
+
+/// Parse the threads of a `THREAD` response, e.g. `(2)(3 6 (4 23)(44 7 96))`
+fn parse_thread_response(response: &str) -> Vec<Vec<UidTree>> {
+    let mut tokens = tokenize(response).into_iter();
+    let mut threads = vec![];
+
+    while let Some(token) = tokens.next() {
+        if token == Token::Open {
+            threads.push(parse_branch(&mut tokens));
+        }
+    }
+
+    threads
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Uid(u32),
+}
+
+fn tokenize(response: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = response.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    number.push(digit);
+                }
+                if let Ok(uid) = number.parse() {
+                    tokens.push(Token::Uid(uid));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    tokens
+}
+
+/// Parse a branch after its opening parenthesis: a chain of UIDs, each the
+/// parent of the next, followed by the branches below the last one
+fn parse_branch(tokens: &mut impl Iterator<Item = Token>) -> Vec<UidTree> {
+    let mut chain = vec![];
+    let mut branches = vec![];
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Uid(uid) => chain.push(uid),
+            Token::Open => branches.extend(parse_branch(tokens)),
+            Token::Close => break,
+        }
+    }
+
+    if chain.is_empty() {
+        // A missing message with several replies
+        return match branches.len() {
+            0 => vec![],
+            1 => branches,
+            _ => vec![UidTree {
+                uid: None,
+                children: branches,
+            }],
+        };
+    }
+
+    let mut tree = UidTree {
+        uid: chain.pop(),
+        children: branches,
+    };
+    while let Some(uid) = chain.pop() {
+        tree = UidTree {
+            uid: Some(uid),
+            children: vec![tree],
+        };
+    }
+
+    vec![tree]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(uid: u32, day: u32, id: &str, references: &str) -> Envelope {
+        let mut headers = HashMap::new();
+        headers.insert("Message-ID".to_string(), format!("<{}>", id));
+        if !references.is_empty() {
+            headers.insert("References".to_string(), references.to_string());
+        }
+
+        Envelope {
+            uid: Some(uid),
+            date: Some(format!("2025-03-{:02}T10:00:00Z", day)),
+            from: vec![],
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            subject: Some("Topic".to_string()),
+            headers,
+            flags: vec![],
+            mailbox_name: "INBOX".to_string(),
+        }
+    }
+
+    /// Render trees as e.g. `1(2 3(4))`, with `-` for a missing message
+    fn render(nodes: &[ThreadNode]) -> String {
+        nodes
+            .iter()
+            .map(|node| {
+                let uid = match node.envelope.as_ref().and_then(|e| e.uid) {
+                    Some(uid) => uid.to_string(),
+                    None => "-".to_string(),
+                };
+                if node.children.is_empty() {
+                    uid
+                } else {
+                    format!("{}({})", uid, render(&node.children))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn render_threads(threads: &[Thread]) -> Vec<String> {
+        threads.iter().map(|t| render(&t.roots)).collect()
+    }
+
+    #[test]
+    fn links_replies_to_their_parents() {
+        let mut reply = message(3, 3, "c", "<a>");
+        reply
+            .headers
+            .insert("In-Reply-To".to_string(), "<b>".to_string());
+        let envelopes = vec![reply, message(1, 1, "a", ""), message(2, 2, "b", "<a>")];
+
+        let threads = build_threads(envelopes, ServerThreading::None);
+
+        assert_eq!(render_threads(&threads), vec!["1(2(3))"]);
+        assert_eq!(threads[0].id, "a");
+        assert_eq!(threads[0].messages, 3);
+    }
+
+    #[test]
+    fn keeps_siblings_of_missing_parent_together() {
+        let envelopes = vec![
+            message(1, 1, "b", "<missing>"),
+            message(2, 2, "c", "<root> <missing>"),
+            message(3, 3, "d", "<c>"),
+        ];
+
+        let threads = build_threads(envelopes, ServerThreading::None);
+
+        // The missing messages leave no empty nodes behind
+        assert_eq!(render_threads(&threads), vec!["1 2(3)"]);
+        assert_eq!(threads[0].id, "b");
+    }
+
+    #[test]
+    fn avoids_reference_cycles() {
+        let envelopes = vec![
+            message(1, 1, "a", "<b>"),
+            message(2, 2, "b", "<a>"),
+            message(3, 3, "c", "<c> <d>"),
+            message(4, 4, "d", "<c>"),
+        ];
+
+        let threads = build_threads(envelopes, ServerThreading::None);
+
+        // The link that would close a loop is dropped
+        assert_eq!(render_threads(&threads), vec!["4(3)", "2(1)"]);
+    }
+
+    #[test]
+    fn merges_replies_by_references_not_subject() {
+        let mut reply = message(2, 2, "b", "<a>");
+        reply.subject = Some("Re: Topic".to_string());
+        let mut forward = message(3, 3, "c", "");
+        forward.subject = Some("Fwd: Topic".to_string());
+        let mut unrelated_reply = message(4, 4, "d", "");
+        unrelated_reply.subject = Some("Re: Topic".to_string());
+        let envelopes = vec![message(1, 1, "a", ""), reply, forward, unrelated_reply];
+
+        let threads = build_threads(envelopes, ServerThreading::None);
+
+        // Subjects alone do not merge threads, JWZ step 5 is left out
+        assert_eq!(render_threads(&threads), vec!["4", "3", "1(2)"]);
+        assert_eq!(threads[2].subject.as_deref(), Some("Topic"));
+    }
+
+    #[test]
+    fn sorts_threads_by_latest_message() {
+        let envelopes = vec![
+            message(1, 1, "a", ""),
+            message(5, 5, "e", "<a>"),
+            message(2, 2, "b", "<a>"),
+            message(3, 3, "c", ""),
+            message(4, 4, "d", ""),
+        ];
+
+        let threads = build_threads(envelopes, ServerThreading::None);
+
+        // Threads by their newest message, replies oldest first
+        assert_eq!(render_threads(&threads), vec!["1(2 5)", "4", "3"]);
+        assert_eq!(
+            threads[0].latest_date.as_deref(),
+            Some("2025-03-05T10:00:00Z")
+        );
+    }
+
+    #[test]
+    fn counts_unread_and_flagged_messages() {
+        let mut read = message(1, 1, "a", "");
+        read.flags = vec!["\\Seen".to_string()];
+        let mut flagged = message(2, 2, "b", "<a>");
+        flagged.flags = vec!["\\Flagged".to_string()];
+
+        let threads = build_threads(vec![read, flagged], ServerThreading::None);
+
+        assert_eq!(threads[0].unread, 1);
+        assert!(threads[0].flagged);
+        assert_eq!(threads[0].flags, vec!["\\Flagged", "\\Seen"]);
+    }
+
+    #[test]
+    fn groups_by_gmail_thread_id() {
+        let envelopes = vec![
+            message(1, 1, "a", ""),
+            message(2, 2, "b", ""),
+            message(3, 3, "c", "<a>"),
+            message(4, 4, "d", ""),
+        ];
+        let thread_ids = HashMap::from([(1, 100), (2, 100), (3, 100)]);
+
+        let threads = build_threads(envelopes, ServerThreading::Gmail(thread_ids));
+
+        // Messages without a thread ID are threaded locally
+        assert_eq!(render_threads(&threads), vec!["4", "1(3) 2"]);
+        assert_eq!(threads[1].id, "100");
+    }
+
+    #[test]
+    fn builds_threads_from_server_trees() {
+        let envelopes = vec![
+            message(2, 2, "b", ""),
+            message(3, 3, "c", ""),
+            message(6, 6, "f", ""),
+            message(7, 7, "g", ""),
+        ];
+        let trees = parse_thread_response("(2)(3 6 (4 23)(44 7 96))");
+
+        let threads = build_threads(envelopes, ServerThreading::References(trees));
+
+        // UIDs that are not cached are dropped
+        assert_eq!(render_threads(&threads), vec!["3(6(7))", "2"]);
+    }
+
+    #[test]
+    fn parses_thread_responses() {
+        let leaf = |uid| UidTree {
+            uid: Some(uid),
+            children: vec![],
+        };
+
+        assert_eq!(
+            parse_thread_response(" (2)(3 6 (4 23)(44 7 96))"),
+            vec![
+                vec![leaf(2)],
+                vec![UidTree {
+                    uid: Some(3),
+                    children: vec![UidTree {
+                        uid: Some(6),
+                        children: vec![
+                            UidTree {
+                                uid: Some(4),
+                                children: vec![leaf(23)],
+                            },
+                            UidTree {
+                                uid: Some(44),
+                                children: vec![UidTree {
+                                    uid: Some(7),
+                                    children: vec![leaf(96)],
+                                }],
+                            },
+                        ],
+                    }],
+                }],
+            ]
+        );
+        assert_eq!(
+            parse_thread_response(" ((3)(5))"),
+            vec![vec![UidTree {
+                uid: None,
+                children: vec![leaf(3), leaf(5)],
+            }]]
+        );
+    }
+
+    #[test]
+    fn parses_gmail_thread_ids() {
+        assert_eq!(
+            parse_thread_id("* 1 FETCH (X-GM-THRID 1278455344230334865 UID 42)"),
+            Some((42, 1278455344230334865))
+        );
+        assert_eq!(parse_thread_id("* 1 FETCH (UID 42)"), None);
+    }
+}
@@ -0,0 +1,409 @@
+// This module detects tracking in the HTML of messages: pixels that report
+// when a message is opened, and links wrapped in redirects that report clicks.
+//
+// Detection runs on the body before it is sanitized, as blocking remote
+// content removes the pixels.
+use std::collections::HashMap;
+
+use oauth2::url::Url;
+use serde::{Deserialize, Serialize};
+
+/// Hosts of email service providers and tools known to track opens and clicks,
+/// matched against the end of the host name
+const TRACKING_DOMAINS: &[(&str, &str)] = &[
+    ("list-manage.com", "Mailchimp"),
+    ("mailchimp.com", "Mailchimp"),
+    ("mandrillapp.com", "Mandrill"),
+    ("sendgrid.net", "SendGrid"),
+    ("sparkpostmail.com", "SparkPost"),
+    ("mailgun.org", "Mailgun"),
+    ("awstrack.me", "Amazon SES"),
+    ("exacttarget.com", "Salesforce Marketing Cloud"),
+    ("pardot.com", "Pardot"),
+    ("hubspotemail.net", "HubSpot"),
+    ("hubspotlinks.com", "HubSpot"),
+    ("hs-analytics.net", "HubSpot"),
+    ("klaviyomail.com", "Klaviyo"),
+    ("customer.io", "Customer.io"),
+    ("intercom-mail.com", "Intercom"),
+    ("rs6.net", "Constant Contact"),
+    ("convertkit-mail.com", "ConvertKit"),
+    ("convertkit-mail2.com", "ConvertKit"),
+    ("mktoresp.com", "Marketo"),
+    ("mixmax.com", "Mixmax"),
+    ("yesware.com", "Yesware"),
+    ("mailtrack.io", "Mailtrack"),
+    ("mailfoogae.appspot.com", "Streak"),
+    ("superhuman.com", "Superhuman"),
+    ("getnotify.com", "Notify"),
+    ("bananatag.com", "Bananatag"),
+    ("google-analytics.com", "Google Analytics"),
+];
+
+/// Query parameters that redirect wrappers put the destination in
+const REDIRECT_PARAMETERS: &[&str] = &[
+    "url",
+    "u",
+    "redirect",
+    "redirect_url",
+    "target",
+    "q",
+    "dest",
+    "destination",
+    "link",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum TrackerKind {
+    /// An image reporting that the message was opened
+    Pixel,
+    /// A link reporting that it was clicked
+    Link,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tracker {
+    pub kind: TrackerKind,
+    pub url: String,
+    pub domain: String,
+    /// The tracking service, if known
+    pub service: Option<String>,
+    /// The unwrapped destination of a tracked link, if it could be found
+    pub destination: Option<String>,
+}
+
+/// Detect the trackers in an HTML body
+///
+/// # Arguments
+/// * `html` - The unsanitized HTML body
+/// # Returns
+/// * `Vec<Tracker>` - The detected pixels and tracked links
+///
+pub fn detect(html: &str) -> Vec<Tracker> {
+    let mut trackers = vec![];
+
+    for attributes in tags(html, "img") {
+        if let Some(tracker) = attributes
+            .get("src")
+            .and_then(|src| pixel(src, &attributes))
+        {
+            trackers.push(tracker);
+        }
+    }
+    for attributes in tags(html, "a") {
+        if let Some(tracker) = attributes.get("href").and_then(|href| tracked_link(href)) {
+            trackers.push(tracker);
+        }
+    }
+
+    trackers
+}
+
+/// Map tracked links to their destinations, for those that could be unwrapped
+pub fn link_rewrites(trackers: &[Tracker]) -> HashMap<String, String> {
+    trackers
+        .iter()
+        .filter(|t| t.kind == TrackerKind::Link)
+        .filter_map(|t| Some((t.url.clone(), t.destination.clone()?)))
+        .collect()
+}
+
+fn pixel(src: &str, attributes: &HashMap<String, String>) -> Option<Tracker> {
+    let url = remote_url(src)?;
+    let domain = url.host_str()?.to_string();
+    let service = service(&domain);
+
+    // The declarations of the inline style, e.g. `width` -> `1px`
+    let style: HashMap<String, String> = attributes
+        .get("style")
+        .map(|style| {
+            style
+                .split(';')
+                .filter_map(|declaration| declaration.split_once(':'))
+                .map(|(property, value)| {
+                    (
+                        property.trim().to_ascii_lowercase(),
+                        value.trim().to_ascii_lowercase(),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let tiny = |name: &str| {
+        [attributes.get(name), style.get(name)]
+            .into_iter()
+            .flatten()
+            .filter_map(|value| value.trim().trim_end_matches("px").parse::<f32>().ok())
+            .any(|size| size <= 1.0)
+    };
+    let hidden = style.get("display").is_some_and(|value| value == "none")
+        || style
+            .get("visibility")
+            .is_some_and(|value| value == "hidden");
+
+    let path = url.path().to_ascii_lowercase();
+    let tracking_path = [
+        "/open", "/track", "/pixel", "/beacon", "/wf/open", "/o.gif", "/e/o/",
+    ]
+    .iter()
+    .any(|pattern| path.contains(pattern));
+
+    let detected =
+        (tiny("width") && tiny("height")) || hidden || (service.is_some() && tracking_path);
+    if !detected {
+        return None;
+    }
+
+    Some(Tracker {
+        kind: TrackerKind::Pixel,
+        url: src.to_string(),
+        domain,
+        service,
+        destination: None,
+    })
+}
+
+fn tracked_link(href: &str) -> Option<Tracker> {
+    let url = remote_url(href)?;
+    let domain = url.host_str()?.to_string();
+    let service = service(&domain);
+
+    // A redirect wrapper carries the destination in its query
+    let destination = url
+        .query_pairs()
+        .filter(|(name, _)| REDIRECT_PARAMETERS.contains(&name.to_ascii_lowercase().as_str()))
+        .find_map(|(_, value)| {
+            let destination = remote_url(&value)?;
+            // A redirect within the same site is not a tracker
+            (destination.host_str()? != domain).then(|| value.into_owned())
+        });
+
+    if service.is_none() && destination.is_none() {
+        return None;
+    }
+
+    Some(Tracker {
+        kind: TrackerKind::Link,
+        url: href.to_string(),
+        domain,
+        service,
+        destination,
+    })
+}
+
+fn remote_url(value: &str) -> Option<Url> {
+    let url = Url::parse(value.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https").then_some(url)
+}
+
+fn service(domain: &str) -> Option<String> {
+    let domain = domain.to_ascii_lowercase();
+    TRACKING_DOMAINS
+        .iter()
+        .find(|(tracking_domain, _)| {
+            domain == *tracking_domain || domain.ends_with(&format!(".{}", tracking_domain))
+        })
+        .map(|(_, service)| service.to_string())
+}
+
+/// Get the attributes of every tag with the given name
+fn tags(html: &str, name: &str) -> Vec<HashMap<String, String>> {
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{}", name);
+    let mut tags = vec![];
+    let mut position = 0;
+
+    while let Some(start) = lower[position..]
+        .find(&open)
+        .map(|i| i + position + open.len())
+    {
+        position = start;
+
+        // Skip longer tag names with the same prefix, e.g. <abbr> for <a>
+        if !html[start..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>') {
+            continue;
+        }
+
+        let (attributes, end) = parse_attributes(&html[start..]);
+        tags.push(attributes);
+        position = start + end;
+    }
+
+    tags
+}
+
+/// Parse the attributes of a tag up to its closing `>`, returning them and the length parsed
+fn parse_attributes(tag: &str) -> (HashMap<String, String>, usize) {
+    let mut attributes = HashMap::new();
+    let mut chars = tag.char_indices().peekable();
+
+    loop {
+        // Skip to the next attribute name
+        while chars
+            .next_if(|(_, c)| c.is_ascii_whitespace() || *c == '/')
+            .is_some()
+        {}
+        let Some(&(start, c)) = chars.peek() else {
+            return (attributes, tag.len());
+        };
+        if c == '>' {
+            return (attributes, start + 1);
+        }
+
+        let mut name = String::new();
+        while let Some((_, c)) =
+            chars.next_if(|(_, c)| !c.is_ascii_whitespace() && !matches!(c, '=' | '>' | '/'))
+        {
+            name.push(c.to_ascii_lowercase());
+        }
+        if name.is_empty() {
+            chars.next();
+            continue;
+        }
+
+        while chars.next_if(|(_, c)| c.is_ascii_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if(|(_, c)| *c == '=').is_some() {
+            while chars.next_if(|(_, c)| c.is_ascii_whitespace()).is_some() {}
+            match chars.next_if(|(_, c)| *c == '"' || *c == '\'') {
+                Some((_, quote)) => {
+                    for (_, c) in chars.by_ref() {
+                        if c == quote {
+                            break;
+                        }
+                        value.push(c);
+                    }
+                }
+                None => {
+                    while let Some((_, c)) =
+                        chars.next_if(|(_, c)| !c.is_ascii_whitespace() && *c != '>')
+                    {
+                        value.push(c);
+                    }
+                }
+            }
+        }
+
+        attributes
+            .entry(name)
+            .or_insert_with(|| decode_entities(&value));
+    }
+}
+
+/// Decode the character references that commonly appear in URLs
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tracking_pixels() {
+        let html = r#"<img src="https://x.example/a.gif" width="1" height="1">
+<IMG SRC="https://mc.us1.list-manage.com/track/open.php?u=1">
+<img style="width: 1px; height: 1px" src="https://y.example/b.gif">
+<img src="https://z.example/c.gif" style="display: none">
+<img src="https://cdn.example/logo.png" width="200" height="50">
+<img src="cid:logo@example.com" width="1" height="1">"#;
+
+        let trackers = detect(html);
+
+        let found: Vec<(&str, Option<&str>)> = trackers
+            .iter()
+            .map(|t| (t.domain.as_str(), t.service.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("x.example", None),
+                ("mc.us1.list-manage.com", Some("Mailchimp")),
+                ("y.example", None),
+                ("z.example", None),
+            ]
+        );
+        assert!(trackers.iter().all(|t| t.kind == TrackerKind::Pixel));
+    }
+
+    #[test]
+    fn detects_tracked_links() {
+        let html = r#"<a href="https://click.sendgrid.net/ls/click?upn=1">Offer</a>
+<a href="https://t.example/r?url=https%3A%2F%2Fshop.example%2Foffer">Shop</a>
+<a href="https://example.com/login?redirect=https://example.com/home">Log in</a>
+<a href="https://example.com/about">About</a>"#;
+
+        let trackers = detect(html);
+
+        assert_eq!(trackers.len(), 2);
+        assert_eq!(trackers[0].kind, TrackerKind::Link);
+        assert_eq!(trackers[0].service.as_deref(), Some("SendGrid"));
+        assert_eq!(trackers[0].destination, None);
+        assert_eq!(trackers[1].domain, "t.example");
+        assert_eq!(
+            trackers[1].destination.as_deref(),
+            Some("https://shop.example/offer")
+        );
+    }
+
+    #[test]
+    fn skips_tags_sharing_a_prefix() {
+        let html = r#"<abbr href="https://click.sendgrid.net/ls/click?upn=1">A</abbr>
+<address><imgx src="https://x.example/a.gif" width="1" height="1"></address>
+<a/href="https://click.sendgrid.net/ls/click?upn=2">B</a>"#;
+
+        let trackers = detect(html);
+
+        assert_eq!(trackers.len(), 1);
+        assert_eq!(trackers[0].url, "https://click.sendgrid.net/ls/click?upn=2");
+    }
+
+    #[test]
+    fn parses_unquoted_and_encoded_attributes() {
+        let html = r#"<a href=https://t.example/r?id=1&amp;u=https://shop.example/a>A</a>
+<a class='x' href = 'https://t.example/r?id=2&amp;u=https://shop.example/b'>B</a>
+<a href="https://t.example/r?id=3&amp;u=https://shop.example/&quot;c&quot;">C</a>"#;
+
+        let trackers = detect(html);
+
+        let urls: Vec<&str> = trackers.iter().map(|t| t.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://t.example/r?id=1&u=https://shop.example/a",
+                "https://t.example/r?id=2&u=https://shop.example/b",
+                "https://t.example/r?id=3&u=https://shop.example/\"c\"",
+            ]
+        );
+        assert_eq!(
+            trackers[0].destination.as_deref(),
+            Some("https://shop.example/a")
+        );
+        assert_eq!(
+            trackers[1].destination.as_deref(),
+            Some("https://shop.example/b")
+        );
+    }
+
+    #[test]
+    fn rewrites_only_unwrapped_links() {
+        let html = r#"<a href="https://click.sendgrid.net/ls/click?upn=1">A</a>
+<a href="https://t.example/r?url=https://shop.example/">B</a>
+<img src="https://x.example/a.gif?url=https://shop.example/" width="1" height="1">"#;
+
+        let rewrites = link_rewrites(&detect(html));
+
+        assert_eq!(
+            rewrites,
+            HashMap::from([(
+                "https://t.example/r?url=https://shop.example/".to_string(),
+                "https://shop.example/".to_string()
+            )])
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use tauri::Emitter;
 
 pub fn navigate(window: tauri::WebviewWindow, url: &str) {
-    window.emit("navigate", url).unwrap();
+    if let Err(e) = window.emit("navigate", url) {
+        println!("Failed to navigate to {}: {}", url, e);
+    }
 }
@@ -1,19 +1,35 @@
 import { invoke } from '@tauri-apps/api/core'
 import type {
   Flag,
+  Account,
   AccountConfig,
   Envelope,
   Mailbox,
   Message,
   EmailAddress,
+  DiscoveredSettings,
+  EnvelopePage,
+  PageQuery,
+  SearchQuery,
+  LocalSearchResult,
+  Thread,
+  ThreadNode,
+  Draft,
+  SendResult,
 } from '$lib/types'
 
 export async function getConfig(): Promise<AccountConfig> {
   return invoke<AccountConfig>('get_config')
 }
 
-export async function configAddAccount(email: string): Promise<void> {
-  return invoke('config_add_account', { email })
+export async function configAddAccount(account: Account): Promise<void> {
+  return invoke('config_add_account', { account })
+}
+
+export async function discoverSettings(
+  email: string
+): Promise<DiscoveredSettings> {
+  return invoke<DiscoveredSettings>('discover_settings', { email })
 }
 
 export async function configRemoveAccount(email: string): Promise<void> {
@@ -24,20 +40,71 @@ export async function loginWithGoogle(): Promise<void> {
   return invoke('login_with_google')
 }
 
+export async function loginWithMicrosoft(): Promise<void> {
+  return invoke('login_with_microsoft')
+}
+
+export async function loginWithPassword(
+  account: Account,
+  password: string
+): Promise<void> {
+  return invoke('login_with_password', { account, password })
+}
+
 export async function getMailboxes(email: string): Promise<Mailbox[]> {
   return invoke<Mailbox[]>('get_mailboxes', { email })
 }
 
-export async function getEnvelopes(
+export async function getThreads(
   email: string,
   mailbox: string
-): Promise<Envelope[]> {
-  const envelopes = await invoke<Envelope[]>('get_envelopes', {
+): Promise<Thread[]> {
+  const threads = await invoke<Thread[]>('get_threads', { email, mailbox })
+
+  const parseDates = (node: ThreadNode): ThreadNode => ({
+    envelope: node.envelope && {
+      ...node.envelope,
+      date: new Date(node.envelope.date),
+    },
+    children: node.children.map(parseDates),
+  })
+
+  return threads.map((thread) => ({
+    ...thread,
+    roots: thread.roots.map(parseDates),
+  }))
+}
+
+export async function getEnvelopePage(
+  email: string,
+  mailbox: string,
+  query: PageQuery
+): Promise<EnvelopePage> {
+  const page = await invoke<EnvelopePage>('get_envelope_page', {
     email,
     mailbox,
+    query,
   })
 
-  console.log('Envelopes:', envelopes)
+  return {
+    ...page,
+    envelopes: page.envelopes.map((envelope) => ({
+      ...envelope,
+      date: new Date(envelope.date),
+    })),
+  }
+}
+
+export async function searchMessages(
+  email: string,
+  mailbox: string,
+  query: SearchQuery
+): Promise<Envelope[]> {
+  const envelopes = await invoke<Envelope[]>('search_messages', {
+    email,
+    mailbox,
+    query,
+  })
 
   return envelopes.map((envelope) => ({
     ...envelope,
@@ -45,6 +112,21 @@ export async function getEnvelopes(
   }))
 }
 
+export async function searchLocal(
+  query: string,
+  limit?: number
+): Promise<LocalSearchResult[]> {
+  const results = await invoke<LocalSearchResult[]>('search_local', {
+    query,
+    limit,
+  })
+
+  return results.map((result) => ({
+    ...result,
+    envelope: { ...result.envelope, date: new Date(result.envelope.date) },
+  }))
+}
+
 export async function getMessage(
   email: string,
   mailbox: string,
@@ -59,15 +141,107 @@ export async function getMessage(
   return message
 }
 
+export async function allowRemoteContent(
+  email: string,
+  allow: { sender?: string; messageId?: string }
+): Promise<void> {
+  return invoke('allow_remote_content', {
+    email,
+    sender: allow.sender,
+    messageId: allow.messageId,
+  })
+}
+
+export async function setUnwrapTrackingLinks(
+  email: string,
+  enabled: boolean
+): Promise<void> {
+  return invoke('set_unwrap_tracking_links', { email, enabled })
+}
+
+export async function setSaveSent(
+  email: string,
+  enabled: boolean | null
+): Promise<void> {
+  return invoke('set_save_sent', { email, enabled })
+}
+
+export async function setAccountAliases(
+  email: string,
+  aliases: string[]
+): Promise<void> {
+  return invoke('set_account_aliases', { email, aliases })
+}
+
+export async function replyMessage(
+  email: string,
+  mailbox: string,
+  uid: number,
+  all = false
+): Promise<Draft> {
+  return invoke<Draft>('reply_message', { email, mailbox, uid, all })
+}
+
+export async function forwardMessage(
+  email: string,
+  mailbox: string,
+  uid: number,
+  asAttachment = false
+): Promise<Draft> {
+  return invoke<Draft>('forward_message', { email, mailbox, uid, asAttachment })
+}
+
+export async function saveAttachment(
+  email: string,
+  mailbox: string,
+  uid: number,
+  part: string,
+  path: string
+): Promise<void> {
+  return invoke('save_attachment', { email, mailbox, uid, part, path })
+}
+
+export async function saveAttachmentToTemp(
+  email: string,
+  mailbox: string,
+  uid: number,
+  part: string,
+  filename?: string | null
+): Promise<string> {
+  return invoke<string>('save_attachment_to_temp', {
+    email,
+    mailbox,
+    uid,
+    part,
+    filename,
+  })
+}
+
 export async function sendEmail(
   from: string,
   to: EmailAddress[],
   cc: EmailAddress[] = [],
   bcc: EmailAddress[] = [],
   subject: string,
-  body: string
-): Promise<string> {
-  return invoke('send_email', { from, to, cc, bcc, subject, body })
+  body: string,
+  draft: Partial<Draft> = {}
+): Promise<SendResult> {
+  return invoke<SendResult>('send_email', {
+    from,
+    draft: {
+      text: '',
+      in_reply_to: null,
+      references: [],
+      forwarded: [],
+      attachments: [],
+      ...draft,
+      to,
+      cc,
+      bcc,
+      subject,
+      body,
+    },
+  })
 }
 
 export async function removeFlags(
@@ -116,9 +290,10 @@ export async function saveDraft(
   uid: number | undefined,
   subject: string,
   body: string,
-  to?: EmailAddress[],
-  cc?: EmailAddress[],
-  bcc?: EmailAddress[]
+  to: EmailAddress[] = [],
+  cc: EmailAddress[] = [],
+  bcc: EmailAddress[] = [],
+  draft: Partial<Draft> = {}
 ): Promise<number> {
   console.log('Saving draft:', {
     email,
@@ -135,12 +310,42 @@ export async function saveDraft(
     email,
     mailbox,
     uid,
-    subject,
-    body,
-    to,
-    cc,
-    bcc,
+    draft: {
+      text: '',
+      in_reply_to: null,
+      references: [],
+      forwarded: [],
+      attachments: [],
+      ...draft,
+      to,
+      cc,
+      bcc,
+      subject,
+      body,
+    },
   })
 
   return newUid
 }
+
+export async function openDraft(
+  email: string,
+  mailbox: string,
+  uid: number
+): Promise<Draft> {
+  return invoke<Draft>('open_draft', { email, mailbox, uid })
+}
+
+export async function watchMailbox(
+  email: string,
+  mailbox: string
+): Promise<void> {
+  return invoke('watch_mailbox', { email, mailbox })
+}
+
+export async function unwatchMailbox(
+  email: string,
+  mailbox: string
+): Promise<void> {
+  return invoke('unwatch_mailbox', { email, mailbox })
+}
@@ -1,6 +1,7 @@
 import { SvelteMap } from 'svelte/reactivity'
 import { Mailbox } from './mailbox.svelte'
 import { getMailboxes } from '$lib/commands'
+import type { ConnectionState } from '$lib/types'
 
 const accounts: Map<string, Account> = new Map()
 
@@ -9,6 +10,7 @@ export class Account {
 
   public email: string
   public syncState: 'idle' | 'syncing' | 'error' | 'initial' = $state('initial')
+  public connectionState: ConnectionState = $state({ state: 'connected' })
 
   constructor(email: string) {
     this.#mailboxes = new SvelteMap<string, Mailbox>()
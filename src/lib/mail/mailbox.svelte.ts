@@ -10,9 +10,13 @@ import ArchiveX from '@lucide/svelte/icons/archive-x'
 import CircleAlert from '@lucide/svelte/icons/circle-alert'
 import Folder from '@lucide/svelte/icons/folder'
 
-import { archiveMessage, deleteMessage, getEnvelopes } from '$lib/commands'
+import { archiveMessage, deleteMessage, getEnvelopePage } from '$lib/commands'
 import { Message } from './message.svelte'
 import type { Account } from './account.svelte'
+import type { Envelope } from '$lib/types'
+
+// The number of envelopes loaded at once
+const PAGE_SIZE = 50
 
 export class Mailbox {
   public account: Account
@@ -23,6 +27,8 @@ export class Mailbox {
   public attributes: string[] = $state<string[]>([])
   public messages: Message[] = $state<Message[]>([])
   public syncState: 'idle' | 'syncing' | 'error' | 'initial' = $state('initial')
+  public hasMore: boolean = $state(true)
+  public loadingMore: boolean = $state(false)
 
   constructor(
     account: Account,
@@ -48,31 +54,15 @@ export class Mailbox {
     this.syncState = 'syncing'
 
     try {
-      // Get the messages for the mailbox
-      const envelopes = await getEnvelopes(this.account.email, this.name).then(
-        (envelopes) => {
-          envelopes.sort((a, b) => {
-            return b.date.getTime() - a.date.getTime()
-          })
-          return envelopes
-        }
-      )
-
-      // Convert envelopes to messages
-      this.messages = envelopes.map((envelope) => {
-        return new Message(
-          this,
-          envelope.uid,
-          envelope.date,
-          envelope.from,
-          envelope.to,
-          envelope.cc,
-          envelope.bcc,
-          envelope.subject,
-          envelope.headers,
-          envelope.flags
-        )
+      // Refresh the loaded messages, or load the first page
+      const count = Math.max(PAGE_SIZE, this.messages.length)
+      const page = await getEnvelopePage(this.account.email, this.name, {
+        type: 'Newest',
+        count,
+        before_uid: null,
       })
+      this.setEnvelopes(page.envelopes)
+      this.hasMore = page.envelopes.length >= count
     } catch (error) {
       console.error('Failed to sync messages:', error)
       this.syncState = 'error'
@@ -83,6 +73,61 @@ export class Mailbox {
     this.syncState = 'idle'
   }
 
+  public async loadMore() {
+    const uids = this.messages
+      .map((message) => message.uid)
+      .filter((uid) => uid !== undefined)
+    if (this.loadingMore || !this.hasMore || uids.length === 0) {
+      return
+    }
+    this.loadingMore = true
+
+    try {
+      // Load the page below the oldest loaded message
+      const page = await getEnvelopePage(this.account.email, this.name, {
+        type: 'Newest',
+        count: PAGE_SIZE,
+        before_uid: Math.min(...uids),
+      })
+      const loaded = new Set(uids)
+      this.messages = [
+        ...this.messages,
+        ...page.envelopes
+          .filter((envelope) => !loaded.has(envelope.uid))
+          .map((envelope) => this.toMessage(envelope)),
+      ]
+      this.hasMore = page.envelopes.length >= PAGE_SIZE
+    } catch (error) {
+      console.error('Failed to load more messages:', error)
+    } finally {
+      this.loadingMore = false
+    }
+  }
+
+  public setEnvelopes(envelopes: Envelope[]) {
+    envelopes.sort((a, b) => {
+      return b.date.getTime() - a.date.getTime()
+    })
+
+    // Convert envelopes to messages
+    this.messages = envelopes.map((envelope) => this.toMessage(envelope))
+  }
+
+  private toMessage(envelope: Envelope): Message {
+    return new Message(
+      this,
+      envelope.uid,
+      envelope.date,
+      envelope.from,
+      envelope.to,
+      envelope.cc,
+      envelope.bcc,
+      envelope.subject,
+      envelope.headers,
+      envelope.flags
+    )
+  }
+
   public async deleteMessage(uid: number): Promise<void> {
     try {
       await deleteMessage(this.account.email, this.name, uid)
@@ -137,7 +137,7 @@ export class Message {
 
     console.log('Sending message...')
     try {
-      await sendEmail(
+      const result = await sendEmail(
         this.mailbox.account.email,
         this.to,
         this.cc,
@@ -145,6 +145,12 @@ export class Message {
         this.subject,
         this.body ?? ''
       )
+      if (result.sent_copy_error) {
+        console.warn(
+          'Message sent, but no copy was saved to the Sent mailbox:',
+          result.sent_copy_error
+        )
+      }
 
       if (!this.uid) {
         console.warn(
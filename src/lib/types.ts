@@ -18,6 +18,60 @@ export interface Envelope {
   mailbox_name: string
 }
 
+export type PageQuery =
+  | { type: 'Sequence'; start: number; end: number }
+  | { type: 'Uid'; start: number; end: number }
+  | { type: 'Newest'; count: number; before_uid?: number | null }
+
+export type SearchQuery = {
+  from?: string
+  to?: string
+  subject?: string
+  body?: string
+  // YYYY-MM-DD
+  since?: string
+  before?: string
+  flags?: Flag[]
+  not_flags?: Flag[]
+  larger?: number
+  smaller?: number
+  has_attachment?: boolean
+  limit?: number
+}
+
+export type LocalSearchResult = {
+  email: string
+  mailbox: string
+  score: number
+  envelope: Envelope
+}
+
+export type ThreadNode = {
+  // null for a message that is referenced but not in the mailbox
+  envelope: Envelope | null
+  children: ThreadNode[]
+}
+
+export type Thread = {
+  id: string
+  subject: string | null
+  messages: number
+  unread: number
+  flagged: boolean
+  flags: Flag[]
+  latest_date: string | null
+  roots: ThreadNode[]
+}
+
+export interface EnvelopePage {
+  envelopes: Envelope[]
+  // Unknown when the page is served from the cache
+  total: number | null
+  unseen: number | null
+  uid_validity: number | null
+  uid_next: number | null
+}
+
 export type Flag =
   | '\\Seen'
   | '\\Answered'
@@ -26,8 +80,67 @@ export type Flag =
   | '\\Draft'
   | (string & {})
 
+export type Attachment = {
+  filename: string | null
+  mime_type: string
+  size: number
+  content_id: string | null
+  // IMAP body section, e.g. '2' or '1.3'
+  part: string
+  inline: boolean
+}
+
+export type TrackerKind = 'Pixel' | 'Link'
+
+export type Tracker = {
+  kind: TrackerKind
+  url: string
+  domain: string
+  service: string | null
+  // Unwrapped destination of a tracked link
+  destination: string | null
+}
+
 export interface Message extends Envelope {
   body: string
+  text: string
+  attachments: Attachment[]
+  blocked_remote_content: number
+  trackers: Tracker[]
+  reply_to: EmailAddress[]
+}
+
+export type ForwardedPart = {
+  mailbox: string
+  uid: number
+  // Body section, or null to attach the whole message as message/rfc822
+  part: string | null
+  filename: string
+  mime_type: string
+  size: number | null
+}
+
+export type Draft = {
+  to: EmailAddress[]
+  cc: EmailAddress[]
+  bcc: EmailAddress[]
+  subject: string
+  body: string
+  text: string
+  in_reply_to: string | null
+  references: string[]
+  forwarded: ForwardedPart[]
+  attachments: OutgoingAttachment[]
+}
+
+export type OutgoingAttachment = {
+  filename: string
+  mime_type?: string | null
+  // Local file, or base64 content
+  path?: string | null
+  data?: string | null
+  // Referenced by cid: URLs in the HTML body
+  content_id?: string | null
 }
 
 export type EmailAddress = {
@@ -35,8 +148,102 @@ export type EmailAddress = {
   address: string
 }
 
+export type Security = 'Tls' | 'StartTls' | 'Plain'
+
+export type AuthMethod = 'Login' | 'Plain' | 'XOAuth2'
+
+export type ServerConfig = {
+  host: string
+  port: number
+  security: Security
+  auth: AuthMethod
+  username?: string | null
+}
+
+export type DiscoverySource = 'Autoconfig' | 'Srv' | 'Mx'
+
+export type Candidate = {
+  server: ServerConfig
+  source: DiscoverySource
+}
+
+export type DiscoveredSettings = {
+  imap: Candidate[]
+  smtp: Candidate[]
+}
+
+export type RemoteContent = {
+  senders: string[]
+  // Message-IDs
+  messages: string[]
+}
+
 export type Account = {
   email: string
+  imap: ServerConfig
+  smtp: ServerConfig
+  remote_content?: RemoteContent
+  unwrap_tracking_links?: boolean
+  aliases?: string[]
+  max_message_size?: number | null
+  // null copies sent messages unless the provider does, e.g. Gmail
+  save_sent?: boolean | null
 }
 
 export type AccountConfig = Account[]
+
+export type MailboxEvent = {
+  email: string
+  mailbox: string
+  uids: number[]
+}
+
+export type EnvelopesSynced = {
+  email: string
+  mailbox: string
+  envelopes: Envelope[]
+}
+
+export type ConnectionState =
+  | { state: 'connected' }
+  // Waiting before the given attempt to connect again
+  | { state: 'reconnecting'; attempt: number }
+  | { state: 'offline' }
+  // The credentials were rejected, the user has to log in again
+  | { state: 'auth_failed' }
+
+export type ConnectionEvent = ConnectionState & {
+  email: string
+}
+
+export type ErrorCode =
+  | 'Auth'
+  | 'Network'
+  | 'Tls'
+  | 'NotFound'
+  | 'ServerRejected'
+  | 'Quota'
+  | 'InvalidInput'
+  | 'Storage'
+  | 'Internal'
+
+// Rejected by every command that fails
+export type MailError = {
+  message: string
+  kind: 'MailError'
+  code: ErrorCode
+  // Whether trying again later may succeed
+  retryable: boolean
+  context: {
+    account: string | null
+    mailbox: string | null
+    uid: number | null
+  }
+}
+
+// Returned by send_email, the message was sent even if no copy was saved
+export type SendResult = {
+  // The SMTP reply code
+  code: string
+  sent_copy_error: MailError | null
+}
@@ -1,11 +1,16 @@
 import type { LayoutLoad } from './$types'
 import { getAccount } from '$lib/mail/account.svelte'
+import { watchMailbox } from '$lib/commands'
 
 export const load: LayoutLoad = async ({ params }) => {
   const email = params.account
   const account = getAccount(email)
   await account.syncMailboxes()
 
+  watchMailbox(email, 'INBOX').catch((error) =>
+    console.error('Failed to watch INBOX:', error)
+  )
+
   return {
     email,
   }